@@ -231,9 +231,7 @@ impl<B: BackingStore> App<B> {
                 } else {
                     new_principal.clone()
                 };
-                snapfaas::fs::utils::set_my_privilge(new_principal.clone());
                 snapfaas::fs::bootstrap::register_user_fsutil(self.fs.as_ref(), new_principal.clone(), clearance);
-                snapfaas::fs::utils::set_my_privilge(Component::dc_true());
             }
 
             let now = SystemTime::now()
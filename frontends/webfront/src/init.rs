@@ -1,6 +1,6 @@
 //! "init" code for any function invocation. It is responsible for resolving the passed-in
-//! gate path in a secure way. That is, it makes sure to set the privilege and the clearance
-//! for a logged in user or a public user. Moreover, it tracks the label for the file system
+//! gate path in a secure way. That is, it resolves the path in a security context with the
+//! privilege of a logged in user or a public user. Moreover, it tracks the label for the file system
 //! traversal and set LabeledInvoke.label to the new label after the traversal.
 
 use std::collections::HashMap;
@@ -31,16 +31,13 @@ pub fn init<S: BackingStore>(
     let (payload, blob, label, headers) = prepare_payload(request, blobstore)?;
     let privilege = login.unwrap_or(Component::dc_true());
 
-    {
-        fs::utils::clear_label();
-        fs::utils::set_my_privilge(privilege);
-        if let Some(label) = label {
-            fs::utils::taint_with_label(label);
-        }
+    let mut ctx = fs::SecurityContext::new(privilege);
+    if let Some(label) = label {
+        ctx.taint_with_label(label);
     }
 
-    let req = prepare_labeled_invoke(gate_path, blob, payload, headers, fs)?;
-    wait_for_completion(req, sched_conn)
+    let req = prepare_labeled_invoke(gate_path, blob, payload, headers, fs, &mut ctx)?;
+    wait_for_completion(req, sched_conn, &ctx)
 }
 
 fn prepare_payload(
@@ -149,18 +146,19 @@ fn prepare_labeled_invoke<S: BackingStore>(
     payload: Vec<u8>,
     headers: HashMap<String, String>,
     fs: &FS<S>,
+    ctx: &mut fs::SecurityContext,
 ) -> Result<sched::message::LabeledInvoke, Response> {
-    let path = fs::path::Path::parse(&gate_path).map_err(|_| {
-        Response::json(&serde_json::json!({"error": "Invalid path."})).with_status_code(400)
+    let path = fs::path::Path::parse(&gate_path, ctx).map_err(|e| {
+        Response::json(&serde_json::json!({ "error": format!("Invalid path: {}", e) }))
+            .with_status_code(400)
     })?;
     let (f, gate_privilege) =
-        fs::utils::resolve_gate_with_clearance_check(fs, path).map_err(|e| {
+        fs::utils::resolve_gate_with_clearance_check(fs, path, ctx).map_err(|e| {
             Response::json(&serde_json::json!({ "error": format!("{:?}", e) }))
                 .with_status_code(400)
         })?;
     let gate_privilege = Some(gate_privilege.into());
-    let label = fs::utils::get_current_label();
-    let label = label.into();
+    let label = ctx.label().clone().into();
     let blobs = blobs.drain().map(|(k, v)| (k, v.name)).collect();
     Ok(sched::message::LabeledInvoke {
         function: Some(f.into()),
@@ -170,13 +168,15 @@ fn prepare_labeled_invoke<S: BackingStore>(
         headers,
         blobs,
         sync: true,
-        invoker: Some(fs::utils::get_privilege().into()),
+        invoker: Some(ctx.privilege().clone().into()),
+        notification_depth: 0,
     })
 }
 
 fn wait_for_completion(
     invoke: LabeledInvoke,
     sched_conn: &mut TcpStream,
+    ctx: &fs::SecurityContext,
 ) -> Result<Response, Response> {
     debug!("submitting: {:?}", invoke);
     // submit the labeled_invoke to the scheduler
@@ -202,14 +202,14 @@ fn wait_for_completion(
     match TaskReturn::decode(bs.as_slice()) {
         Ok(tr) => {
             if !Into::<Buckle>::into(tr.label.clone().unwrap()).can_flow_to_with_privilege(
-                &fs::utils::get_current_label(),
-                &fs::utils::get_privilege(),
+                ctx.label(),
+                ctx.privilege(),
             ) {
                 Err(Response::json(&serde_json::json!({
                     "error": "unauthorized to read response",
                     "label": format!("{:?}", Into::<Buckle>::into(tr.label.unwrap())),
-                    "current_label": format!("{:?}", fs::utils::get_current_label()),
-                    "privilege": format!("{:?}", fs::utils::get_privilege())
+                    "current_label": format!("{:?}", ctx.label()),
+                    "privilege": format!("{:?}", ctx.privilege())
                 }))
                 .with_status_code(401))
             } else {
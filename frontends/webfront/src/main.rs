@@ -10,6 +10,14 @@ pub mod init;
 struct Cli {
     #[command(flatten)]
     store: cli::Store,
+    #[command(flatten)]
+    replication: cli::Replication,
+    #[command(flatten)]
+    cache: cli::Cache,
+    #[command(flatten)]
+    metrics: cli::Metrics,
+    #[command(flatten)]
+    encryption: cli::Encryption,
     /// Path of the blob directory
     #[arg(long, value_name = "PATH", default_value = "blobs")]
     blobs: std::ffi::OsString,
@@ -45,12 +53,12 @@ fn main() -> Result<(), std::io::Error> {
     let private_key_bytes = std::fs::read(cli.secret_key)?;
     let base_url = cli.base_url;
     let sched_address = cli.faasten_scheduler;
-    let blobstore = Blobstore::new(cli.blobs, cli.tmp);
+    let blobstore = Blobstore::new(cli.blobs, cli.tmp).with_key(cli.encryption.key());
     let listen_addr = cli.listen;
     if let Some(tikv_pds) = cli.store.tikv {
         let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
         let client =
-            rt.block_on(async { tikv_client::RawClient::new(tikv_pds).await.unwrap() });
+            rt.block_on(async { tikv_client::TransactionClient::new(tikv_pds).await.unwrap() });
         let tikv = snapfaas::fs::tikv::TikvClient::new(client, std::sync::Arc::new(rt));
         let app = app::App::new(
             PKey::private_key_from_pem(private_key_bytes.as_slice()).unwrap(),
@@ -60,19 +68,52 @@ fn main() -> Result<(), std::io::Error> {
                 client_secret: github_client_secret,
             },
             blobstore,
-            tikv,
+            cli.metrics.layer(cli.cache.layer(cli.encryption.layer(tikv))),
+            base_url,
+            sched_address,
+        );
+        start_app(app, &listen_addr)
+    } else if let Some(paths) = cli.store.lmdb {
+        let db = cli.replication.open(&paths, |path| {
+            &*std::boxed::Box::leak(Box::new(
+                lmdb::Environment::new()
+                    .set_map_size(100 * 1024 * 1024 * 1024)
+                    .set_max_dbs(2)
+                    .open(&std::path::Path::new(path))
+                    .unwrap(),
+            ))
+        });
+        let app = app::App::new(
+            PKey::private_key_from_pem(private_key_bytes.as_slice()).unwrap(),
+            PKey::public_key_from_pem(public_key_bytes.as_slice()).unwrap(),
+            app::GithubOAuthCredentials {
+                client_id: github_client_id,
+                client_secret: github_client_secret,
+            },
+            blobstore,
+            cli.metrics.layer(cli.cache.layer(cli.encryption.layer(db))),
+            base_url,
+            sched_address,
+        );
+        start_app(app, &listen_addr)
+    } else if let Some(paths) = cli.store.dir {
+        let db = cli.replication.open(&paths, |path| {
+            snapfaas::fs::dirstore::DirStore::open(path).expect("open store directory")
+        });
+        let app = app::App::new(
+            PKey::private_key_from_pem(private_key_bytes.as_slice()).unwrap(),
+            PKey::public_key_from_pem(public_key_bytes.as_slice()).unwrap(),
+            app::GithubOAuthCredentials {
+                client_id: github_client_id,
+                client_secret: github_client_secret,
+            },
+            blobstore,
+            cli.metrics.layer(cli.cache.layer(cli.encryption.layer(db))),
             base_url,
             sched_address,
         );
         start_app(app, &listen_addr)
-    } else if let Some(path) = cli.store.lmdb {
-        let dbenv = std::boxed::Box::leak(Box::new(
-            lmdb::Environment::new()
-                .set_map_size(100 * 1024 * 1024 * 1024)
-                .set_max_dbs(2)
-                .open(&std::path::Path::new(&path))
-                .unwrap(),
-        ));
+    } else if cli.store.in_memory {
         let app = app::App::new(
             PKey::private_key_from_pem(private_key_bytes.as_slice()).unwrap(),
             PKey::public_key_from_pem(public_key_bytes.as_slice()).unwrap(),
@@ -81,7 +122,7 @@ fn main() -> Result<(), std::io::Error> {
                 client_secret: github_client_secret,
             },
             blobstore,
-            &*dbenv,
+            cli.metrics.layer(cli.cache.layer(cli.encryption.layer(snapfaas::fs::memory::MemoryStore::new()))),
             base_url,
             sched_address,
         );
@@ -85,6 +85,33 @@ class DirEntry():
         self.fd = fd
         self.syscall = syscall
 
+    def stat(self):
+        req = syscalls_pb2.Syscall(dentStat=self.fd)
+        self.syscall._send(req)
+        return self.syscall._recv(syscalls_pb2.DentStatResult())
+
+    def subscribe(self, gate):
+        """Invokes `gate` on each change of this directory or file"""
+        req = syscalls_pb2.Syscall(dentSubscribe=syscalls_pb2.DentSubscribe(fd=self.fd, gate_fd=gate.fd))
+        self.syscall._send(req)
+        return self.syscall._recv(syscalls_pb2.DentResult()).success
+
+    def unsubscribe(self, gate):
+        req = syscalls_pb2.Syscall(dentUnsubscribe=syscalls_pb2.DentSubscribe(fd=self.fd, gate_fd=gate.fd))
+        self.syscall._send(req)
+        return self.syscall._recv(syscalls_pb2.DentResult()).success
+
+    def walk(self, max_depth: int = None, kinds: list = [], name: str = None, clearance: syscalls_pb2.Buckle = None):
+        """Returns (path, kind, depth) for each entry below this directory or
+        faceted directory, depth-first, or None"""
+        req = syscalls_pb2.Syscall(dentWalk=syscalls_pb2.DentWalk(fd=self.fd, max_depth=max_depth, kinds=kinds, name=name, clearance=clearance))
+        self.syscall._send(req)
+        response = self.syscall._recv(syscalls_pb2.DentWalkResult())
+        if response.success:
+            return [(e.path, e.kind, e.depth) for e in response.entries]
+        else:
+            return None
+
 class Directory(DirEntry):
     def ls(self):
         req = syscalls_pb2.Syscall(dentList = self.fd)
@@ -103,11 +130,27 @@ class Directory(DirEntry):
         self.syscall._send(req)
         return self.syscall._recv(syscalls_pb2.DentResult())
 
+    def rename(self, name: str, target: 'Directory', new_name: str):
+        req = syscalls_pb2.Syscall(dentRename=syscalls_pb2.DentRename(src_fd=self.fd,name=name,dest_fd=target.fd,new_name=new_name))
+        self.syscall._send(req)
+        return self.syscall._recv(syscalls_pb2.DentResult())
+
 
     @contextmanager
-    def open_at(self, path: [str]):
+    def open_at(self, path):
+        """Opens `path` relative to this directory entry, either a Faasten path
+        string such as `home:<T,faasten>:"a:b"`, which the server parses, or a
+        list of names and facet labels. Paths starting with `~` start at the
+        root rather than at this directory entry."""
         cur_dent = self.fd
         cur_kind = syscalls_pb2.DentDirectory
+        if isinstance(path, str):
+            v = self.syscall.open_path(cur_dent, path)
+            if not v.success:
+                raise OpenError
+            cur_dent = v.fd
+            cur_kind = v.kind
+            path = []
         for component in path:
             v = self.syscall.open_at(cur_dent, component)
             if not v.success:
@@ -127,6 +170,10 @@ class Directory(DirEntry):
                 yield Service(cur_dent, self.syscall)
             case syscalls_pb2.DentBlob:
                 yield BlobEntry(cur_dent, self.syscall)
+            case syscalls_pb2.DentMap:
+                yield Map(cur_dent, self.syscall)
+            case syscalls_pb2.DentLog:
+                yield Log(cur_dent, self.syscall)
             case _:
                 yield DirEntry(cur_dent, self.syscall)
         req = syscalls_pb2.Syscall(dentClose=cur_dent)
@@ -134,8 +181,11 @@ class Directory(DirEntry):
         self.syscall._recv(syscalls_pb2.DentResult())
 
 class File(DirEntry):
-    def read(self):
-        req = syscalls_pb2.Syscall(dentRead=self.fd)
+    def read(self, offset=None, length=None):
+        if offset is None and length is None:
+            req = syscalls_pb2.Syscall(dentRead=self.fd)
+        else:
+            req = syscalls_pb2.Syscall(dentReadRange=syscalls_pb2.DentRead(fd=self.fd, offset=offset, length=length))
         self.syscall._send(req)
         response = self.syscall._recv(syscalls_pb2.DentResult())
         if response.success:
@@ -149,6 +199,64 @@ class File(DirEntry):
         response = self.syscall._recv(syscalls_pb2.DentResult())
         return response.success
 
+    def append(self, data):
+        req = syscalls_pb2.Syscall(dentAppend=syscalls_pb2.DentAppend(fd=self.fd, data=data))
+        self.syscall._send(req)
+        response = self.syscall._recv(syscalls_pb2.DentResult())
+        return response.success
+
+    def truncate(self, length):
+        req = syscalls_pb2.Syscall(dentTruncate=syscalls_pb2.DentTruncate(fd=self.fd, length=length))
+        self.syscall._send(req)
+        response = self.syscall._recv(syscalls_pb2.DentResult())
+        return response.success
+
+class Map(DirEntry):
+    def get(self, key: str):
+        req = syscalls_pb2.Syscall(dentMapGet=syscalls_pb2.DentMapGet(fd=self.fd, key=key))
+        self.syscall._send(req)
+        response = self.syscall._recv(syscalls_pb2.DentResult())
+        if response.success and response.HasField("data"):
+            return response.data
+        else:
+            return None
+
+    def put(self, key: str, value: bytes):
+        req = syscalls_pb2.Syscall(dentMapPut=syscalls_pb2.DentMapPut(fd=self.fd, key=key, value=value))
+        self.syscall._send(req)
+        return self.syscall._recv(syscalls_pb2.DentResult()).success
+
+    def delete(self, key: str):
+        req = syscalls_pb2.Syscall(dentMapDelete=syscalls_pb2.DentMapDelete(fd=self.fd, key=key))
+        self.syscall._send(req)
+        return self.syscall._recv(syscalls_pb2.DentResult()).success
+
+    def cas(self, key: str, expected: bytes = None, value: bytes = None):
+        req = syscalls_pb2.Syscall(dentMapCas=syscalls_pb2.DentMapCas(fd=self.fd, key=key, expected=expected, value=value))
+        self.syscall._send(req)
+        return self.syscall._recv(syscalls_pb2.DentResult()).success
+
+class Log(DirEntry):
+    def append(self, record: bytes):
+        req = syscalls_pb2.Syscall(dentLogAppend=syscalls_pb2.DentLogAppend(fd=self.fd, record=record))
+        self.syscall._send(req)
+        return self.syscall._recv(syscalls_pb2.DentResult()).success
+
+    def read(self, offset: int = 0, max: int = None):
+        """Returns the offset of the first record read and the records, or None"""
+        req = syscalls_pb2.Syscall(dentLogRead=syscalls_pb2.DentLogRead(fd=self.fd, offset=offset, max=max))
+        self.syscall._send(req)
+        response = self.syscall._recv(syscalls_pb2.DentLogReadResult())
+        if response.success:
+            return response.offset, list(response.records)
+        else:
+            return None
+
+    def trim(self, offset: int):
+        req = syscalls_pb2.Syscall(dentLogTrim=syscalls_pb2.DentLogTrim(fd=self.fd, offset=offset))
+        self.syscall._send(req)
+        return self.syscall._recv(syscalls_pb2.DentResult()).success
+
 class FacetedDirectory(DirEntry):
     def ls(self):
         req = syscalls_pb2.Syscall(dentLsFaceted = syscalls_pb2.DentLsFaceted(fd = self.fd))
@@ -325,6 +433,18 @@ class Syscall():
         self._send(req)
         return self._recv(syscalls_pb2.DentResult())
 
+    def dent_create_map(self, label: syscalls_pb2.Buckle):
+        req = syscalls_pb2.Syscall(
+            dentCreate = syscalls_pb2.DentCreate(label = label, map = syscalls_pb2.Void()))
+        self._send(req)
+        return self._recv(syscalls_pb2.DentResult())
+
+    def dent_create_log(self, label: syscalls_pb2.Buckle):
+        req = syscalls_pb2.Syscall(
+            dentCreate = syscalls_pb2.DentCreate(label = label, log = syscalls_pb2.Void()))
+        self._send(req)
+        return self._recv(syscalls_pb2.DentResult())
+
     def dent_create_faceted(self):
         req = syscalls_pb2.Syscall(
             dentCreate = syscalls_pb2.DentCreate(facetedDirectory = syscalls_pb2.Void()))
@@ -414,6 +534,11 @@ class Syscall():
         self._send(req)
         return self._recv(syscalls_pb2.DentOpenResult())
 
+    def open_path(self, dent: int, path: str) -> syscalls_pb2.DentOpenResult:
+        req = syscalls_pb2.Syscall(dentOpen = syscalls_pb2.DentOpen(fd=dent,path=path))
+        self._send(req)
+        return self._recv(syscalls_pb2.DentOpenResult())
+
     def open_at_facet(self, dent: int, name: str):
         req = syscalls_pb2.Syscall(dentOpen = syscalls_pb2.DentOpen(fd=dent,name=name))
         self._send(req)
@@ -449,10 +574,27 @@ class Syscall():
         return response
 
     def taint_with_label(self, label):
+        """Raise the current label to include `label`. Returns the new current
+        label, or None if it would exceed the clearance.
+        """
         req = syscalls_pb2.Syscall(taintWithLabel = label)
         self._send(req)
-        response = self._recv(syscalls_pb2.Buckle())
-        return response
+        response = self._recv(syscalls_pb2.MaybeBuckle())
+        if response.HasField("label"):
+            return response.label
+        return None
+
+    def set_clearance(self, clearance: syscalls_pb2.Buckle):
+        """Bound how high reading Faasten objects may raise the current label.
+        Reads beyond the clearance fail instead. Returns the new clearance, or
+        None if the current label is already above it.
+        """
+        req = syscalls_pb2.Syscall(setClearance = clearance)
+        self._send(req)
+        response = self._recv(syscalls_pb2.MaybeBuckle())
+        if response.HasField("label"):
+            return response.label
+        return None
 
     def declassify(self, secrecy: syscalls_pb2.Component):
         """Declassify to the target secrecy and leave integrity untouched.
@@ -8,10 +8,13 @@ use jwt::{PKeyWithDigest, SignWithKey};
 use labeled::buckle::{Buckle, Component};
 use openssl::pkey::PKey;
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
 use snapfaas::{
     blobstore, cli,
-    fs::{BackingStore, FS},
+    fs::{
+        encrypted::EncryptedStore,
+        path::{Path, PathComponent},
+        BackingStore, SecurityContext, FS,
+    },
 };
 use std::{
     io::{stdout, Write},
@@ -25,6 +28,12 @@ struct Cli {
     action: Action,
     #[command(flatten)]
     store: cli::Store,
+    #[command(flatten)]
+    replication: cli::Replication,
+    #[command(flatten)]
+    cache: cli::Cache,
+    #[command(flatten)]
+    encryption: cli::Encryption,
 }
 
 #[derive(Parser, Debug)]
@@ -48,6 +57,60 @@ struct FaastenPath {
     path: String,
 }
 
+#[derive(Parser, Debug)]
+struct Tree {
+    /// Faasten path of the directory
+    #[arg(value_name = "FAASTEN_PATH")]
+    path: String,
+    /// Maximum depth to descend, unlimited if omitted
+    #[arg(long)]
+    depth: Option<usize>,
+    /// Only print entries whose names match the glob
+    #[arg(long, value_name = "GLOB")]
+    name: Option<String>,
+    /// Skip directories whose labels don't flow to the clearance, top if omitted
+    #[arg(long, value_name = "BUCKLE")]
+    clearance: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct Export {
+    /// Faasten path of the subtree
+    #[arg(value_name = "FAASTEN_PATH")]
+    path: String,
+    /// Local path of the archive
+    #[arg(value_name = "ARCHIVE")]
+    archive: String,
+}
+
+#[derive(Parser, Debug)]
+struct Import {
+    /// Local path of the archive
+    #[arg(value_name = "ARCHIVE")]
+    archive: String,
+    /// Faasten path to link the subtree at, either a new name in a directory
+    /// or an unallocated facet of a faceted directory
+    #[arg(value_name = "FAASTEN_PATH")]
+    dest: String,
+}
+
+#[derive(Parser, Debug)]
+struct Fsck {
+    /// Path of the blob directory, which referenced blobs are checked against
+    #[arg(long, value_name = "PATH")]
+    blobs: std::ffi::OsString,
+    /// Unlink damaged entries. Orphans are left to the garbage collector.
+    #[arg(long)]
+    repair: bool,
+}
+
+#[derive(Parser, Debug)]
+struct GenStorageKey {
+    /// Local path of the new key file
+    #[arg(value_name = "LOCAL_PATH")]
+    path: String,
+}
+
 #[derive(Parser, Debug)]
 struct CreateBlob {
     /// Local path of the blob
@@ -71,6 +134,30 @@ struct Mkdir {
     label: String,
 }
 
+#[derive(Parser, Debug)]
+struct Principal {
+    #[arg(value_name = "Component")]
+    component: String,
+}
+
+#[derive(Parser, Debug)]
+struct SetQuota {
+    #[arg(value_name = "Component")]
+    component: String,
+    /// Maximum number of objects, unlimited if omitted
+    #[arg(long)]
+    objects: Option<u64>,
+    /// Maximum bytes of file data, unlimited if omitted
+    #[arg(long)]
+    file_bytes: Option<u64>,
+    /// Maximum bytes of blobs, unlimited if omitted
+    #[arg(long)]
+    blob_bytes: Option<u64>,
+    /// Also reset the usage to zero
+    #[arg(long)]
+    reset_usage: bool,
+}
+
 #[derive(Parser, Debug)]
 struct Jwt {
     #[arg(value_name = "Component")]
@@ -109,6 +196,8 @@ enum Action {
     List(FaastenPath),
     /// List the Faasten faceted directory
     FacetedList(FaastenPath),
+    /// Recursively list the Faasten directory or faceted directory
+    Tree(Tree),
     /// Read the Faasten file
     Read(FaastenPath),
     /// Delete the Faasten FS object
@@ -121,34 +210,72 @@ enum Action {
     Jwt(Jwt),
     /// Generate a key pair and store them in Faasten storage
     GenKeypair(GenKeypair),
+    /// Generate a key file for encrypting stored objects and blobs
+    GenStorageKey(GenStorageKey),
+    /// Encrypt objects and blobs stored before encryption was enabled
+    EncryptPlaintext,
+    /// Rewrite objects stored in the legacy JSON encoding
+    MigrateEncoding,
+    /// Copy objects written with TiKV's raw API, before the store switched to
+    /// its transactional API, to the transactional API. Requires --tikv
+    MigrateTikvRaw,
+    /// Export a subtree, with labels and blobs, to a local archive
+    Export(Export),
+    /// Import a subtree from a local archive
+    Import(Import),
+    /// Check the consistency of the objects and blobs reachable from the root
+    Fsck(Fsck),
+    /// Show the storage quota and usage of a principal
+    Quota(Principal),
+    /// Set the storage quota of a principal
+    SetQuota(SetQuota),
+}
+
+/// Parses a Faasten path, or exits with the parse error. `~` expands to the
+/// privilege of `ctx`.
+fn parse_path(path: &str, ctx: &SecurityContext) -> Path {
+    Path::parse(path, ctx).unwrap_or_else(|e| {
+        eprintln!("Invalid Faasten path {:?}: {}", path, e);
+        std::process::exit(1);
+    })
 }
 
 pub fn main() -> std::io::Result<()> {
     env_logger::init();
     let cli = Cli::parse();
 
-    let fs: FS<Box<dyn BackingStore>> = if let Some(tikv_pds) = cli.store.tikv {
+    let tikv = cli.store.tikv.clone();
+    let store: Box<dyn BackingStore> = if let Some(tikv_pds) = cli.store.tikv {
         let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
-        let client = rt.block_on(async { tikv_client::RawClient::new(tikv_pds).await.unwrap() });
-        FS::new(Box::new(snapfaas::fs::tikv::TikvClient::new(
+        let client = rt.block_on(async { tikv_client::TransactionClient::new(tikv_pds).await.unwrap() });
+        Box::new(snapfaas::fs::tikv::TikvClient::new(
             client,
             std::sync::Arc::new(rt),
-        )))
-    } else if let Some(lmdb) = cli.store.lmdb.as_ref() {
-        if !std::path::Path::new(lmdb).exists() {
-            eprintln!("LMDB path does not exist: {}", lmdb);
-            std::process::exit(1);
-        }
-        let dbenv = std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::get_dbenv(lmdb)));
-        FS::new(Box::new(&*dbenv))
+        ))
+    } else if let Some(paths) = cli.store.lmdb.as_ref() {
+        Box::new(cli.replication.open(paths, |lmdb| {
+            if !std::path::Path::new(lmdb).exists() {
+                eprintln!("LMDB path does not exist: {}", lmdb);
+                std::process::exit(1);
+            }
+            &*std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::get_dbenv(lmdb)))
+        }))
+    } else if let Some(paths) = cli.store.dir.as_ref() {
+        Box::new(cli.replication.open(paths, |path| {
+            snapfaas::fs::dirstore::DirStore::open(path).expect("open store directory")
+        }))
+    } else if cli.store.in_memory {
+        Box::new(snapfaas::fs::memory::MemoryStore::new())
     } else {
         panic!("We shouldn't reach here.")
     };
+    let key = cli.encryption.key();
+    let fs = FS::new(cli.cache.layer(EncryptedStore::new(store, key.clone())));
 
-    let blobstore = blobstore::Blobstore::default();
+    let mut blobstore = blobstore::Blobstore::default().with_key(key.clone());
     match cli.action {
         Action::Bootstrap(bs) => {
-            snapfaas::fs::bootstrap::prepare_fs(&fs, &bs.yaml).expect("");
+            snapfaas::fs::bootstrap::prepare_fs(&fs, blobstore, &bs.yaml).expect("");
         }
         Action::UpdatePython(ui) => {
             snapfaas::fs::bootstrap::update_python(&fs, blobstore, &ui.path);
@@ -157,36 +284,72 @@ pub fn main() -> std::io::Result<()> {
             snapfaas::fs::bootstrap::update_fsutil(&fs, blobstore, &ui.path);
         }
         Action::List(fp) => {
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+            let mut ctx = SecurityContext::new(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
 
-            let path = snapfaas::fs::path::Path::parse(&fp.path).unwrap();
-            match fs.list_dir(path) {
+            let path = parse_path(&fp.path, &ctx);
+            match fs.list_dir(path.clone(), &mut ctx) {
                 Ok(entries) => {
                     for (name, dent) in entries {
-                        println!("{}\t{:?}", name, dent);
+                        // printed as path components, quoted as needed
+                        let name = PathComponent::Dscrp(name);
+                        match dent.stat(&fs, &mut ctx) {
+                            Ok(snapfaas::fs::Stat { size, metadata: Some(meta) }) => println!(
+                                "{}\t{:?}\tsize={:?}\tcreated={}\tmodified={}\tcreator={:?}",
+                                name, dent, size, meta.created, meta.modified, meta.creator
+                            ),
+                            _ => println!("{}\t{:?}", name, dent),
+                        }
                     }
                 }
-                Err(e) => log::warn!("Failed list. {:?}", e),
+                Err(e) => log::warn!("Failed list {}. {:?}", path, e),
             }
         }
         Action::FacetedList(fp) => {
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+            let mut ctx = SecurityContext::new(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
 
-            let path = snapfaas::fs::path::Path::parse(&fp.path).unwrap();
-            match fs.list_faceted(path, &Buckle::top()) {
+            let path = parse_path(&fp.path, &ctx);
+            match fs.list_faceted(path, &Buckle::top(), &mut ctx) {
                 Ok(entries) => {
                     for (label, _directory) in entries {
-                        println!("{:?}", label);
+                        println!("{}", PathComponent::Facet(label));
                     }
                 }
                 Err(e) => log::warn!("Failed list. {:?}", e),
             }
         }
+        Action::Tree(t) => {
+            let mut ctx = SecurityContext::new(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+
+            let path = parse_path(&t.path, &ctx);
+            let options = snapfaas::fs::WalkOptions {
+                max_depth: t.depth,
+                name: t.name.map(|name| glob::Pattern::new(&name).unwrap()),
+                clearance: Some(t.clearance.map_or(Buckle::top(), |c| Buckle::parse(&c).unwrap())),
+                ..Default::default()
+            };
+            match fs.walk(path.clone(), options, &mut ctx) {
+                Ok(walk) => {
+                    println!("{}", path);
+                    for visited in walk {
+                        match visited {
+                            Ok(visited) => println!(
+                                "{}{}\t{:?}",
+                                "  ".repeat(visited.depth),
+                                visited.path.last().unwrap(),
+                                visited.entry.kind()
+                            ),
+                            Err(e) => log::warn!("Failed walk. {:?}", e),
+                        }
+                    }
+                }
+                Err(e) => log::warn!("Failed walk {}. {:?}", path, e),
+            }
+        }
         Action::Read(fp) => {
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+            let mut ctx = SecurityContext::new(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
 
-            let path = snapfaas::fs::path::Path::parse(&fp.path).unwrap();
-            match fs.read_file(path) {
+            let path = parse_path(&fp.path, &ctx);
+            match fs.read_file(path, &mut ctx) {
                 Ok(data) => {
                     stdout().write(&data).unwrap();
                 }
@@ -194,36 +357,34 @@ pub fn main() -> std::io::Result<()> {
             }
         }
         Action::Delete(fp) => {
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+            let mut ctx = SecurityContext::new(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
 
-            let path = snapfaas::fs::path::Path::parse(&fp.path).unwrap();
+            let path = parse_path(&fp.path, &ctx);
             println!(
                 "{}",
-                fs.rm(path.parent().unwrap(), &path.file_name().unwrap())
+                fs.rm(path.parent().unwrap(), &path.file_name().unwrap(), &mut ctx)
                     .is_ok()
             );
         }
         Action::Mkdir(md) => {
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+            let mut ctx = SecurityContext::new(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
 
-            let dest = snapfaas::fs::path::Path::parse(&md.path).unwrap();
+            let dest = parse_path(&md.path, &ctx);
             let label = labeled::buckle::Buckle::parse(&md.label).unwrap();
 
-            let new_dir = fs.create_directory(label);
+            let new_dir = snapfaas::fs::NewEntry::Directory(label);
             println!(
                 "{}",
-                fs.link(dest.parent().unwrap(), dest.file_name().unwrap(), new_dir)
+                fs.create_and_link(dest.parent().unwrap(), dest.file_name().unwrap(), new_dir, &mut ctx)
                     .is_ok()
             );
         }
         Action::CreateBlob(cb) => {
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+            let mut ctx = SecurityContext::new(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
 
             let mut file = std::fs::File::open(&cb.src)?;
-            let dest = snapfaas::fs::path::Path::parse(&cb.dest).unwrap();
+            let dest = parse_path(&cb.dest, &ctx);
             let label = labeled::buckle::Buckle::parse(&cb.label).unwrap();
-            let mut blobstore: blobstore::Blobstore<Sha256> =
-                snapfaas::blobstore::Blobstore::default();
             let mut blob = blobstore.create().unwrap();
             let _ = std::io::copy(&mut file, &mut blob);
             let blob = blobstore.save(blob).unwrap();
@@ -234,7 +395,8 @@ pub fn main() -> std::io::Result<()> {
                     dest.parent().unwrap(),
                     dest.file_name().unwrap(),
                     label,
-                    blob.name
+                    blob.name,
+                    &mut ctx,
                 )
                 .is_ok()
             );
@@ -271,8 +433,8 @@ pub fn main() -> std::io::Result<()> {
                 public_key_pem,
             } = generate_ec_keys()?;
 
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
-            let private_dest = snapfaas::fs::path::Path::parse(&gkp.private_key).unwrap();
+            let mut ctx = SecurityContext::new(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+            let private_dest = parse_path(&gkp.private_key, &ctx);
             let private_label = labeled::buckle::Buckle::parse("faasten,faasten").unwrap();
             println!(
                 "{}",
@@ -282,10 +444,11 @@ pub fn main() -> std::io::Result<()> {
                     private_dest.file_name().unwrap(),
                     private_label,
                     private_key_pem,
+                    &mut ctx,
                 )
                 .is_ok()
             );
-            let public_dest = snapfaas::fs::path::Path::parse(&gkp.public_key).unwrap();
+            let public_dest = parse_path(&gkp.public_key, &ctx);
             let public_label = labeled::buckle::Buckle::parse("T,faasten").unwrap();
             println!(
                 "{}",
@@ -295,10 +458,116 @@ pub fn main() -> std::io::Result<()> {
                     public_dest.file_name().unwrap(),
                     public_label,
                     public_key_pem,
+                    &mut ctx,
                 )
                 .is_ok()
             );
         }
+        Action::GenStorageKey(gsk) => {
+            snapfaas::crypto::Key::generate().save(&gsk.path)?;
+        }
+        Action::EncryptPlaintext => {
+            if key.is_none() {
+                eprintln!("Encrypting requires --key-file");
+                std::process::exit(1);
+            }
+            println!("{}\t{}", fs.encrypt_plaintext(), blobstore.encrypt_plaintext()?);
+        }
+        Action::Export(e) => {
+            let ctx = SecurityContext::new(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+
+            let path = parse_path(&e.path, &ctx);
+            match fs.export(path.clone()) {
+                Ok(archive) => {
+                    let out = std::io::BufWriter::new(std::fs::File::create(&e.archive)?);
+                    archive.write(out, &blobstore)?;
+                    println!("{}", archive.objects.len());
+                }
+                Err(err) => log::warn!("Failed export {}. {:?}", path, err),
+            }
+        }
+        Action::Import(i) => {
+            let ctx = SecurityContext::new(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+
+            let dest = parse_path(&i.dest, &ctx);
+            let input = std::io::BufReader::new(std::fs::File::open(&i.archive)?);
+            let archive = snapfaas::fs::Archive::read(input, &mut blobstore)?;
+            match fs.import(&archive, dest.clone()) {
+                Ok(_) => println!("{}", archive.objects.len()),
+                Err(err) => log::warn!("Failed import {}. {:?}", dest, err),
+            }
+        }
+        Action::Fsck(f) => {
+            let blobs: blobstore::Blobstore = blobstore::Blobstore::new(f.blobs.clone(), "tmp".into());
+            let saved_blobs: std::collections::HashSet<String> = match blobs.list() {
+                Ok(names) => names.into_iter().collect(),
+                Err(e) => {
+                    eprintln!("Cannot list blobs in {:?}: {}", f.blobs, e);
+                    std::process::exit(1);
+                }
+            };
+            match fs.fsck(&saved_blobs) {
+                Ok(report) => {
+                    for damage in report.damaged.iter() {
+                        println!("{}\t{}", damage.path, damage.problem);
+                    }
+                    for uid in report.orphans.iter() {
+                        println!("orphan {:016x}", uid);
+                    }
+                    println!(
+                        "checked={}\tdamaged={}\torphans={}",
+                        report.checked, report.damaged.len(), report.orphans.len()
+                    );
+                    let missing_blobs = report.damaged.iter().any(|d| matches!(d.problem, snapfaas::fs::Problem::MissingBlob(_)));
+                    if f.repair && saved_blobs.is_empty() && missing_blobs {
+                        // more likely the wrong blob directory than every blob lost
+                        eprintln!("Refusing to repair: {:?} holds no blobs, but blobs are referenced", f.blobs);
+                        std::process::exit(1);
+                    } else if f.repair {
+                        match fs.repair(&report) {
+                            Ok(unlinked) => println!("unlinked={}", unlinked),
+                            Err(e) => log::warn!("Failed repair. {:?}", e),
+                        }
+                    }
+                }
+                Err(e) => log::warn!("Failed fsck. {:?}", e),
+            }
+        }
+        Action::MigrateTikvRaw => {
+            let Some(tikv_pds) = tikv else {
+                eprintln!("Migrating from TiKV's raw API requires --tikv");
+                std::process::exit(1);
+            };
+            let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+            let raw = rt.block_on(async { tikv_client::RawClient::new(tikv_pds.clone()).await.unwrap() });
+            let client = rt.block_on(async { tikv_client::TransactionClient::new(tikv_pds).await.unwrap() });
+            let store = snapfaas::fs::tikv::TikvClient::new(client, std::sync::Arc::new(rt));
+            println!("{}", store.migrate_from_raw(&raw));
+        }
+        Action::MigrateEncoding => match fs.migrate_encoding() {
+            Ok(migrated) => println!("{}", migrated),
+            Err(e) => log::warn!("Failed migration. {:?}", e),
+        },
+        Action::Quota(p) => {
+            let principal = Buckle::parse(format!("T,{}", p.component).as_str())
+                .unwrap()
+                .integrity;
+            let (quota, usage) = fs.quota(&principal);
+            println!("{:?}\n{:?}", quota, usage);
+        }
+        Action::SetQuota(sq) => {
+            let principal = Buckle::parse(format!("T,{}", sq.component).as_str())
+                .unwrap()
+                .integrity;
+            fs.set_quota(&principal, snapfaas::fs::Quota {
+                objects: sq.objects,
+                file_bytes: sq.file_bytes,
+                blob_bytes: sq.blob_bytes,
+            });
+            if sq.reset_usage {
+                fs.reset_usage(&principal, Default::default());
+            }
+        }
         Action::Jwt(jwt) => {
             let private_key_bytes = std::fs::read(jwt.secret_key)?;
             let pkey = PKey::private_key_from_pem(private_key_bytes.as_slice())?;
@@ -1,6 +1,6 @@
 use clap::Parser;
-use labeled::buckle::Buckle;
-use snapfaas::{cli, fs};
+use snapfaas::{blobstore::Blobstore, cli, fs::{self, BackingStore}};
+use std::collections::HashSet;
 use std::thread;
 use std::time::Duration;
 
@@ -10,11 +10,25 @@ struct Cli {
     /// Periodically run garbage collection
     #[arg(short, long, value_name = "SECS", default_value_t = 60)]
     interval: u64,
-    /// Run garbage collection once
+    /// Run garbage collection once. Only safe when nothing else is using the store.
     #[arg(long, conflicts_with = "interval")]
     once: bool,
+    /// Path of the blob directory. If present, unreferenced blobs are collected too
+    #[arg(long, value_name = "PATH")]
+    blobs: Option<std::ffi::OsString>,
+    /// Blobs saved or used within this period are never collected
+    #[arg(long, value_name = "SECS", default_value_t = 3600)]
+    blob_grace: u64,
     #[command(flatten)]
     store: cli::Store,
+    #[command(flatten)]
+    replication: cli::Replication,
+    #[command(flatten)]
+    cache: cli::Cache,
+    #[command(flatten)]
+    metrics: cli::Metrics,
+    #[command(flatten)]
+    encryption: cli::Encryption,
 }
 
 fn main() {
@@ -22,23 +36,101 @@ fn main() {
 
     let cli = Cli::parse();
 
-    let interval = cli.interval;
-
-    if let Some(_) = cli.store.tikv {
-        todo!();
-    } else if let Some(lmdb) = cli.store.lmdb.as_ref() {
-        fs::utils::taint_with_label(Buckle::top());
-        let dbenv = std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::get_dbenv(lmdb)));
-        let mut fs = fs::FS::new(&*dbenv);
-        loop {
-            if let Ok(collected) = fs.collect_garbage() {
-                log::debug!("garbage collected {}", collected.len())
-            }
-            if cli.once {
-                break;
-            } else {
-                thread::sleep(Duration::new(interval, 0));
+    let store: Box<dyn BackingStore> = if let Some(tikv_pds) = cli.store.tikv {
+        let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+        let client = rt.block_on(async { tikv_client::TransactionClient::new(tikv_pds).await.unwrap() });
+        Box::new(cli.cache.layer(cli.encryption.layer(fs::tikv::TikvClient::new(
+            client,
+            std::sync::Arc::new(rt),
+        ))))
+    } else if let Some(paths) = cli.store.lmdb.as_ref() {
+        Box::new(cli.cache.layer(cli.encryption.layer(cli.replication.open(paths, |lmdb| {
+            &*std::boxed::Box::leak(Box::new(fs::lmdb::get_dbenv(lmdb)))
+        }))))
+    } else if let Some(paths) = cli.store.dir.as_ref() {
+        Box::new(cli.cache.layer(cli.encryption.layer(cli.replication.open(paths, |path| {
+            fs::dirstore::DirStore::open(path).expect("open store directory")
+        }))))
+    } else if cli.store.in_memory {
+        Box::new(cli.cache.layer(cli.encryption.layer(fs::memory::MemoryStore::new())))
+    } else {
+        panic!("We shouldn't reach here.")
+    };
+    let fs = fs::FS::new(cli.metrics.layer(store));
+    let blobstore: Option<Blobstore> = cli.blobs.map(|base_dir| Blobstore::new(base_dir, "tmp".into()));
+    let blob_grace = Duration::new(cli.blob_grace, 0);
+
+    if cli.once {
+        match fs.collect_garbage() {
+            Ok(collected) => log::info!("garbage collected {}", collected.len()),
+            Err(e) => log::error!("garbage collection failed: {:?}", e),
+        }
+        if let Some(blobstore) = blobstore.as_ref() {
+            collect_blobs(&fs, blobstore, blob_grace, None);
+        }
+        return;
+    }
+
+    // Objects created by running functions are unreachable until they are
+    // linked, so only objects that stayed unreachable for a whole interval
+    // are collected. The same goes for blobs whose references are replaced
+    // while an invocation is about to use them.
+    let mut candidates: HashSet<u64> = HashSet::new();
+    let mut blob_candidates: HashSet<String> = HashSet::new();
+    loop {
+        match fs.collect_garbage_among(&candidates) {
+            Ok((collected, fresh)) => {
+                log::info!("garbage collected {}", collected.len());
+                candidates = fresh;
+            },
+            Err(e) => log::error!("garbage collection failed: {:?}", e),
+        }
+        if let Some(blobstore) = blobstore.as_ref() {
+            blob_candidates = collect_blobs(&fs, blobstore, blob_grace, Some(&blob_candidates));
+        }
+        thread::sleep(Duration::new(cli.interval, 0));
+    }
+}
+
+/// Deletes unreferenced blobs, restricted to `candidates` if present, and
+/// returns the unreferenced blobs that were kept.
+fn collect_blobs<S: BackingStore>(
+    fs: &fs::FS<S>,
+    blobstore: &Blobstore,
+    grace: Duration,
+    candidates: Option<&HashSet<String>>,
+) -> HashSet<String> {
+    let references = match fs.blob_references() {
+        Ok(references) => references,
+        Err(e) => {
+            log::error!("blob garbage collection failed: {:?}", e);
+            return HashSet::new();
+        }
+    };
+    let unreferenced = match blobstore.unreferenced(&references) {
+        Ok(unreferenced) => unreferenced,
+        Err(e) => {
+            log::error!("blob garbage collection failed: {:?}", e);
+            return HashSet::new();
+        }
+    };
+
+    let mut kept = HashSet::new();
+    let mut collected = 0;
+    for name in unreferenced {
+        if candidates.is_none_or(|c| c.contains(&name)) {
+            match blobstore.remove_if_unused(&name, grace) {
+                Ok(true) => {
+                    fs.refund_blob(&name);
+                    collected += 1;
+                    continue;
+                },
+                Ok(false) => (),
+                Err(e) => log::warn!("failed to collect blob {}: {:?}", name, e),
             }
         }
+        kept.insert(name);
     }
+    log::info!("blobs collected {}", collected);
+    kept
 }
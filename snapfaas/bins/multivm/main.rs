@@ -11,10 +11,12 @@
 
 use clap::Parser;
 use log::warn;
+use snapfaas::blobstore::Blobstore;
 use snapfaas::cli;
+use snapfaas::crypto::Key;
 use snapfaas::resource_manager::ResourceManager;
 use snapfaas::worker::Worker;
-use snapfaas::{fs::tikv::TikvClient, fs::BackingStore, sched};
+use snapfaas::{fs::encrypted::EncryptedStore, fs::tikv::TikvClient, fs::BackingStore, sched};
 
 use std::net::{SocketAddr, TcpStream};
 use std::str::FromStr;
@@ -31,6 +33,14 @@ struct Cli {
     memory: u32,
     #[command(flatten)]
     store: cli::Store,
+    #[command(flatten)]
+    replication: cli::Replication,
+    #[command(flatten)]
+    cache: cli::Cache,
+    #[command(flatten)]
+    metrics: cli::Metrics,
+    #[command(flatten)]
+    encryption: cli::Encryption,
 }
 
 fn main() {
@@ -48,15 +58,28 @@ fn main() {
 
     // create the worker pool
     let pool_size = manager.total_mem_in_mb() / 128;
-    let pool = if let Some(path) = cli.store.lmdb.as_ref() {
-        let dbenv = std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::get_dbenv(path)));
-        new_workerpool(pool_size, sched_addr, manager, &*dbenv)
+    let key = cli.encryption.key();
+    let pool = if let Some(paths) = cli.store.lmdb.as_ref() {
+        let db = cli.replication.open(paths, |path| {
+            &*std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::get_dbenv(path)))
+        });
+        let db = EncryptedStore::new(db, key.clone());
+        new_workerpool(pool_size, sched_addr, manager, cli.cache.layer(db), key, &cli.metrics)
     } else if let Some(tikv_pds) = cli.store.tikv {
         let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
         let client =
-            rt.block_on(async { tikv_client::RawClient::new(tikv_pds).await.unwrap() });
-        let db = TikvClient::new(client, Arc::new(rt));
-        new_workerpool(pool_size, sched_addr, manager, db)
+            rt.block_on(async { tikv_client::TransactionClient::new(tikv_pds).await.unwrap() });
+        let db = EncryptedStore::new(TikvClient::new(client, Arc::new(rt)), key.clone());
+        new_workerpool(pool_size, sched_addr, manager, cli.cache.layer(db), key, &cli.metrics)
+    } else if let Some(paths) = cli.store.dir.as_ref() {
+        let db = cli.replication.open(paths, |path| {
+            snapfaas::fs::dirstore::DirStore::open(path).expect("open store directory")
+        });
+        let db = EncryptedStore::new(db, key.clone());
+        new_workerpool(pool_size, sched_addr, manager, cli.cache.layer(db), key, &cli.metrics)
+    } else if cli.store.in_memory {
+        let db = EncryptedStore::new(snapfaas::fs::memory::MemoryStore::new(), key.clone());
+        new_workerpool(pool_size, sched_addr, manager, cli.cache.layer(db), key, &cli.metrics)
     } else {
         panic!("We shouldn't reach here");
     };
@@ -73,18 +96,23 @@ fn new_workerpool<T>(
     sched_addr: SocketAddr,
     manager: ResourceManager,
     db: T,
+    key: Option<Key>,
+    metrics: &cli::Metrics,
 ) -> threadpool::ThreadPool
 where
     T: BackingStore + Clone + Send + 'static,
 {
     let pool = threadpool::ThreadPool::new(pool_size);
+    // each worker's clone records the store operations of its invocations
+    let db = metrics.layer(db);
     let manager = Arc::new(Mutex::new(manager));
     for i in 0..pool_size as u32 {
         let sched_addr_dup = sched_addr.clone();
         let manager_dup = Arc::clone(&manager);
         let db_dup = db.clone();
+        let blobstore = Blobstore::default().with_key(key.clone());
         pool.execute(move || {
-            Worker::new(i + 100, sched_addr_dup, manager_dup, db_dup).wait_and_process();
+            Worker::new(i + 100, sched_addr_dup, manager_dup, db_dup, blobstore).wait_and_process();
         });
     }
     pool
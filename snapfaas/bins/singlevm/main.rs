@@ -35,6 +35,10 @@ struct Cli {
     start_label: Option<String>,
     #[command(flatten)]
     store: cli::Store,
+    #[command(flatten)]
+    replication: cli::Replication,
+    #[command(flatten)]
+    encryption: cli::Encryption,
 }
 
 fn main() {
@@ -114,12 +118,19 @@ fn main() {
         FS::new({
             let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
             let client =
-                rt.block_on(async { tikv_client::RawClient::new(tikv_pds).await.unwrap() });
-            Box::new(TikvClient::new(client, Arc::new(rt)))
+                rt.block_on(async { tikv_client::TransactionClient::new(tikv_pds).await.unwrap() });
+            Box::new(cli.encryption.layer(TikvClient::new(client, Arc::new(rt))))
         })
-    } else if let Some(path) = cli.store.lmdb.as_ref() {
-        let dbenv = std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::get_dbenv(path)));
-        FS::new(Box::new(&*dbenv))
+    } else if let Some(paths) = cli.store.lmdb.as_ref() {
+        FS::new(Box::new(cli.encryption.layer(cli.replication.open(paths, |path| {
+            &*std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::get_dbenv(path)))
+        }))))
+    } else if let Some(paths) = cli.store.dir.as_ref() {
+        FS::new(Box::new(cli.encryption.layer(cli.replication.open(paths, |path| {
+            snapfaas::fs::dirstore::DirStore::open(path).expect("open store directory")
+        }))))
+    } else if cli.store.in_memory {
+        FS::new(Box::new(cli.encryption.layer(snapfaas::fs::memory::MemoryStore::new())))
     } else {
         panic!("We shouldn't reach here.");
     };
@@ -127,7 +138,7 @@ fn main() {
     let mut env = SyscallGlobalEnv {
         sched_conn: None,
         fs,
-        blobstore: Blobstore::default(),
+        blobstore: Blobstore::default().with_key(cli.encryption.key()),
     };
 
     // Synchronously send the request to vm and wait for a response
@@ -0,0 +1,107 @@
+//! Format of encrypted blobs
+//!
+//! An encrypted blob is `MAGIC`, followed by a random base nonce, followed by
+//! the blob's plaintext in `CHUNK_LEN` chunks, each sealed separately so blobs
+//! are never held in memory whole. A chunk's nonce is the base nonce with the
+//! chunk's index XORed into its last 8 bytes, and each chunk authenticates the
+//! blob's name, its index and whether it is the last, so chunks cannot be
+//! reordered, dropped or moved to other blobs, and the blob cannot be
+//! truncated. A blob has at least one chunk, which is empty if the blob is.
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::unix::prelude::FileExt;
+
+use crate::crypto::{random_nonce, Key, Nonce, NONCE_LEN, TAG_LEN};
+
+pub const MAGIC: &[u8] = b"\xfa\x00blob";
+pub const CHUNK_LEN: usize = 64 * 1024;
+
+/// Returns whether the file starts with `MAGIC`
+pub fn is_encrypted(file: &File) -> Result<bool> {
+    let mut magic = [0; MAGIC.len()];
+    match file.read_exact_at(&mut magic, 0) {
+        Ok(()) => Ok(magic == MAGIC),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn chunk_nonce(base: &Nonce, index: u64) -> Nonce {
+    let mut nonce = *base;
+    for (byte, i) in nonce[NONCE_LEN - 8..].iter_mut().zip(index.to_be_bytes()) {
+        *byte ^= i;
+    }
+    nonce
+}
+
+fn chunk_aad(name: &str, index: u64, last: bool) -> Vec<u8> {
+    [name.as_bytes(), &index.to_be_bytes(), &[last as u8]].concat()
+}
+
+/// Reads until `buf` is full or `r` ends, and returns how much was read
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match r.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Calls `f` with each index and `size`-byte chunk of `r`, and whether it is
+/// the last chunk, which may be shorter or, only if it is the first, empty
+fn for_each_chunk<R, F>(r: &mut R, size: usize, mut f: F) -> Result<()>
+where
+    R: Read,
+    F: FnMut(u64, &[u8], bool) -> Result<()>,
+{
+    let (mut chunk, mut next) = (vec![0; size], vec![0; size]);
+    let mut len = read_full(r, &mut chunk)?;
+    let mut index = 0;
+    loop {
+        if len < size {
+            return f(index, &chunk[..len], true);
+        }
+        let next_len = read_full(r, &mut next)?;
+        f(index, &chunk, next_len == 0)?;
+        if next_len == 0 {
+            return Ok(());
+        }
+        std::mem::swap(&mut chunk, &mut next);
+        len = next_len;
+        index += 1;
+    }
+}
+
+/// Writes the blob `name` with plaintext `src` to `dst` encrypted
+pub fn encrypt<R: Read, W: Write>(key: &Key, name: &str, src: &mut R, dst: &mut W) -> Result<()> {
+    let base = random_nonce();
+    dst.write_all(MAGIC)?;
+    dst.write_all(&base)?;
+    for_each_chunk(src, CHUNK_LEN, |index, chunk, last| {
+        dst.write_all(&key.seal_with_nonce(&chunk_nonce(&base, index), chunk, &chunk_aad(name, index, last)))
+    })
+}
+
+/// Writes the plaintext of the encrypted blob `name` read from `src` to `dst`.
+/// Fails with `ErrorKind::InvalidData` if the blob fails to authenticate, in
+/// which case some of its plaintext may have been written already.
+pub fn decrypt<R: Read, W: Write>(key: &Key, name: &str, src: &mut R, dst: &mut W) -> Result<()> {
+    let invalid = || Error::new(ErrorKind::InvalidData, format!("blob {} failed to authenticate", name));
+    let mut header = [0; MAGIC.len() + NONCE_LEN];
+    src.read_exact(&mut header)?;
+    if !header.starts_with(MAGIC) {
+        return Err(invalid());
+    }
+    let base: Nonce = header[MAGIC.len()..].try_into().unwrap();
+    for_each_chunk(src, CHUNK_LEN + TAG_LEN, |index, chunk, last| {
+        let plaintext = key.open_with_nonce(&chunk_nonce(&base, index), chunk, &chunk_aad(name, index, last))
+            .ok_or_else(invalid)?;
+        dst.write_all(&plaintext)
+    })
+}
@@ -1,16 +1,43 @@
-use std::io::{Read, Result, Seek, Write};
+//! Content-addressed storage of blobs in a local directory
+//!
+//! With a key, blobs are saved encrypted in the format of `encryption`, and
+//! decrypted into temporary files when opened. Blobs saved without a key are
+//! still read as they are, until `encrypt_plaintext` encrypts them and marks
+//! the blob directory with `STRICT_MARKER`. From then on, blobs that are not
+//! encrypted are refused, as anyone able to write the blob directory could
+//! have replaced them.
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Seek, Write};
+use std::fs::DirBuilder;
+use std::os::unix::fs::DirBuilderExt;
 use std::os::unix::prelude::FileExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use std::{ffi::OsString, fs::File, marker::PhantomData};
 
 use sha2::{Digest, Sha256};
 use tempfile::NamedTempFile;
 
+use crate::crypto::Key;
+
+mod encryption;
+
+/// Directory that encrypted blobs handed out by path are decrypted into, by
+/// default. It is on tmpfs, so their plaintext is never written to disk, but
+/// a decrypted blob stays in memory until `remove_if_unused` removes the blob,
+/// or the machine restarts.
+const PLAINTEXT_DIR: &str = "/dev/shm/snapfaas-plaintext";
+
+/// File in the base directory marking that all blobs are encrypted. `list`
+/// skips it, as it is not a directory.
+const STRICT_MARKER: &str = "encrypted";
 
 #[derive(Debug)]
 pub struct Blobstore<D = Sha256> {
     base_dir: OsString,
     tmp_dir: OsString,
+    key: Option<Key>,
+    plaintext_dir: Option<OsString>,
     digest: PhantomData<D>,
 }
 
@@ -19,6 +46,8 @@ impl<D> Default for Blobstore<D> {
         Blobstore {
             base_dir: OsString::from("blobs"),
             tmp_dir: OsString::from("tmp"),
+            key: None,
+            plaintext_dir: None,
             digest: PhantomData,
         }
     }
@@ -26,7 +55,147 @@ impl<D> Default for Blobstore<D> {
 
 impl<D> Blobstore<D> {
     pub const fn new(base_dir: OsString, tmp_dir: OsString) -> Self {
-        Blobstore { base_dir, tmp_dir, digest: PhantomData }
+        Blobstore { base_dir, tmp_dir, key: None, plaintext_dir: None, digest: PhantomData }
+    }
+
+    /// Encrypts blobs saved from now on with `key`, if any. Blobs handed out
+    /// by path, i.e., kernels and images, are decrypted into `PLAINTEXT_DIR`.
+    pub fn with_key(mut self, key: Option<Key>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Decrypts blobs handed out by path into `dir` instead of
+    /// `PLAINTEXT_DIR`. It should be on a volatile file system, e.g., tmpfs.
+    pub fn with_plaintext_dir(mut self, dir: OsString) -> Self {
+        self.plaintext_dir = Some(dir);
+        self
+    }
+
+    fn blob_path(&self, name: &String) -> PathBuf {
+        let (d, n) = name.split_at(2);
+        PathBuf::from(&self.base_dir).join(d).join(n)
+    }
+
+    /// Like `blob_path`, but fails instead of panicking on names too short to
+    /// be blob names, e.g., names read from objects
+    fn named_blob_path(&self, name: &String) -> Result<PathBuf> {
+        if name.get(..2).is_none() {
+            return Err(Error::new(ErrorKind::NotFound, format!("no blob is named {:?}", name)));
+        }
+        Ok(self.blob_path(name))
+    }
+
+    fn plaintext_dir(&self) -> &Path {
+        self.plaintext_dir.as_deref().map_or(Path::new(PLAINTEXT_DIR), Path::new)
+    }
+
+    fn plaintext_path(&self, name: &str) -> PathBuf {
+        self.plaintext_dir().join(name)
+    }
+
+    /// Returns whether blobs that are not encrypted are refused
+    fn is_strict(&self) -> bool {
+        Path::new(&self.base_dir).join(STRICT_MARKER).exists()
+    }
+
+    fn refuse_plaintext(&self, name: &str) -> Result<()> {
+        if self.is_strict() {
+            return Err(Error::new(ErrorKind::InvalidData, format!("blob {} is not encrypted", name)));
+        }
+        Ok(())
+    }
+
+    /// Writes the plaintext of the encrypted blob in `file` to `dst`
+    fn decrypt<W: Write>(&self, name: &str, mut file: File, dst: &mut W) -> Result<()> {
+        let key = self.key.as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("blob {} is encrypted but there is no key", name)))?;
+        encryption::decrypt(key, name, &mut file, dst)
+    }
+
+    /// Atomically replaces the blob at `path` with the encryption of `src`.
+    /// The encryption is staged in the base directory, as renaming across file
+    /// systems fails, and `list` skips files there.
+    fn write_encrypted<R: Read>(&self, key: &Key, name: &str, src: &mut R, path: &Path) -> Result<()> {
+        let mut sealed = NamedTempFile::new_in(&self.base_dir)?;
+        encryption::encrypt(key, name, src, &mut sealed)?;
+        sealed.as_file().sync_data()?;
+        let file = sealed.persist(path)?;
+        let mut perms = file.metadata()?.permissions();
+        perms.set_readonly(true);
+        file.set_permissions(perms)
+    }
+
+    /// Returns the names of all saved blobs
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for dir in std::fs::read_dir(&self.base_dir)? {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in std::fs::read_dir(dir.path())? {
+                let file = file?;
+                if let (Some(d), Some(n)) = (dir.file_name().to_str(), file.file_name().to_str()) {
+                    names.push(format!("{}{}", d, n));
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    /// Records that the blob is in use. A blob's modification time is the last
+    /// time it was saved or used, which the garbage collector respects.
+    /// Opening a blob does not touch it, so in-flight invocations touch the
+    /// blobs they use.
+    pub fn touch(&self, name: &String) -> Result<()> {
+        File::open(self.named_blob_path(name)?)?.set_modified(SystemTime::now())
+    }
+
+    /// Returns the names of saved blobs that are not in `references`
+    pub fn unreferenced(&self, references: &HashMap<String, usize>) -> Result<Vec<String>> {
+        Ok(self.list()?.into_iter().filter(|name| !references.contains_key(name)).collect())
+    }
+
+    /// Deletes the blob unless it was saved or used within `grace`. Returns
+    /// whether the blob was deleted.
+    ///
+    /// In-flight invocations are protected only by the grace period, so it must
+    /// be longer than any invocation.
+    pub fn remove_if_unused(&self, name: &String, grace: Duration) -> Result<bool> {
+        let path = self.blob_path(name);
+        let last_used = std::fs::metadata(&path)?.modified()?;
+        if last_used.elapsed().is_ok_and(|elapsed| elapsed >= grace) {
+            std::fs::remove_file(path)?;
+            match std::fs::remove_file(self.plaintext_path(name)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Encrypts the saved blobs that are not encrypted, if there is a key, and
+    /// returns how many were encrypted. Blobs that are not encrypted are
+    /// refused afterwards, so processes saving blobs without the key must be
+    /// stopped first.
+    pub fn encrypt_plaintext(&self) -> Result<usize> {
+        let Some(key) = self.key.as_ref() else {
+            return Ok(0);
+        };
+        let mut encrypted = 0;
+        for name in self.list()? {
+            let path = self.blob_path(&name);
+            let mut file = File::open(&path)?;
+            if !encryption::is_encrypted(&file)? {
+                self.write_encrypted(key, &name, &mut file, &path)?;
+                encrypted += 1;
+            }
+        }
+        File::create(Path::new(&self.base_dir).join(STRICT_MARKER))?.sync_all()?;
+        Ok(encrypted)
     }
 }
 
@@ -40,20 +209,49 @@ impl<D: Digest> Blobstore<D> {
     }
 
     pub fn open(&self, name: String) -> Result<Blob> {
-        let blob_path = {
-            let (d, n) = name.split_at(2);
-            PathBuf::from(&self.base_dir).join(d).join(n)
-        };
+        let file = File::open(self.named_blob_path(&name)?)?;
+        if !encryption::is_encrypted(&file)? {
+            self.refuse_plaintext(&name)?;
+            return Ok(Blob {
+                name,
+                file
+            });
+        }
+        let mut plaintext = tempfile::tempfile_in(&self.tmp_dir)?;
+        self.decrypt(&name, file, &mut plaintext)?;
+        plaintext.rewind()?;
         Ok(Blob {
             name,
-            file: File::open(blob_path)?
+            file: plaintext
         })
     }
 
     // a hack, see the place that calls vm.launch in worker.rs
     pub fn local_path_string(&self, name: &String) -> Option<String> {
-        let (d, n) = name.split_at(2);
-        PathBuf::from(&self.base_dir).join(d).join(n).into_os_string().into_string().ok()
+        let path = self.blob_path(name);
+        let file = File::open(&path).ok()?;
+        if !encryption::is_encrypted(&file).ok()? {
+            if let Err(e) = self.refuse_plaintext(name) {
+                log::error!("Refused blob {}: {}", name, e);
+                return None;
+            }
+            return path.into_os_string().into_string().ok();
+        }
+        let plaintext_path = self.plaintext_path(name);
+        if !plaintext_path.exists() {
+            let res = (|| -> Result<()> {
+                DirBuilder::new().recursive(true).mode(0o700).create(self.plaintext_dir())?;
+                let mut plaintext = NamedTempFile::new_in(self.plaintext_dir())?;
+                self.decrypt(name, file, &mut plaintext)?;
+                plaintext.persist(&plaintext_path)?;
+                Ok(())
+            })();
+            if let Err(e) = res {
+                log::error!("Failed to decrypt blob {}: {}", name, e);
+                return None;
+            }
+        }
+        plaintext_path.into_os_string().into_string().ok()
     }
 
     pub fn save(&mut self, new_blob: NewBlob<D>) -> Result<Blob> {
@@ -65,6 +263,14 @@ impl<D: Digest> Blobstore<D> {
         hpath.push(dir);
         let _ = std::fs::create_dir_all(hpath.clone());
         hpath.push(fname);
+        if let Some(key) = self.key.as_ref() {
+            self.write_encrypted(key, &name, &mut new_blob.file.reopen()?, &hpath)?;
+            // the returned blob reads the plaintext, which is gone once closed
+            return Ok(Blob {
+                name,
+                file: new_blob.file.reopen()?
+            });
+        }
         let file = new_blob.file.persist(hpath)?;
         let mut perms = file.metadata()?.permissions();
         perms.set_readonly(true);
@@ -130,3 +336,56 @@ impl<D: Digest> Write for NewBlob<D> {
         self.file.flush()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypted_blobstore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (base_dir, tmp_dir) = (tmp.path().join("blobs"), tmp.path().join("tmp"));
+        std::fs::create_dir_all(&tmp_dir).unwrap();
+        let mut plain: Blobstore = Blobstore::new(base_dir.clone().into(), tmp_dir.clone().into());
+        let mut legacy = plain.create().unwrap();
+        legacy.write_all(b"legacy").unwrap();
+        let legacy = plain.save(legacy).unwrap().name;
+
+        let key = Key::generate();
+        let mut blobstore: Blobstore = Blobstore::new(base_dir.into(), tmp_dir.into())
+            .with_key(Some(key.clone()))
+            .with_plaintext_dir(tmp.path().join("plaintext").into());
+        let data: Vec<u8> = (0..2 * encryption::CHUNK_LEN + 7).map(|i| i as u8).collect();
+        let mut new_blob = blobstore.create().unwrap();
+        new_blob.write_all(&data).unwrap();
+        let name = blobstore.save(new_blob).unwrap().name;
+        assert!(std::fs::read(blobstore.blob_path(&name)).unwrap().starts_with(encryption::MAGIC));
+
+        let mut read = Vec::new();
+        blobstore.open(name.clone()).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(std::fs::read(blobstore.local_path_string(&name).unwrap()).unwrap(), data);
+        assert!(plain.open(name.clone()).is_err());
+
+        let legacy_path = blobstore.blob_path(&legacy);
+        assert_eq!(blobstore.local_path_string(&legacy), legacy_path.to_str().map(String::from));
+        assert_eq!(blobstore.encrypt_plaintext().unwrap(), 1);
+        let mut read = Vec::new();
+        blobstore.open(legacy.clone()).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"legacy");
+        assert_eq!(blobstore.list().unwrap().len(), 2);
+
+        // a blob replaced by plaintext is refused once all are encrypted
+        std::fs::remove_file(&legacy_path).unwrap();
+        std::fs::write(&legacy_path, b"replaced").unwrap();
+        assert_eq!(blobstore.open(legacy.clone()).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(blobstore.local_path_string(&legacy).is_none());
+
+        // a truncated blob fails to authenticate
+        let path = blobstore.blob_path(&name);
+        let sealed = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, &sealed[..sealed.len() - 7 - crate::crypto::TAG_LEN]).unwrap();
+        assert_eq!(blobstore.open(name).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -2,6 +2,12 @@
 
 use clap::{Args, Parser};
 
+use crate::crypto::Key;
+use crate::fs::encrypted::EncryptedStore;
+use crate::fs::instrument::InstrumentedStore;
+use crate::fs::shard::{ReplicatedStore, ShardedStore};
+use crate::fs::BackingStore;
+
 #[derive(Parser, Debug)]
 pub struct VmConfig {
     /// MicroVM ID
@@ -93,7 +99,108 @@ pub struct Store {
     /// Space delimited addresses of TiKV PDs
     #[arg(long, value_name = "ADDR:PORT")]
     pub tikv: Option<Vec<String>>,
-    /// Path of the LMDB directory
+    /// Paths of LMDB directories, sharding keys across them if several
+    #[arg(long, value_name = "PATH")]
+    pub lmdb: Option<Vec<String>>,
+    /// Paths of directories storing each key in a file, sharding keys across
+    /// them if several
     #[arg(long, value_name = "PATH")]
-    pub lmdb: Option<String>,
+    pub dir: Option<Vec<String>>,
+    /// Use a volatile in-memory store. Everything is lost on exit
+    #[arg(long)]
+    pub in_memory: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct Replication {
+    /// Synchronously replicate writes to a store of the same kind as
+    /// `--lmdb` or `--dir` at these paths. Every process writing the store
+    /// must set it
+    #[arg(long, value_name = "PATH", conflicts_with = "tikv")]
+    pub replica: Option<Vec<String>>,
+    /// Overwrite the replica when reads find it out of date
+    #[arg(long, requires = "replica")]
+    pub read_repair: bool,
+}
+
+impl Replication {
+    /// Opens the shards at `paths` and those of the replica, if any, with `open`
+    pub fn open<S, F>(&self, paths: &[String], mut open: F) -> ReplicatedStore<ShardedStore<S>>
+    where
+        S: BackingStore,
+        F: FnMut(&String) -> S,
+    {
+        let primary = ShardedStore::new(paths.iter().map(&mut open).collect());
+        let replica = self.replica.as_ref().map(|paths| ShardedStore::new(paths.iter().map(&mut open).collect()));
+        let store = ReplicatedStore::new(primary, replica);
+        if self.read_repair {
+            store.with_read_repair()
+        } else {
+            store
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct Cache {
+    /// Cache up to this many bytes of recently read objects in memory, none
+    /// if zero
+    #[arg(long, value_name = "BYTES", default_value_t = 0)]
+    pub cache_bytes: usize,
+    /// Check for writes by other processes at most this often and drop what
+    /// they changed from the cache. Every process writing the store must set
+    /// it, even those caching nothing
+    #[arg(long, value_name = "MS")]
+    pub cache_max_staleness: Option<u64>,
+}
+
+impl Cache {
+    /// Layers the configured cache over `store`
+    pub fn layer<S: BackingStore>(&self, store: S) -> crate::fs::cache::CachingStore<S> {
+        let cache = crate::fs::cache::CachingStore::new(store, self.cache_bytes);
+        match self.cache_max_staleness {
+            Some(ms) => cache.with_version_checks(std::time::Duration::from_millis(ms)),
+            None => cache,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct Encryption {
+    /// Encrypt stored objects and blobs with the key in this file. Objects
+    /// and blobs stored before are still read until they are encrypted, after
+    /// which reading unencrypted objects and blobs fails
+    #[arg(long, value_name = "PATH")]
+    pub key_file: Option<String>,
+}
+
+impl Encryption {
+    /// Reads the configured key, if any
+    pub fn key(&self) -> Option<Key> {
+        self.key_file.as_ref().map(|path| Key::load(path).unwrap_or_else(|e| panic!("read key file {}: {}", path, e)))
+    }
+
+    /// Layers encryption with the configured key, if any, over `store`
+    pub fn layer<S: BackingStore>(&self, store: S) -> EncryptedStore<S> {
+        EncryptedStore::new(store, self.key())
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct Metrics {
+    /// Log the totals of the store operations, with their latency
+    /// histograms, this often
+    #[arg(long, value_name = "SECS")]
+    pub store_metrics_interval: Option<u64>,
+}
+
+impl Metrics {
+    /// Layers instrumentation over `store`, logging its totals as configured
+    pub fn layer<S: BackingStore>(&self, store: S) -> InstrumentedStore<S> {
+        let store = InstrumentedStore::new(store);
+        if let Some(secs) = self.store_metrics_interval {
+            store.log_totals_every(std::time::Duration::from_secs(secs));
+        }
+        store
+    }
 }
@@ -0,0 +1,127 @@
+//! Authenticated encryption of data at rest
+//!
+//! Objects and blobs are encrypted with AES-256-GCM under a key read from a
+//! local key file of `KEY_LEN` random bytes. Every sealed value has a random
+//! nonce of its own, which keeps nonces from repeating for up to about 2^32
+//! values sealed under the same key.
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use rand::RngCore;
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+pub type Nonce = [u8; NONCE_LEN];
+
+pub fn random_nonce() -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key([u8; KEY_LEN]);
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+impl Key {
+    pub fn generate() -> Self {
+        let mut key = [0; KEY_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut key);
+        Key(key)
+    }
+
+    /// Reads a key file, which must hold exactly `KEY_LEN` bytes
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        std::fs::File::open(path)?.read_to_end(&mut bytes)?;
+        let key = bytes.try_into().map_err(|bytes: Vec<u8>| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("a key file holds {} bytes, not {}", KEY_LEN, bytes.len()),
+        ))?;
+        Ok(Key(key))
+    }
+
+    /// Writes a new key file readable only by its owner. Fails if the file
+    /// exists, so a key in use is never overwritten.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+        file.write_all(&self.0)?;
+        file.sync_all()
+    }
+
+    /// Encrypts `plaintext` under a random nonce, authenticating `aad` along
+    /// with it. Returns the nonce followed by the ciphertext and the tag.
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let nonce = random_nonce();
+        let mut sealed = nonce.to_vec();
+        sealed.extend(self.seal_with_nonce(&nonce, plaintext, aad));
+        sealed
+    }
+
+    /// Decrypts what `seal` returned for the same `aad`, or returns `None` if
+    /// it fails to authenticate
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+        self.open_with_nonce(nonce.try_into().unwrap(), sealed, aad)
+    }
+
+    /// Encrypts `plaintext` under `nonce`, which must never be used with this
+    /// key for another plaintext. Returns the ciphertext followed by the tag.
+    pub(crate) fn seal_with_nonce(&self, nonce: &Nonce, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut tag = [0; TAG_LEN];
+        let mut sealed = encrypt_aead(Cipher::aes_256_gcm(), &self.0, Some(nonce), aad, plaintext, &mut tag)
+            .expect("AES-GCM encryption");
+        sealed.extend_from_slice(&tag);
+        sealed
+    }
+
+    pub(crate) fn open_with_nonce(&self, nonce: &Nonce, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < TAG_LEN {
+            return None;
+        }
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+        decrypt_aead(Cipher::aes_256_gcm(), &self.0, Some(nonce), aad, ciphertext, tag).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let key = Key::generate();
+        let sealed = key.seal(b"secret", b"aad");
+        assert_eq!(sealed.len(), NONCE_LEN + 6 + TAG_LEN);
+        assert_ne!(key.seal(b"secret", b"aad"), sealed);
+        assert_eq!(key.open(&sealed, b"aad"), Some(b"secret".to_vec()));
+        assert_eq!(key.open(&sealed, b"other"), None);
+        assert_eq!(Key::generate().open(&sealed, b"aad"), None);
+        let mut tampered = sealed.clone();
+        tampered[NONCE_LEN] ^= 1;
+        assert_eq!(key.open(&tampered, b"aad"), None);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("key");
+        key.save(&path).unwrap();
+        assert!(key.save(&path).is_err());
+        assert_eq!(Key::load(&path).unwrap(), key);
+        std::fs::write(&path, b"short").unwrap();
+        assert!(Key::load(&path).is_err());
+    }
+}
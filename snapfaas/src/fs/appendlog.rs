@@ -0,0 +1,194 @@
+//! Labeled append-only logs
+//!
+//! A log is an ordered sequence of records, each identified by its offset,
+//! i.e., its position since the log was created. Records are appended to an
+//! inline tail, which is sealed into an immutable segment object once it holds
+//! `SEGMENT_LEN` records, and read from any offset that has not been trimmed.
+//! Like chunks of files, segments are unlabeled and only reachable through
+//! their log. Record bytes are charged to the log's creator as file bytes.
+use super::*;
+
+pub const SEGMENT_LEN: usize = 256;
+
+pub type Segment = Vec<Vec<u8>>;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Log {
+    /// Offset of the first record that has not been trimmed
+    start: u64,
+    /// Offset of the first record of the first segment, or of the tail if
+    /// there are no segments
+    base: u64,
+    /// Sealed segments of `SEGMENT_LEN` records and their sizes in bytes
+    segments: Vec<(ObjectRef<Segment>, u64)>,
+    tail: Vec<Vec<u8>>,
+}
+
+impl Log {
+    /// Returns the offset the next record will be appended at
+    pub fn end(&self) -> u64 {
+        self.base + (self.segments.len() * SEGMENT_LEN + self.tail.len()) as u64
+    }
+
+    /// Returns the number of records that have not been trimmed
+    pub fn len(&self) -> u64 {
+        self.end() - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(super) fn segments(&self) -> impl Iterator<Item = &ObjectRef<Segment>> {
+        self.segments.iter().map(|(segment, _)| segment)
+    }
+
+    /// Bytes of records in segments and the tail, which includes trimmed
+    /// records until their segment is dropped
+    pub(super) fn size(&self) -> u64 {
+        self.segments.iter().map(|(_, size)| size).sum::<u64>() + records_size(&self.tail)
+    }
+
+    fn append(&mut self, record: Vec<u8>, txn: &mut Transaction) {
+        self.tail.push(record);
+        if self.tail.len() == SEGMENT_LEN {
+            let segment = std::mem::take(&mut self.tail);
+            let size = records_size(&segment);
+            self.segments.push((ObjectRef::stage_new(&segment, txn), size));
+        }
+    }
+
+    /// Returns the offset of the first record read and up to `max` records
+    /// starting at `offset`, or at the start if `offset` has been trimmed
+    fn read_from<B: BackingStore>(&self, offset: u64, max: usize, fs: &FS<B>) -> Result<(u64, Vec<Vec<u8>>), FsError> {
+        let first = offset.clamp(self.start, self.end());
+        let mut records = Vec::new();
+        let mut pos = (first - self.base) as usize;
+        let sealed = self.segments.len() * SEGMENT_LEN;
+        while records.len() < max && pos < sealed {
+            let segment_ref = &self.segments[pos / SEGMENT_LEN].0;
+            let segment = segment_ref.get(fs)?;
+            let from = pos % SEGMENT_LEN;
+            let n = (SEGMENT_LEN - from).min(max - records.len());
+            records.extend_from_slice(segment.get(from..from + n).ok_or(FsError::CorruptedObject(segment_ref.uid))?);
+            pos += n;
+        }
+        if records.len() < max && pos >= sealed {
+            let from = pos - sealed;
+            let n = (self.tail.len() - from).min(max - records.len());
+            records.extend_from_slice(&self.tail[from..from + n]);
+        }
+        Ok((first, records))
+    }
+
+    /// Returns the offset of the first record that has not been trimmed and
+    /// all records from there on
+    pub(super) fn records<B: BackingStore>(&self, fs: &FS<B>) -> Result<(u64, Vec<Vec<u8>>), FsError> {
+        self.read_from(self.start, usize::MAX, fs)
+    }
+
+    /// Returns a log holding `records` from offset `start` on, staging its
+    /// segments in `txn`
+    pub(super) fn staged(start: u64, records: Vec<Vec<u8>>, txn: &mut Transaction) -> Self {
+        let mut log = Log { start, base: start, ..Default::default() };
+        for record in records {
+            log.append(record, txn);
+        }
+        log
+    }
+
+    /// Discards records before `offset`. Segments are dropped once all their
+    /// records are discarded.
+    fn trim(&mut self, offset: u64) {
+        self.start = self.start.max(offset.min(self.end()));
+        let dropped = (((self.start - self.base) as usize) / SEGMENT_LEN).min(self.segments.len());
+        self.segments.drain(..dropped);
+        self.base += (dropped * SEGMENT_LEN) as u64;
+        if self.segments.is_empty() {
+            self.tail.drain(..(self.start - self.base) as usize);
+            self.base = self.start;
+        }
+    }
+}
+
+fn records_size(records: &[Vec<u8>]) -> u64 {
+    records.iter().map(|r| r.len() as u64).sum()
+}
+
+impl ObjectRef<Labeled<Log>> {
+    /// Appends `record`. Does not taint the current label.
+    pub fn push<B: BackingStore>(&self, record: Vec<u8>, fs: &FS<B>, ctx: &SecurityContext) -> Result<(), FsError> {
+        self.update(fs, ctx, record.len() as u64, |log, txn| log.append(record.clone(), txn))
+    }
+
+    /// Returns the offset of the first record read and up to `max` records
+    /// starting at `offset`, or at the first record that has not been trimmed
+    /// if `offset` has been.
+    pub fn read_from<B: BackingStore>(&self, offset: u64, max: usize, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<(u64, Vec<Vec<u8>>), FsError> {
+        self.get(fs)?.unlabel(ctx)?.read_from(offset, max, fs)
+    }
+
+    /// Discards the records before `offset`. Does not taint the current label.
+    pub fn trim<B: BackingStore>(&self, offset: u64, fs: &FS<B>, ctx: &SecurityContext) -> Result<(), FsError> {
+        self.update(fs, ctx, 0, |log, _| log.trim(offset))
+    }
+
+    /// Applies `f` to the log and commits the result, retrying on conflicts.
+    /// Changes in size are charged to the log's creator, and the `written`
+    /// bytes must fit in its quota.
+    fn update<B, F>(&self, fs: &FS<B>, ctx: &SecurityContext, written: u64, mut f: F) -> Result<(), FsError>
+    where
+        B: BackingStore,
+        F: FnMut(&mut Log, &mut Transaction),
+    {
+        let key = self.uid.to_be_bytes();
+        loop {
+            let (prev, mut labeled) = self.get_encoded(fs)?;
+            let mut txn = Transaction::default();
+            let old_size = labeled.data.size();
+            labeled.modify_blind(ctx, |log| f(log, &mut txn))?;
+            let charge = Charge::blind_file_bytes(old_size, labeled.data.size(), written);
+            fs.stage_charge(&labeled.meta.creator, &charge, &mut txn)?;
+            txn.check(&key, Some(&prev));
+            txn.put(&key, encoding::encode(&labeled).as_slice());
+            if fs.0.commit(&txn) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+
+    #[test]
+    fn test_log() {
+        let fs = FS::new(MemoryStore::new());
+        let mut ctx = SecurityContext::default();
+        let log: ObjectRef<Labeled<Log>> = ObjectRef::create(Buckle::public(), &fs.0, &ctx);
+        let n = SEGMENT_LEN as u64 * 2 + 3;
+        for i in 0..n {
+            log.push(i.to_be_bytes().to_vec(), &fs, &ctx).unwrap();
+        }
+        let record = |i: u64| i.to_be_bytes().to_vec();
+        assert_eq!(log.get(&fs).unwrap().data.segments.len(), 2);
+
+        let (first, records) = log.read_from(SEGMENT_LEN as u64 - 1, 3, &fs, &mut ctx).unwrap();
+        assert_eq!(first, SEGMENT_LEN as u64 - 1);
+        assert_eq!(records, (first..first + 3).map(record).collect::<Vec<_>>());
+        let (first, records) = log.read_from(n - 2, 10, &fs, &mut ctx).unwrap();
+        assert_eq!((first, records), (n - 2, vec![record(n - 2), record(n - 1)]));
+
+        log.trim(SEGMENT_LEN as u64 + 1, &fs, &ctx).unwrap();
+        assert_eq!(log.get(&fs).unwrap().data.segments.len(), 1);
+        let (first, records) = log.read_from(0, 1, &fs, &mut ctx).unwrap();
+        assert_eq!((first, records), (SEGMENT_LEN as u64 + 1, vec![record(SEGMENT_LEN as u64 + 1)]));
+
+        log.trim(n, &fs, &ctx).unwrap();
+        assert!(log.get(&fs).unwrap().data.is_empty());
+        log.push(record(n), &fs, &ctx).unwrap();
+        assert_eq!(log.read_from(0, 10, &fs, &mut ctx).unwrap(), (n, vec![record(n)]));
+    }
+}
@@ -0,0 +1,433 @@
+//! Portable archives of subtrees
+//!
+//! `FS::export` captures the objects reachable from an entry in an `Archive`,
+//! keyed by their uids at export time, and `FS::import` recreates them under
+//! fresh uids in any file system, e.g., one backed by a different store. Like
+//! the garbage collector, both read and write objects directly, preserving
+//! labels and metadata without checking or tainting any label, and import does
+//! not charge quotas. They must only be run by trusted tooling (e.g.,
+//! `admin_fstools`).
+//!
+//! Archives are stored as a header line, the archive as a line of JSON and
+//! the contents of each blob it references, so they are self-contained:
+//!
+//! ```text
+//! faasten-archive 1
+//! {"root":...,"objects":{...}}
+//! blob <name> <length>
+//! <length bytes>
+//! ...
+//! ```
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Read, Write};
+
+use crate::blobstore::Blobstore;
+
+use super::*;
+
+const ARCHIVE_HEADER: &str = "faasten-archive 1";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Archive {
+    /// Key of the exported entry in `objects`
+    pub root: u64,
+    pub objects: BTreeMap<u64, ArchivedObject>,
+}
+
+/// An object with its contents inlined and its references to other objects
+/// replaced by their keys in the archive. Bytes are hex-encoded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ArchivedObject {
+    Directory { label: Buckle, meta: Metadata, entries: BTreeMap<String, u64> },
+    FacetedDirectory { facets: Vec<(Buckle, u64)> },
+    File { label: Buckle, meta: Metadata, data: String },
+    DirectGate { label: Buckle, meta: Metadata, gate: DirectGate },
+    RedirectGate {
+        label: Buckle,
+        meta: Metadata,
+        privilege: Component,
+        invoker_integrity_clearance: Component,
+        declassify: Component,
+        gate: u64,
+    },
+    Service { label: Buckle, meta: Metadata, service: Service },
+    Blob { label: Buckle, meta: Metadata, blob: Blob },
+    Map { label: Buckle, meta: Metadata, entries: BTreeMap<String, String> },
+    /// Records from offset `start` on
+    Log { label: Buckle, meta: Metadata, start: u64, records: Vec<String> },
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn decode_hex(uid: u64, data: &str) -> Result<Vec<u8>, FsError> {
+    hex::decode(data).map_err(|_| FsError::CorruptedObject(uid))
+}
+
+impl Archive {
+    /// Returns the names of the blobs referenced by blob objects and by the
+    /// functions of direct gates
+    pub fn blobs(&self) -> BTreeSet<Blob> {
+        let mut blobs = BTreeSet::new();
+        for object in self.objects.values() {
+            match object {
+                ArchivedObject::Blob { blob, .. } => {
+                    blobs.insert(blob.clone());
+                },
+                ArchivedObject::DirectGate { gate, .. } => {
+                    let function = &gate.function;
+                    blobs.extend([&function.app_image, &function.runtime_image, &function.kernel].map(Clone::clone));
+                },
+                _ => (),
+            }
+        }
+        blobs.remove("");
+        blobs
+    }
+
+    /// Writes the archive followed by the contents of the blobs it references,
+    /// read from `blobstore`
+    pub fn write<W: Write>(&self, mut out: W, blobstore: &Blobstore) -> io::Result<()> {
+        writeln!(out, "{}", ARCHIVE_HEADER)?;
+        serde_json::to_writer(&mut out, self)?;
+        writeln!(out)?;
+        for name in self.blobs() {
+            let mut blob = blobstore.open(name.clone())?;
+            writeln!(out, "blob {} {}", name, blob.len()?)?;
+            io::copy(&mut blob, &mut out)?;
+        }
+        out.flush()
+    }
+
+    /// Reads an archive written by `write`, saving the blobs it contains to
+    /// `blobstore`
+    pub fn read<R: BufRead>(mut input: R, blobstore: &mut Blobstore) -> io::Result<Archive> {
+        let mut line = String::new();
+        input.read_line(&mut line)?;
+        if line.trim_end() != ARCHIVE_HEADER {
+            return Err(invalid_data("not a Faasten archive"));
+        }
+        line.clear();
+        input.read_line(&mut line)?;
+        let archive: Archive = serde_json::from_str(&line)?;
+        loop {
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(archive);
+            }
+            let (name, len) = match line.trim_end().split(' ').collect::<Vec<_>>()[..] {
+                ["blob", name, len] => (name.to_string(), len.parse::<u64>().map_err(invalid_data)?),
+                _ => return Err(invalid_data("malformed blob header")),
+            };
+            let mut blob = blobstore.create()?;
+            if io::copy(&mut (&mut input).take(len), &mut blob)? != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if blobstore.save(blob)?.name != name {
+                return Err(invalid_data(format!("blob {} does not match its contents", name)));
+            }
+        }
+    }
+}
+
+impl<S: BackingStore> FS<S> {
+    /// Returns an archive of the entry at `path` and every object reachable
+    /// from it, including the targets of redirect gates outside the subtree.
+    /// Fails on dangling references rather than export a partial subtree.
+    pub fn export<P: Into<Path>>(&self, path: P) -> Result<Archive, FsError> {
+        let root = self.read_path(path, &mut SecurityContext::default())?;
+        let mut objects = BTreeMap::new();
+        let mut stack = vec![root.clone()];
+        while let Some(entry) = stack.pop() {
+            let uid = entry.uid();
+            if objects.contains_key(&uid) {
+                continue;
+            }
+            let object = match entry {
+                DirEntry::Directory(dir) => {
+                    let Labeled { label, data, meta } = dir.get(self)?;
+                    stack.extend(data.entries.values().cloned());
+                    let entries = data.entries.iter().map(|(name, entry)| (name.clone(), entry.uid())).collect();
+                    ArchivedObject::Directory { label, meta, entries }
+                },
+                DirEntry::FacetedDirectory(faceted) => {
+                    let faceted = match faceted.get(self) {
+                        Err(FsError::DanglingReference(_)) => FacetedDirectory::default(),
+                        res => res?,
+                    };
+                    stack.extend(faceted.facets.iter().map(|(_, dir)| DirEntry::Directory(*dir)));
+                    let facets = faceted.facets.into_iter().map(|(label, dir)| (label, dir.uid)).collect();
+                    ArchivedObject::FacetedDirectory { facets }
+                },
+                DirEntry::File(file) => {
+                    let Labeled { label, data, meta } = file.get(self)?;
+                    let data = hex::encode(data.read_range(0, data.len(), self)?);
+                    ArchivedObject::File { label, meta, data }
+                },
+                DirEntry::Gate(gate) => match gate.get(self)? {
+                    Labeled { label, data: Gate::Direct(gate), meta } => ArchivedObject::DirectGate { label, meta, gate },
+                    Labeled { label, data: Gate::Redirect(redirect), meta } => {
+                        stack.push(DirEntry::Gate(redirect.gate));
+                        ArchivedObject::RedirectGate {
+                            label,
+                            meta,
+                            privilege: redirect.privilege,
+                            invoker_integrity_clearance: redirect.invoker_integrity_clearance,
+                            declassify: redirect.declassify,
+                            gate: redirect.gate.uid,
+                        }
+                    },
+                },
+                DirEntry::Service(service) => {
+                    let Labeled { label, data, meta } = service.get(self)?;
+                    ArchivedObject::Service { label, meta, service: data }
+                },
+                DirEntry::Blob(blob) => {
+                    let Labeled { label, data, meta } = blob.get(self)?;
+                    ArchivedObject::Blob { label, meta, blob: data }
+                },
+                DirEntry::Map(map) => {
+                    let Labeled { label, data, meta } = map.get(self)?;
+                    let entries = data.into_iter().map(|(key, value)| (key, hex::encode(value))).collect();
+                    ArchivedObject::Map { label, meta, entries }
+                },
+                DirEntry::Log(log) => {
+                    let Labeled { label, data, meta } = log.get(self)?;
+                    let (start, records) = data.records(self)?;
+                    ArchivedObject::Log { label, meta, start, records: records.into_iter().map(hex::encode).collect() }
+                },
+            };
+            objects.insert(uid, object);
+        }
+        Ok(Archive { root: root.uid(), objects })
+    }
+
+    /// Recreates the objects of `archive` under fresh uids and links its root
+    /// at `dest`, and returns the root. If the last component of `dest` is a
+    /// facet, the root must be a directory with that label, which becomes
+    /// the facet. Fails with `FsError::NameExists` if `dest` exists.
+    ///
+    /// The objects and the link are committed in a single transaction, so an
+    /// import that fails, e.g., on an archive with dangling references,
+    /// leaves nothing behind.
+    pub fn import<P: Into<Path>>(&self, archive: &Archive, dest: P) -> Result<DirEntry, FsError> {
+        let dest: Path = dest.into();
+        let last = dest.last().cloned().ok_or(FsError::BadPath)?;
+        let parent = self.read_path(dest.parent().unwrap_or_else(Path::root), &mut SecurityContext::default())?;
+        match (&parent, &last, archive.objects.get(&archive.root)) {
+            (DirEntry::Directory(_), PathComponent::Dscrp(_), Some(_)) => (),
+            (DirEntry::FacetedDirectory(_), PathComponent::Facet(facet), Some(ArchivedObject::Directory { label, .. })) if label == facet => (),
+            (_, _, None) => return Err(FsError::DanglingReference(archive.root)),
+            _ => return Err(FsError::BadPath),
+        }
+
+        loop {
+            let mut txn = Transaction::default();
+            let mut entries = BTreeMap::new();
+            for (&key, object) in archive.objects.iter() {
+                let uid: u64 = rand::random();
+                txn.check(&uid.to_be_bytes(), None);
+                entries.insert(key, archived_entry(object, uid));
+            }
+            for (key, object) in archive.objects.iter() {
+                stage_object(&entries[key], object, &entries, &mut txn)?;
+            }
+            let root = entries[&archive.root].clone();
+
+            match (&parent, &last, &root) {
+                (DirEntry::Directory(dir), PathComponent::Dscrp(name), _) => {
+                    let (prev, mut labeled_dir) = dir.get_encoded(self)?;
+                    if labeled_dir.data.entries.contains_key(name) {
+                        return Err(FsError::NameExists);
+                    }
+                    labeled_dir.data.entries.insert(name.clone(), root.clone());
+                    labeled_dir.meta.modified = now_millis();
+                    txn.check(&dir.uid.to_be_bytes(), Some(&prev));
+                    txn.put(&dir.uid.to_be_bytes(), encoding::encode(&labeled_dir).as_slice());
+                },
+                (DirEntry::FacetedDirectory(faceted), PathComponent::Facet(facet), DirEntry::Directory(root_dir)) => {
+                    let prev = match faceted.get_encoded(self) {
+                        Ok(prev) => Some(prev),
+                        Err(FsError::DanglingReference(_)) => None,
+                        Err(e) => return Err(e),
+                    };
+                    let mut facets = prev.as_ref().map(|(_, f)| f.clone()).unwrap_or_default();
+                    if facets.facets.iter().any(|(label, _)| label == facet) {
+                        return Err(FsError::NameExists);
+                    }
+                    facets.facets.push((facet.clone(), *root_dir));
+                    txn.check(&faceted.uid.to_be_bytes(), prev.as_ref().map(|(e, _)| e.as_slice()));
+                    txn.put(&faceted.uid.to_be_bytes(), encoding::encode(&facets).as_slice());
+                },
+                _ => return Err(FsError::BadPath),
+            }
+            // fails if the destination changed or a fresh uid was taken
+            if self.0.commit(&txn) {
+                return Ok(root);
+            }
+        }
+    }
+}
+
+/// Stages storing `object` as `entry` in `txn`, translating the archive keys
+/// it references with `entries`
+fn stage_object(entry: &DirEntry, object: &ArchivedObject, entries: &BTreeMap<u64, DirEntry>, txn: &mut Transaction) -> Result<(), FsError> {
+    let uid = entry.uid();
+    let translate = |key: &u64| entries.get(key).cloned().ok_or(FsError::DanglingReference(*key));
+    fn labeled<T>(label: &Buckle, meta: &Metadata, data: T) -> Labeled<T> {
+        Labeled { label: label.clone(), data, meta: meta.clone() }
+    }
+    let encoded = match object {
+        ArchivedObject::Directory { label, meta, entries } => {
+            let mut directory = Directory::default();
+            for (name, key) in entries.iter() {
+                directory.entries.insert(name.clone(), translate(key)?);
+            }
+            encoding::encode(&labeled(label, meta, directory))
+        },
+        ArchivedObject::FacetedDirectory { facets } => {
+            let mut faceted = FacetedDirectory::default();
+            for (facet, key) in facets.iter() {
+                let DirEntry::Directory(dir) = translate(key)? else {
+                    return Err(FsError::CorruptedObject(uid));
+                };
+                faceted.facets.push((facet.clone(), dir));
+            }
+            encoding::encode(&faceted)
+        },
+        ArchivedObject::File { label, meta, data } => {
+            let file = File::staged(&decode_hex(uid, data)?, txn);
+            encoding::encode(&labeled(label, meta, file))
+        },
+        ArchivedObject::DirectGate { label, meta, gate } => {
+            encoding::encode(&labeled(label, meta, Gate::Direct(gate.clone())))
+        },
+        ArchivedObject::RedirectGate { label, meta, privilege, invoker_integrity_clearance, declassify, gate } => {
+            let DirEntry::Gate(gate) = translate(gate)? else {
+                return Err(FsError::CorruptedObject(uid));
+            };
+            encoding::encode(&labeled(label, meta, Gate::Redirect(RedirectGate {
+                privilege: privilege.clone(),
+                invoker_integrity_clearance: invoker_integrity_clearance.clone(),
+                declassify: declassify.clone(),
+                gate,
+            })))
+        },
+        ArchivedObject::Service { label, meta, service } => {
+            encoding::encode(&labeled(label, meta, service.clone()))
+        },
+        ArchivedObject::Blob { label, meta, blob } => {
+            encoding::encode(&labeled(label, meta, blob.clone()))
+        },
+        ArchivedObject::Map { label, meta, entries } => {
+            let mut map = Map::new();
+            for (key, value) in entries.iter() {
+                map.insert(key.clone(), decode_hex(uid, value)?);
+            }
+            encoding::encode(&labeled(label, meta, map))
+        },
+        ArchivedObject::Log { label, meta, start, records } => {
+            let records = records.iter().map(|record| decode_hex(uid, record)).collect::<Result<_, _>>()?;
+            let log = Log::staged(*start, records, txn);
+            encoding::encode(&labeled(label, meta, log))
+        },
+    };
+    txn.put(&uid.to_be_bytes(), encoded.as_slice());
+    Ok(())
+}
+
+/// Returns the entry referring to `object` stored under `uid`
+fn archived_entry(object: &ArchivedObject, uid: u64) -> DirEntry {
+    match object {
+        ArchivedObject::Directory { .. } => DirEntry::Directory(ObjectRef::new(uid)),
+        ArchivedObject::FacetedDirectory { .. } => DirEntry::FacetedDirectory(ObjectRef::new(uid)),
+        ArchivedObject::File { .. } => DirEntry::File(ObjectRef::new(uid)),
+        ArchivedObject::DirectGate { .. } | ArchivedObject::RedirectGate { .. } => DirEntry::Gate(ObjectRef::new(uid)),
+        ArchivedObject::Service { .. } => DirEntry::Service(ObjectRef::new(uid)),
+        ArchivedObject::Blob { .. } => DirEntry::Blob(ObjectRef::new(uid)),
+        ArchivedObject::Map { .. } => DirEntry::Map(ObjectRef::new(uid)),
+        ArchivedObject::Log { .. } => DirEntry::Log(ObjectRef::new(uid)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+
+    fn path(s: &str) -> Path {
+        Path::parse(s, &SecurityContext::default()).unwrap()
+    }
+
+    #[test]
+    fn test_export_import() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut blobstore = Blobstore::new(tmp.path().join("blobs").into(), tmp.path().into());
+        let mut image = blobstore.create().unwrap();
+        image.write_all(b"image").unwrap();
+        let image = blobstore.save(image).unwrap().name;
+
+        let src = FS::new(MemoryStore::new());
+        src.initialize();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        let alice = Buckle::parse("alice,alice").unwrap();
+        src.create_and_link(Path::root(), "app".into(), NewEntry::Directory(alice.clone()), &mut ctx).unwrap();
+        src.create_and_link(path(":app"), "f".into(), NewEntry::File(alice.clone(), b"hello".to_vec()), &mut ctx).unwrap();
+        let DirEntry::Map(map) = src.create_and_link(path(":app"), "m".into(), NewEntry::Map(alice.clone()), &mut ctx).unwrap() else { panic!("not a map") };
+        map.insert("k".into(), b"v".to_vec(), &src, &mut ctx).unwrap();
+        let DirEntry::Log(log) = src.create_and_link(path(":app"), "l".into(), NewEntry::Log(alice.clone()), &mut ctx).unwrap() else { panic!("not a log") };
+        for record in [b"a", b"b", b"c"] {
+            log.push(record.to_vec(), &src, &ctx).unwrap();
+        }
+        log.trim(1, &src, &ctx).unwrap();
+        let DirEntry::Gate(gate) = src.create_and_link(path(":app"), "g".into(), NewEntry::DirectGate(alice.clone(), DirectGate {
+            privilege: Component::dc_true(),
+            invoker_integrity_clearance: Component::dc_true(),
+            declassify: Component::dc_true(),
+            function: Function { app_image: image.clone(), ..Default::default() },
+        }), &mut ctx).unwrap() else { panic!("not a gate") };
+        src.create_and_link(path(":app"), "r".into(), NewEntry::RedirectGate(alice.clone(), RedirectGate {
+            privilege: Component::dc_true(),
+            invoker_integrity_clearance: Component::dc_true(),
+            declassify: Component::dc_true(),
+            gate,
+        }), &mut ctx).unwrap();
+        let DirEntry::FacetedDirectory(faceted) = src.create_and_link(path(":app"), "facets".into(), NewEntry::FacetedDirectory, &mut ctx).unwrap() else { panic!("not faceted") };
+        faceted.open(&alice, &src, &ctx).unwrap();
+
+        let archive = src.export(path(":app")).unwrap();
+        assert_eq!(archive.objects.len(), 8);
+        assert_eq!(archive.blobs(), BTreeSet::from([image.clone()]));
+        let mut bytes = Vec::new();
+        archive.write(&mut bytes, &blobstore).unwrap();
+        let archive = Archive::read(bytes.as_slice(), &mut blobstore).unwrap();
+
+        let dest = FS::new(MemoryStore::new());
+        dest.initialize();
+        dest.import(&archive, path(":restored")).unwrap();
+        assert!(matches!(dest.import(&archive, path(":restored")), Err(FsError::NameExists)));
+
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        let DirEntry::File(file) = dest.read_path(path(":restored:f"), &mut ctx).unwrap() else { panic!("not a file") };
+        assert_eq!(file.get(&dest).unwrap().label(), &alice);
+        assert_eq!(file.read(&dest, &mut ctx).unwrap(), b"hello");
+        let DirEntry::Map(map) = dest.read_path(path(":restored:m"), &mut ctx).unwrap() else { panic!("not a map") };
+        assert_eq!(map.lookup("k", &dest, &mut ctx).unwrap(), Some(b"v".to_vec()));
+        let DirEntry::Log(log) = dest.read_path(path(":restored:l"), &mut ctx).unwrap() else { panic!("not a log") };
+        assert_eq!(log.read_from(0, 10, &dest, &mut ctx).unwrap(), (1, vec![b"b".to_vec(), b"c".to_vec()]));
+        let DirEntry::Gate(redirect) = dest.read_path(path(":restored:r"), &mut ctx).unwrap() else { panic!("not a gate") };
+        assert_eq!(redirect.to_invokable(&dest, &mut ctx).unwrap().function.app_image, image);
+        let facets = dest.list_faceted(path(":restored:facets"), &alice, &mut ctx).unwrap();
+        assert_eq!(facets.keys().collect::<Vec<_>>(), vec![&alice]);
+
+        // an archive with a dangling reference is rejected without writing
+        let mut broken = archive.clone();
+        let Some(ArchivedObject::Directory { entries, .. }) = broken.objects.get_mut(&broken.root) else { panic!("not a directory") };
+        entries.insert("missing".into(), u64::MAX);
+        let keys = dest.0.scan(&[], None, usize::MAX).len();
+        assert!(matches!(dest.import(&broken, path(":broken")), Err(FsError::DanglingReference(u64::MAX))));
+        assert_eq!(dest.0.scan(&[], None, usize::MAX).len(), keys);
+    }
+}
@@ -3,18 +3,17 @@ use std::io::{Read, Write};
 use lazy_static::lazy_static;
 use log::{debug, warn};
 use serde::Deserialize;
-use sha2::Sha256;
 
 use labeled::buckle::{self, Component, Buckle};
 
-use super::{BackingStore, Blob, FsError};
-use crate::{blobstore::Blobstore, fs::{Function, DirectGate, DirEntry, Gate}};
+use super::{BackingStore, Blob, FsError, SecurityContext};
+use crate::{blobstore::Blobstore, fs::{Function, DirectGate, DirEntry, Gate, NewEntry}};
 
 const FSUTIL_MEMSIZE: usize = 128;
 
 lazy_static! {
     static ref FSTN_IMAGE_BASE: super::path::Path =
-        super::path::Path::parse("home:<T,faasten>").unwrap();
+        super::path::Path::parse("home:<T,faasten>", &SecurityContext::default()).unwrap();
     // home:<T,faasten>:fsutil can be read by anyone but only faasten can update it.
     static ref FSUTIL_POLICY: buckle::Buckle =
         buckle::Buckle::parse("T,faasten").unwrap();
@@ -25,7 +24,6 @@ lazy_static! {
 }
 
 const ROOT_PRIV: buckle::Component = buckle::Component::dc_false();
-const EMPTY_PRIV: buckle::Component = buckle::Component::dc_true();
 
 fn localfile2blob(blobstore: &mut Blobstore, local_path: &str) -> String {
     let mut f = std::fs::File::open(local_path).expect("open");
@@ -40,7 +38,7 @@ fn localfile2blob(blobstore: &mut Blobstore, local_path: &str) -> String {
 
 /// The preparer installs supported kernels and runtime images in the directory `FSTN_IMAGE_BASE`.
 /// Kernels and runtime images are stored as blobs.
-pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, config_path: &str) -> Result<(), FsError> {
+pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, mut blobstore: Blobstore, config_path: &str) -> Result<(), FsError> {
     #[derive(Deserialize)]
     struct Config {
         kernel: String,
@@ -52,7 +50,6 @@ pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, config_path: &str) -> Resu
     let config = std::fs::File::open(config_path).expect("open configuration file");
     let config: Config = serde_yaml::from_reader(config).expect("deserialize");
 
-    let mut blobstore = crate::blobstore::Blobstore::<Sha256>::default();
     let label = buckle::Buckle::parse("T,faasten").unwrap();
 
     if !fs.initialize() {
@@ -62,14 +59,14 @@ pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, config_path: &str) -> Resu
 
     // bootstrap
     // set up ``home''
-    super::utils::set_my_privilge(ROOT_PRIV.clone());
-    if super::utils::create_faceted(&fs, super::path::Path::root(), "home".to_string()).is_err() {
+    let mut root = SecurityContext::new(ROOT_PRIV.clone());
+    if super::utils::create_faceted(&fs, super::path::Path::root(), "home".to_string(), &mut root).is_err() {
         log::warn!("`home` exists");
 
     }
 
     // start acting as `faasten`
-    super::utils::set_my_privilge(FAASTEN_PRIV.clone());
+    let mut ctx = SecurityContext::new(FAASTEN_PRIV.clone());
 
     debug!("creating kernel blob...");
     let kernel_blob = {
@@ -81,6 +78,7 @@ pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, config_path: &str) -> Resu
             name,
             label.clone(),
             blobname.clone(),
+            &mut ctx,
         )?;
         blobname
     };
@@ -95,6 +93,7 @@ pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, config_path: &str) -> Resu
             name,
             label.clone(),
             blobname.clone(),
+            &mut ctx,
         )?;
         blobname
     };
@@ -109,6 +108,7 @@ pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, config_path: &str) -> Resu
             name,
             label.clone(),
             blobname.clone(),
+            &mut ctx,
         )?;
         blobname
     };
@@ -122,26 +122,21 @@ pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, config_path: &str) -> Resu
             kernel: kernel_blob,
         };
 
-        if let DirEntry::Directory(dir) = fs.read_path(FSTN_IMAGE_BASE.clone())? {
+        if let DirEntry::Directory(dir) = fs.read_path(FSTN_IMAGE_BASE.clone(), &mut ctx)? {
             let name: String = "fsutil".into();
-            match dir.list(fs).get(&name) {
+            match dir.list(fs, &mut ctx)?.get(&name) {
                 Some(DirEntry::Gate(gate)) => {
                     gate.replace(Gate::Direct(DirectGate {
                         privilege: buckle::Component::dc_true(),
                         invoker_integrity_clearance: buckle::Component::dc_true(),
                         declassify: buckle::Component::dc_true(),
                         function,
-                    }), fs)?;
+                    }), fs, &mut ctx)?;
                 },
-                Some(_) => {
-                    dir.unlink(&name, fs)?;
-                    let gate = fs.create_direct_gate(FSUTIL_POLICY.clone(), DirectGate { privilege: buckle::Component::dc_true(), invoker_integrity_clearance: buckle::Component::dc_true(), declassify: buckle::Component::dc_true(),  function }).expect("create gate");
-                    dir.link(name, gate, fs)?;
+                _ => {
+                    let gate = NewEntry::DirectGate(FSUTIL_POLICY.clone(), DirectGate { privilege: buckle::Component::dc_true(), invoker_integrity_clearance: buckle::Component::dc_true(), declassify: buckle::Component::dc_true(), function });
+                    fs.create_in(&dir, name, gate, true, &mut ctx)?;
                 },
-                None => {
-                    let gate = fs.create_direct_gate(FSUTIL_POLICY.clone(), DirectGate { privilege: buckle::Component::dc_true(), invoker_integrity_clearance: buckle::Component::dc_true(), declassify: buckle::Component::dc_true(), function }).expect("create gate");
-                    dir.link(name, gate, fs)?;
-                }
             }
         } else {
             Err(FsError::BadPath)?
@@ -163,10 +158,10 @@ pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, config_path: &str) -> Resu
             name,
             label.clone(),
             blobname,
+            &mut ctx,
         )
         .expect(&format!("link {:?} blob", rt));
     }
-    super::utils::set_my_privilge(EMPTY_PRIV.clone());
     debug!("Done with bootstrapping.");
     Ok(())
 }
@@ -175,15 +170,16 @@ fn dup_fsutil<S: BackingStore>(
     fs: &super::FS<S>,
     privilege: Component,
     invoker_integrity_clearance: Component,
+    ctx: &mut SecurityContext,
 ) -> Result<(), FsError> {
-    let faasten_fsutil = super::path::Path::parse("home:<T,faasten>:fsutil").unwrap();
-    let base_dir = super::path::Path::parse("~").unwrap();
-    match fs.read_path(faasten_fsutil)? {
+    let faasten_fsutil = super::path::Path::parse("home:<T,faasten>:fsutil", ctx).unwrap();
+    let base_dir = super::path::Path::parse("~", ctx).unwrap();
+    match fs.read_path(faasten_fsutil, ctx)? {
         super::DirEntry::Gate(gate) => {
             let new_gate = fs.create_redirect_gate(Buckle::public(), super::RedirectGate {
                 privilege: privilege.clone(), invoker_integrity_clearance, declassify: privilege, gate
-            })?;
-            fs.link(base_dir, "fsutil".into(), new_gate)
+            }, ctx)?;
+            fs.link(base_dir, "fsutil".into(), new_gate, ctx)
         },
         _ => Err(super::errors::FsError::NotAGate),
     }
@@ -192,26 +188,24 @@ fn dup_fsutil<S: BackingStore>(
 pub fn register_user_fsutil<S: BackingStore>(fs: &super::FS<S>, user: Component, clearance: Component) {
     debug!("Duplicating faasten-supplied fsutil to user-specific fsutil");
     // generate the per-user fsutil gate, acting on behalf of the user
-    super::utils::set_my_privilge(user.clone());
+    let mut ctx = SecurityContext::new(user.clone());
 
-    match dup_fsutil(fs, user.clone(), clearance.clone()) {
+    match dup_fsutil(fs, user.clone(), clearance.clone(), &mut ctx) {
         Err(e) => warn!("{:?}", e),
         _ => (),
     }
-
-    super::utils::set_my_privilge(EMPTY_PRIV.clone());
 }
 
-pub fn get_runtime_blob<S: BackingStore>(fs: &super::FS<S>, runtime: &str) -> Blob {
+pub fn get_runtime_blob<S: BackingStore>(fs: &super::FS<S>, runtime: &str, ctx: &mut SecurityContext) -> Blob {
     let mut runtime_fs_path = FSTN_IMAGE_BASE.clone();
     runtime_fs_path.push_dscrp(runtime.to_string());
-    fs.open_blob(runtime_fs_path).unwrap()
+    fs.open_blob(runtime_fs_path, ctx).unwrap()
 }
 
-pub fn get_kernel_blob<S: BackingStore>(fs: &super::FS<S>) -> Blob {
+pub fn get_kernel_blob<S: BackingStore>(fs: &super::FS<S>, ctx: &mut SecurityContext) -> Blob {
     let mut kernel_fs_path = FSTN_IMAGE_BASE.clone();
     kernel_fs_path.push_dscrp("kernel".to_string());
-    fs.open_blob(kernel_fs_path).unwrap()
+    fs.open_blob(kernel_fs_path, ctx).unwrap()
 }
 
 pub fn update_fsutil<S: BackingStore>(
@@ -219,11 +213,7 @@ pub fn update_fsutil<S: BackingStore>(
     _blobstore: Blobstore,
     _local_path: &str,
 ) {
-    super::utils::set_my_privilge(FAASTEN_PRIV.clone());
-
-    // TODO
-
-    super::utils::set_my_privilge(EMPTY_PRIV.clone());
+    // TODO act as `faasten`
 }
 
 pub fn update_python<S: BackingStore>(
@@ -231,13 +221,11 @@ pub fn update_python<S: BackingStore>(
     mut blobstore: Blobstore,
     local_path: &str,
 ) {
-    super::utils::set_my_privilge(FAASTEN_PRIV.clone());
+    let mut ctx = SecurityContext::new(FAASTEN_PRIV.clone());
 
     debug!("repointing :home:<T,faasten>:python...");
     let blobname = localfile2blob(&mut blobstore, local_path);
     let mut path = FSTN_IMAGE_BASE.clone();
     path.push_dscrp("python".to_string());
-    fs.replace_blob(path, blobname.clone()).expect("repoint python blob");
-
-    super::utils::set_my_privilge(EMPTY_PRIV.clone());
+    fs.replace_blob(path, blobname.clone(), &mut ctx).expect("repoint python blob");
 }
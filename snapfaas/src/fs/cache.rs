@@ -0,0 +1,357 @@
+//! Read-through caching of any backing store
+//!
+//! `CachingStore` keeps recently read values, up to `capacity` bytes of keys
+//! and values, in memory, shared by its clones, so traversing the same
+//! directories again does not go to the underlying store. Writing a key
+//! through the cache invalidates it, and a failed `cas` or `commit`
+//! invalidates the keys it found changed.
+//!
+//! Writes that bypass the cache, e.g., by other processes, are only noticed
+//! with version checks. Keys are split into ranges by their first byte, each
+//! with a version stored under `VERSION_PREFIX` followed by that byte. Every
+//! cache with version checks enabled writes a new random version of each
+//! range it writes to in the same transaction as the writes, and reads the
+//! versions at most once per `max_staleness`, dropping what it cached of the
+//! ranges that changed, including those it wrote itself. Reads are then at
+//! most `max_staleness` out of date, provided all writers go through caches
+//! with version checks, which writers that read too little to cache anything
+//! get with a zero capacity.
+//!
+//! Versions are random rather than counted, so writing one neither reads the
+//! old version nor conflicts with other writers of the same range.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{BackingStore, Transaction};
+
+pub const VERSION_PREFIX: &[u8] = b"cache/version/";
+/// Just past every key starting with `VERSION_PREFIX`
+const VERSION_END: &[u8] = b"cache/version0";
+
+/// Returns the range of `key`, the keys sharing a version
+fn range(key: &[u8]) -> u8 {
+    key.first().copied().unwrap_or(0)
+}
+
+fn version_key(range: u8) -> Vec<u8> {
+    [VERSION_PREFIX, &[range]].concat()
+}
+
+fn decode_version(value: &[u8]) -> u64 {
+    value.try_into().map_or(0, u64::from_be_bytes)
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    /// Values and the tick they were last used at
+    entries: HashMap<Vec<u8>, (Vec<u8>, u64)>,
+    /// Keys by the tick they were last used at
+    recency: BTreeMap<u64, Vec<u8>>,
+    /// Bytes of the keys and values in `entries`
+    size: usize,
+    tick: u64,
+    /// Bumped on every invalidation, so a read that raced with one does not
+    /// cache what may be the old value
+    epoch: u64,
+    /// The versions of the ranges last read from the store, missing if never
+    /// written, and when
+    versions: Option<(BTreeMap<u8, u64>, Instant)>,
+}
+
+impl Lru {
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let (value, used) = self.entries.get_mut(key)?;
+        self.tick += 1;
+        let key = self.recency.remove(used).expect("recency in sync");
+        *used = self.tick;
+        self.recency.insert(self.tick, key);
+        Some(value.clone())
+    }
+
+    /// Caches `value`, evicting the least recently used values until the
+    /// cache fits in `capacity` bytes. Values that alone exceed it are not
+    /// cached.
+    fn insert(&mut self, key: &[u8], value: Vec<u8>, capacity: usize) {
+        self.remove(key);
+        let size = key.len() + value.len();
+        if size > capacity {
+            return;
+        }
+        self.tick += 1;
+        self.entries.insert(key.to_vec(), (value, self.tick));
+        self.recency.insert(self.tick, key.to_vec());
+        self.size += size;
+        while self.size > capacity {
+            let (_, oldest) = self.recency.first_key_value().expect("recency in sync");
+            let oldest = oldest.clone();
+            self.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((value, used)) = self.entries.remove(key) {
+            self.recency.remove(&used);
+            self.size -= key.len() + value.len();
+        }
+    }
+
+    fn invalidate(&mut self, key: &[u8]) {
+        self.epoch += 1;
+        self.remove(key);
+    }
+
+    fn clear_ranges(&mut self, ranges: &BTreeSet<u8>) {
+        self.epoch += 1;
+        let keys: Vec<Vec<u8>> = self.entries.keys().filter(|key| ranges.contains(&range(key))).cloned().collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+}
+
+/// A `BackingStore` caching another. Clones share the same cache.
+#[derive(Debug, Clone)]
+pub struct CachingStore<S> {
+    inner: S,
+    capacity: usize,
+    max_staleness: Option<Duration>,
+    lru: Arc<Mutex<Lru>>,
+}
+
+impl<S: BackingStore> CachingStore<S> {
+    /// Caches up to `capacity` bytes of keys and values read from `inner`.
+    /// Nothing is cached if `capacity` is zero.
+    pub fn new(inner: S, capacity: usize) -> Self {
+        CachingStore { inner, capacity, max_staleness: None, lru: Default::default() }
+    }
+
+    /// Enables version checks, as described in the module documentation
+    pub fn with_version_checks(mut self, max_staleness: Duration) -> Self {
+        self.max_staleness = Some(max_staleness);
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn read_versions(&self) -> BTreeMap<u8, u64> {
+        self.inner.scan(VERSION_PREFIX, Some(VERSION_END), usize::from(u8::MAX) + 1)
+            .into_iter()
+            .filter_map(|(key, value)| match key.strip_prefix(VERSION_PREFIX)? {
+                &[range] => Some((range, decode_version(&value))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Drops what is cached of the ranges whose versions have changed since
+    /// they were last read more than `max_staleness` ago
+    fn validate(&self) {
+        let Some(max_staleness) = self.max_staleness else {
+            return;
+        };
+        if self.lru.lock().unwrap().versions.as_ref().is_some_and(|(_, read)| read.elapsed() < max_staleness) {
+            return;
+        }
+        let versions = self.read_versions();
+        let mut lru = self.lru.lock().unwrap();
+        if let Some((seen, _)) = lru.versions.as_ref() {
+            let changed = seen.keys().chain(versions.keys())
+                .filter(|range| seen.get(range) != versions.get(range))
+                .copied()
+                .collect();
+            lru.clear_ranges(&changed);
+        }
+        lru.versions = Some((versions, Instant::now()));
+    }
+
+    /// Returns `txn` with new versions of the ranges it writes to, if
+    /// version checks are enabled
+    fn with_versions(&self, txn: &Transaction) -> Transaction {
+        let mut txn = txn.clone();
+        if self.max_staleness.is_some() {
+            let ranges: BTreeSet<u8> = txn.writes().iter().map(|(key, _)| range(key)).collect();
+            for range in ranges {
+                txn.put(&version_key(range), &rand::random::<u64>().to_be_bytes());
+            }
+        }
+        txn
+    }
+
+    /// Commits `txn`, with new versions, until it commits or `retry` returns
+    /// false after it failed to
+    fn commit_versioned<F: FnMut() -> bool>(&self, txn: Transaction, mut retry: F) -> bool {
+        loop {
+            if self.commit(&txn) {
+                return true;
+            }
+            if !retry() {
+                return false;
+            }
+        }
+    }
+
+    fn invalidate(&self, key: &[u8]) {
+        self.lru.lock().unwrap().invalidate(key);
+    }
+}
+
+impl<S: BackingStore> BackingStore for CachingStore<S> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        if self.capacity == 0 {
+            return self.inner.get(key);
+        }
+        self.validate();
+        let epoch = {
+            let mut lru = self.lru.lock().unwrap();
+            if let Some(value) = lru.get(key) {
+                return Some(value);
+            }
+            lru.epoch
+        };
+        let value = self.inner.get(key)?;
+        let mut lru = self.lru.lock().unwrap();
+        if lru.epoch == epoch {
+            lru.insert(key, value.clone(), self.capacity);
+        }
+        Some(value)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) {
+        if self.max_staleness.is_none() {
+            self.inner.put(key, value);
+            self.invalidate(key);
+            return;
+        }
+        let mut txn = Transaction::default();
+        txn.put(key, value);
+        self.commit_versioned(txn, || true);
+    }
+
+    fn add(&self, key: &[u8], value: &[u8]) -> bool {
+        if self.max_staleness.is_none() {
+            let added = self.inner.add(key, value);
+            self.invalidate(key);
+            return added;
+        }
+        let mut txn = Transaction::default();
+        txn.check(key, None);
+        txn.put(key, value);
+        self.commit_versioned(txn, || self.inner.get(key).is_none())
+    }
+
+    fn cas(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<(), Option<Vec<u8>>> {
+        if self.max_staleness.is_none() {
+            let res = self.inner.cas(key, expected, value);
+            self.invalidate(key);
+            return res;
+        }
+        let mut txn = Transaction::default();
+        txn.check(key, expected);
+        txn.put(key, value);
+        let mut current = None;
+        if self.commit_versioned(txn, || {
+            current = self.inner.get(key);
+            current.as_deref() == expected
+        }) {
+            Ok(())
+        } else {
+            Err(current)
+        }
+    }
+
+    fn del(&self, key: &[u8]) {
+        if self.max_staleness.is_none() {
+            self.inner.del(key);
+            self.invalidate(key);
+            return;
+        }
+        let mut txn = Transaction::default();
+        txn.del(key);
+        self.commit_versioned(txn, || true);
+    }
+
+    fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.inner.scan(start, end, limit)
+    }
+
+    fn commit(&self, txn: &Transaction) -> bool {
+        let committed = self.inner.commit(&self.with_versions(txn));
+        let mut lru = self.lru.lock().unwrap();
+        for (key, _) in txn.writes() {
+            lru.invalidate(key);
+        }
+        if !committed {
+            // some of the checked values were cached but out of date
+            for (key, _) in txn.checks() {
+                lru.invalidate(key);
+            }
+        }
+        committed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+
+    #[test]
+    fn test_caching_store() {
+        let store = MemoryStore::new();
+        // room for two single-byte keys and values
+        let cache = CachingStore::new(store.clone(), 4);
+        cache.put(b"a", b"1");
+        cache.put(b"b", b"2");
+        assert_eq!(cache.get(b"a"), Some(b"1".to_vec()));
+
+        // bypassing the cache goes unnoticed until the key is invalidated
+        store.put(b"a", b"stale");
+        assert_eq!(cache.get(b"a"), Some(b"1".to_vec()));
+        let mut txn = Transaction::default();
+        txn.check(b"a", Some(b"1"));
+        txn.put(b"a", b"3");
+        assert!(!cache.commit(&txn));
+        assert_eq!(cache.get(b"a"), Some(b"stale".to_vec()));
+
+        // "b" is the least recently used
+        cache.get(b"c");
+        store.put(b"c", b"4");
+        assert_eq!(cache.get(b"c"), Some(b"4".to_vec()));
+        store.put(b"b", b"5");
+        assert_eq!(cache.get(b"b"), Some(b"5".to_vec()));
+
+        let first = CachingStore::new(store.clone(), 16).with_version_checks(Duration::ZERO);
+        let second = CachingStore::new(store.clone(), 16).with_version_checks(Duration::ZERO);
+        assert_eq!(first.get(b"a"), Some(b"stale".to_vec()));
+        second.put(b"a", b"6");
+        assert_eq!(first.get(b"a"), Some(b"6".to_vec()));
+
+        // versions are only written along with the writes they version
+        let version = store.get(&version_key(b'a'));
+        assert!(version.is_some());
+        let mut txn = Transaction::default();
+        txn.check(b"a", Some(b"0"));
+        txn.put(b"a", b"0");
+        assert!(!second.commit(&txn));
+        assert_eq!(second.cas(b"a", Some(b"0"), b"0"), Err(Some(b"6".to_vec())));
+        assert!(!second.add(b"a", b"0"));
+        assert_eq!(store.get(&version_key(b'a')), version);
+
+        // only the ranges written are dropped
+        assert_eq!(first.get(b"b"), Some(b"5".to_vec()));
+        store.put(b"b", b"stale");
+        second.put(b"a", b"7");
+        assert_eq!(first.get(b"a"), Some(b"7".to_vec()));
+        assert_eq!(first.get(b"b"), Some(b"5".to_vec()));
+
+        // values larger than the cache are not cached
+        let small = CachingStore::new(store.clone(), 4);
+        assert_eq!(small.get(b"b"), Some(b"stale".to_vec()));
+        store.put(b"b", b"8");
+        assert_eq!(small.get(b"b"), Some(b"8".to_vec()));
+    }
+}
@@ -0,0 +1,139 @@
+//! Security contexts
+//!
+//! A `SecurityContext` is the label state of one principal acting on the file
+//! system, e.g., one function invocation or one frontend request: the current
+//! label, which reads raise, the privilege, which writes and creations are
+//! checked against, and the clearance, which bounds how high reads may raise
+//! the current label. File system operations take the context explicitly, so
+//! nothing carries over between requests unless the caller passes the same
+//! context along.
+//!
+//! Changes made through a context queue notifications for their subscribers
+//! on the context, which the caller takes and delivers, or drops along with
+//! the context.
+use std::cell::RefCell;
+
+use super::*;
+
+#[derive(Debug, Clone)]
+pub struct SecurityContext {
+    label: Buckle,
+    privilege: Component,
+    clearance: Buckle,
+    notifications: RefCell<Vec<Notification>>,
+    notification_depth: u32,
+}
+
+/// The public label, the empty privilege and an unbounded clearance
+impl Default for SecurityContext {
+    fn default() -> Self {
+        SecurityContext::new(Component::dc_true())
+    }
+}
+
+impl SecurityContext {
+    /// Returns a context with the public label and an unbounded clearance
+    pub fn new(privilege: Component) -> Self {
+        SecurityContext::with_label(Buckle::public(), privilege)
+    }
+
+    /// Returns a context starting at `label` with an unbounded clearance
+    pub fn with_label(label: Buckle, privilege: Component) -> Self {
+        SecurityContext {
+            label,
+            privilege,
+            clearance: Buckle::top(),
+            notifications: RefCell::new(Vec::new()),
+            notification_depth: 0,
+        }
+    }
+
+    /// Sets how many notifications led to this context, e.g., the depth of
+    /// the notification whose gate invocation runs in it
+    pub fn with_notification_depth(mut self, depth: u32) -> Self {
+        self.notification_depth = depth;
+        self
+    }
+
+    pub fn notification_depth(&self) -> u32 {
+        self.notification_depth
+    }
+
+    /// Returns and clears the notifications queued by changes made through
+    /// this context
+    pub fn take_notifications(&mut self) -> Vec<Notification> {
+        self.notifications.take()
+    }
+
+    pub(super) fn queue_notifications<I: IntoIterator<Item = Notification>>(&self, notifications: I) {
+        self.notifications.borrow_mut().extend(notifications)
+    }
+
+    pub fn label(&self) -> &Buckle {
+        &self.label
+    }
+
+    pub fn privilege(&self) -> &Component {
+        &self.privilege
+    }
+
+    pub fn clearance(&self) -> &Buckle {
+        &self.clearance
+    }
+
+    /// Returns `<p,p>` for the privilege `p`, the facet of the principal's home
+    pub fn ufacet(&self) -> Buckle {
+        Buckle { secrecy: self.privilege.clone(), integrity: self.privilege.clone() }
+    }
+
+    /// Whether the current label and privilege allow writing an object
+    /// labeled `label`
+    pub fn can_write(&self, label: &Buckle) -> bool {
+        self.label.can_flow_to_with_privilege(label, &self.privilege)
+    }
+
+    /// Raises the current label to include `label`, or returns
+    /// `FsError::ClearanceError` and leaves it unchanged if the result would
+    /// not flow to the clearance
+    pub fn taint(&mut self, label: &Buckle) -> Result<(), FsError> {
+        let new_label = label.clone().lub(self.label.clone());
+        if !new_label.can_flow_to(&self.clearance) {
+            return Err(FsError::ClearanceError);
+        }
+        self.label = new_label;
+        Ok(())
+    }
+
+    /// Raises the current label to include `label` regardless of the
+    /// clearance, and returns the new label
+    pub fn taint_with_label(&mut self, label: Buckle) -> Buckle {
+        self.label = self.label.clone().lub(label);
+        self.label.clone()
+    }
+
+    /// Bounds how high reads may raise the current label. Fails, returning the
+    /// current label, if it already exceeds `clearance`.
+    pub fn set_clearance(&mut self, clearance: Buckle) -> Result<(), Buckle> {
+        if !self.label.can_flow_to(&clearance) {
+            return Err(self.label.clone());
+        }
+        self.clearance = clearance;
+        Ok(())
+    }
+
+    /// Declassifies the current label with `privilege` and returns it
+    pub fn declassify_with(&mut self, privilege: &Component) -> Buckle {
+        self.label = self.label.clone().downgrade(privilege);
+        self.label.clone()
+    }
+
+    /// Returns the current label with its secrecy replaced by `target`, if
+    /// `target` and the privilege imply the current secrecy
+    pub fn declassify(&self, target: Component) -> Result<Buckle, Buckle> {
+        if (target.clone() & self.privilege.clone()).implies(&self.label.secrecy) {
+            Ok(Buckle::new(target, self.label.integrity.clone()))
+        } else {
+            Err(self.label.clone())
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Backing store keeping each key in a file of a local directory, for
+//! single-node deployments and tests that need nothing but a disk.
+//!
+//! A key is stored in a file named by its hex encoding followed by `.v`, so
+//! files sort like their keys and can be inspected with ordinary tools. Keys
+//! too long for that to fit in a file name are stored in a file named by
+//! their SHA-256 hash followed by `.h`, holding the key's length as 8
+//! big-endian bytes and the key before the value. Values are written to a
+//! temporary file first and renamed into place, syncing the directory after,
+//! and every operation holds an advisory lock on the directory's `.lock` file,
+//! shared for reads and exclusive for writes, so the store can be used by
+//! several processes at once. A crash in the middle of a `commit` may leave
+//! only some of its writes applied.
+//!
+//! `scan` lists the whole directory, which is fine for the stores this is
+//! meant for but not for large ones.
+use std::convert::{TryFrom, TryInto};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use super::{BackingStore, Transaction};
+
+const SUFFIX: &str = ".v";
+const HASHED_SUFFIX: &str = ".h";
+const LOCK_FILE: &str = ".lock";
+/// The longest file name most file systems allow
+const MAX_NAME_LEN: usize = 255;
+
+/// Whether `key` is stored under its hash
+fn is_hashed(key: &[u8]) -> bool {
+    2 * key.len() + SUFFIX.len() > MAX_NAME_LEN
+}
+
+/// Splits what is stored under a hashed key into the key and the value
+fn split_hashed(stored: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (len, rest) = stored.split_at_checked(8)?;
+    let len = usize::try_from(u64::from_be_bytes(len.try_into().ok()?)).ok()?;
+    rest.split_at_checked(len)
+}
+
+#[derive(Debug, Clone)]
+pub struct DirStore {
+    dir: PathBuf,
+}
+
+impl DirStore {
+    /// Opens the store in `dir`, creating the directory if it does not exist
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        let store = DirStore { dir: dir.as_ref().to_path_buf() };
+        store.lock_file()?;
+        Ok(store)
+    }
+
+    fn key_path(&self, key: &[u8]) -> PathBuf {
+        if is_hashed(key) {
+            self.dir.join(format!("{}{}", hex::encode(Sha256::digest(key)), HASHED_SUFFIX))
+        } else {
+            self.dir.join(format!("{}{}", hex::encode(key), SUFFIX))
+        }
+    }
+
+    fn lock_file(&self) -> io::Result<File> {
+        OpenOptions::new().create(true).truncate(false).write(true).open(self.dir.join(LOCK_FILE))
+    }
+
+    /// Runs `f` holding the lock, exclusively if `exclusive`. Each call locks
+    /// a file description of its own, so threads exclude each other like
+    /// processes do. The lock is released when the file is closed.
+    fn locked<R, F: FnOnce() -> R>(&self, exclusive: bool, f: F) -> R {
+        let lock = self.lock_file().expect("open lock file");
+        if exclusive {
+            lock.lock().expect("lock store");
+        } else {
+            lock.lock_shared().expect("lock store");
+        }
+        f()
+    }
+
+    fn read(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let stored = match fs::read(self.key_path(key)) {
+            Ok(stored) => stored,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => panic!("read {:?}: {}", self.key_path(key), e),
+        };
+        if !is_hashed(key) {
+            return Some(stored);
+        }
+        match split_hashed(&stored) {
+            Some((stored_key, value)) if stored_key == key => Some(value.to_vec()),
+            _ => panic!("read {:?}: does not hold the key", self.key_path(key)),
+        }
+    }
+
+    /// Returns the key stored in the file named `name`, if it holds one
+    fn file_key(&self, name: &str) -> Option<Vec<u8>> {
+        if let Some(hex) = name.strip_suffix(SUFFIX) {
+            return hex::decode(hex).ok();
+        }
+        name.strip_suffix(HASHED_SUFFIX)?;
+        let stored = fs::read(self.dir.join(name)).ok()?;
+        split_hashed(&stored).map(|(key, _)| key.to_vec())
+    }
+
+    /// Makes renames and removals in the directory durable
+    fn sync_dir(&self) -> io::Result<()> {
+        File::open(&self.dir)?.sync_all()
+    }
+
+    fn write(&self, key: &[u8], value: &[u8]) {
+        let path = self.key_path(key);
+        let res = tempfile::NamedTempFile::new_in(&self.dir).and_then(|mut tmp| {
+            if is_hashed(key) {
+                tmp.write_all(&(key.len() as u64).to_be_bytes())?;
+                tmp.write_all(key)?;
+            }
+            tmp.write_all(value)?;
+            tmp.as_file().sync_data()?;
+            tmp.persist(&path).map_err(|e| e.error)?;
+            self.sync_dir()
+        });
+        if let Err(e) = res {
+            panic!("write {:?}: {}", path, e);
+        }
+    }
+
+    fn remove(&self, key: &[u8]) {
+        match fs::remove_file(self.key_path(key)).and_then(|()| self.sync_dir()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => panic!("remove {:?}: {}", self.key_path(key), e),
+            _ => (),
+        }
+    }
+}
+
+impl BackingStore for DirStore {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.locked(false, || self.read(key))
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) {
+        self.locked(true, || self.write(key, value))
+    }
+
+    fn add(&self, key: &[u8], value: &[u8]) -> bool {
+        self.locked(true, || {
+            if self.key_path(key).exists() {
+                false
+            } else {
+                self.write(key, value);
+                true
+            }
+        })
+    }
+
+    fn cas(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<(), Option<Vec<u8>>> {
+        self.locked(true, || {
+            let old = self.read(key);
+            if old.as_deref() == expected {
+                self.write(key, value);
+                Ok(())
+            } else {
+                Err(old)
+            }
+        })
+    }
+
+    fn del(&self, key: &[u8]) {
+        self.locked(true, || self.remove(key))
+    }
+
+    fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.locked(false, || {
+            let entries = fs::read_dir(&self.dir).unwrap_or_else(|e| panic!("list {:?}: {}", self.dir, e));
+            let mut keys: Vec<Vec<u8>> = entries.filter_map(|entry| {
+                self.file_key(&entry.ok()?.file_name().into_string().ok()?)
+            }).filter(|key| key.as_slice() >= start && end.is_none_or(|end| key.as_slice() < end)).collect();
+            keys.sort();
+            keys.into_iter()
+                .filter_map(|key| self.read(&key).map(|value| (key, value)))
+                .take(limit)
+                .collect()
+        })
+    }
+
+    fn commit(&self, txn: &Transaction) -> bool {
+        self.locked(true, || {
+            if txn.checks().iter().any(|(key, expected)| self.read(key) != *expected) {
+                return false;
+            }
+            for (key, value) in txn.writes() {
+                match value {
+                    Some(value) => self.write(key, value),
+                    None => self.remove(key),
+                }
+            }
+            true
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dir_store() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = DirStore::open(tmp.path().join("store")).unwrap();
+        assert!(store.add(b"a", b"1"));
+        assert!(!store.add(b"a", b"2"));
+        store.put(b"", b"empty");
+        store.put(b"ab", b"3");
+        assert_eq!(store.cas(b"a", Some(b"2"), b"4"), Err(Some(b"1".to_vec())));
+        assert_eq!(store.cas(b"a", Some(b"1"), b"4"), Ok(()));
+
+        let mut txn = Transaction::default();
+        txn.check(b"a", Some(b"4"));
+        txn.check(b"b", None);
+        txn.put(b"b", b"5");
+        txn.del(b"ab");
+        assert!(store.commit(&txn));
+        assert!(!store.commit(&txn));
+
+        // reopening sees the same keys, in order
+        let store = DirStore::open(tmp.path().join("store")).unwrap();
+        assert_eq!(store.get(b"a"), Some(b"4".to_vec()));
+        assert_eq!(store.get(b"ab"), None);
+        let keys: Vec<Vec<u8>> = store.scan(b"", None, 10).into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![b"".to_vec(), b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(store.scan(b"a", Some(b"b"), 10), vec![(b"a".to_vec(), b"4".to_vec())]);
+
+        // keys too long for a file name are stored under their hash
+        let long = vec![b'c'; 200];
+        store.put(&long, b"6");
+        assert!(store.key_path(&long).file_name().unwrap().len() <= MAX_NAME_LEN);
+        assert_eq!(store.get(&long), Some(b"6".to_vec()));
+        assert_eq!(store.scan(b"b", None, 10), vec![(b"b".to_vec(), b"5".to_vec()), (long.clone(), b"6".to_vec())]);
+        store.del(&long);
+        assert_eq!(store.get(&long), None);
+    }
+}
@@ -0,0 +1,207 @@
+//! Encoding of stored FS objects
+//!
+//! Objects are stored as `MAGIC`, followed by the format version, followed by
+//! the bincode encoding of the object. Objects stored before versioning was
+//! introduced are JSON, which never starts with `MAGIC`. They, and objects in
+//! older versions, are still read transparently and `FS::migrate_encoding`
+//! rewrites them.
+//!
+//! Versions:
+//! 1. bincode encoding
+//! 2. adds `Metadata` to `Labeled`
+//! 3. stores `File`s in chunks
+//!
+//! Objects whose encoding changed are decoded as their older type by `decode`
+//! and converted.
+use std::any::{Any, TypeId};
+use std::collections::HashSet;
+
+use bincode::Options;
+
+use super::*;
+
+const MAGIC: u8 = 0xfa;
+const VERSION: u8 = 3;
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+pub fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut bs = vec![MAGIC, VERSION];
+    options().serialize_into(&mut bs, value).expect("encode FS object");
+    bs
+}
+
+/// Decodes an object in the current, an older or the legacy JSON encoding, or
+/// returns `None` if `bs` is none of them.
+pub fn decode<T: DeserializeOwned + 'static>(bs: &[u8]) -> Option<T> {
+    // files were plain byte vectors before version 3
+    if TypeId::of::<T>() == TypeId::of::<Labeled<File>>() && version(bs)? < 3 {
+        let Labeled { label, data, meta } = decode_as_encoded::<Labeled<Vec<u8>>>(bs)?;
+        let file: Box<dyn Any> = Box::new(Labeled { label, data: File::unchunked(data), meta });
+        return file.downcast().ok().map(|file| *file);
+    }
+    decode_as_encoded(bs)
+}
+
+/// Returns the version of the encoding of `bs`, where the legacy JSON encoding
+/// is version 0
+fn version(bs: &[u8]) -> Option<u8> {
+    match bs {
+        [MAGIC, version @ 1..=VERSION, ..] => Some(*version),
+        [MAGIC, ..] => None,
+        _ => Some(0),
+    }
+}
+
+/// Decodes `bs` as the type it was encoded from, whatever its version
+fn decode_as_encoded<T: DeserializeOwned>(bs: &[u8]) -> Option<T> {
+    match bs {
+        [MAGIC, 1, rest @ ..] => {
+            // `Metadata` is the last field of `Labeled`, so a version 1
+            // `Labeled` followed by the default metadata is a version 2 one.
+            // Other objects are unchanged and ignore the trailing bytes.
+            let mut bs = rest.to_vec();
+            options().serialize_into(&mut bs, &Metadata::default()).ok()?;
+            options().allow_trailing_bytes().deserialize(&bs).ok()
+        },
+        [MAGIC, 2..=VERSION, rest @ ..] => options().deserialize(rest).ok(),
+        [MAGIC, ..] => None,
+        _ => serde_json::from_slice(bs).ok(),
+    }
+}
+
+fn is_outdated(bs: &[u8]) -> bool {
+    !bs.is_empty() && !bs.starts_with(&[MAGIC, VERSION])
+}
+
+impl<S: BackingStore> FS<S> {
+    /// Rewrites objects reachable from `ROOT_REF` that are still stored as JSON
+    /// or in an older version in the current encoding, and returns how many were rewritten, or an
+    /// error if the root does not exist.
+    ///
+    /// Objects are rewritten with CAS, so concurrent writers are safe, and
+    /// objects they change are written in the current encoding anyway. Like the
+    /// garbage collector, migration never taints any context's label.
+    pub fn migrate_encoding(&self) -> Result<usize, FsError> {
+        let mut migrated = 0;
+        let root = self.migrate_object(&ROOT_REF, &mut migrated).ok_or(FsError::BadPath)?;
+
+        let mut visited = HashSet::new();
+        visited.insert(ROOT_REF.uid);
+        let mut stack: Vec<DirEntry> = root.data.entries.into_values().collect();
+        while let Some(entry) = stack.pop() {
+            match entry {
+                DirEntry::Directory(dir) => {
+                    if visited.insert(dir.uid) {
+                        if let Some(dir) = self.migrate_object(&dir, &mut migrated) {
+                            stack.extend(dir.data.entries.into_values());
+                        }
+                    }
+                },
+                DirEntry::FacetedDirectory(faceted) => {
+                    if visited.insert(faceted.uid) {
+                        if let Some(faceted) = self.migrate_object(&faceted, &mut migrated) {
+                            stack.extend(faceted.facets.into_iter().map(|(_, dir)| DirEntry::Directory(dir)));
+                        }
+                    }
+                },
+                DirEntry::Gate(gate) => {
+                    if visited.insert(gate.uid) {
+                        if let Some(Gate::Redirect(redirect)) = self.migrate_object(&gate, &mut migrated).map(|g| g.data) {
+                            stack.push(DirEntry::Gate(redirect.gate));
+                        }
+                    }
+                },
+                DirEntry::File(file) => {
+                    if visited.insert(file.uid) {
+                        self.migrate_object(&file, &mut migrated);
+                    }
+                },
+                DirEntry::Service(service) => {
+                    if visited.insert(service.uid) {
+                        self.migrate_object(&service, &mut migrated);
+                    }
+                },
+                DirEntry::Blob(blob) => {
+                    if visited.insert(blob.uid) {
+                        self.migrate_object(&blob, &mut migrated);
+                    }
+                },
+                DirEntry::Map(map) => {
+                    if visited.insert(map.uid) {
+                        self.migrate_object(&map, &mut migrated);
+                    }
+                },
+                DirEntry::Log(log) => {
+                    if visited.insert(log.uid) {
+                        self.migrate_object(&log, &mut migrated);
+                    }
+                },
+            }
+        }
+        Ok(migrated)
+    }
+
+    fn migrate_object<T: Serialize + DeserializeOwned + 'static>(&self, obj: &ObjectRef<T>, migrated: &mut usize) -> Option<T> {
+        let (bs, value) = obj.get_encoded(self).ok()?;
+        if is_outdated(&bs) && obj.cas(Some(&bs), &value, &self.0) {
+            *migrated += 1;
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+
+    #[test]
+    fn test_reads_legacy_json() {
+        let file: Labeled<Vec<u8>> = Labeled::new(Buckle::public(), b"data".to_vec(), &SecurityContext::default());
+        let legacy = serde_json::to_vec(&file).unwrap();
+        let decoded: Labeled<Vec<u8>> = decode(&legacy).unwrap();
+        assert_eq!(decoded.data, file.data);
+        assert!(encode(&file).len() < legacy.len());
+        let decoded: Labeled<Vec<u8>> = decode(&encode(&file)).unwrap();
+        assert_eq!(decoded.data, file.data);
+    }
+
+    #[test]
+    fn test_reads_version_1() {
+        #[derive(Serialize)]
+        struct LabeledV1<T> {
+            label: Buckle,
+            data: T,
+        }
+        let mut v1 = vec![MAGIC, 1];
+        options().serialize_into(&mut v1, &LabeledV1 { label: Buckle::public(), data: b"data".to_vec() }).unwrap();
+        let decoded: Labeled<File> = decode(&v1).unwrap();
+        assert_eq!(decoded.data.len(), 4);
+        assert_eq!(decoded.meta.created, 0);
+
+        let mut v1 = vec![MAGIC, 1];
+        options().serialize_into(&mut v1, &FacetedDirectory::default()).unwrap();
+        assert!(decode::<FacetedDirectory>(&v1).is_some());
+        assert!(is_outdated(&v1));
+    }
+
+    #[test]
+    fn test_migrate_encoding() {
+        let fs = FS::new(MemoryStore::new());
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        let root: Labeled<Directory> = Labeled::new(Buckle::new(true, false), Default::default(), &ctx);
+        fs.0.put(&ROOT_REF.uid.to_be_bytes(), &serde_json::to_vec(&root).unwrap());
+        let file: ObjectRef<Labeled<File>> = ObjectRef::new(1);
+        fs.0.put(&file.uid.to_be_bytes(), &serde_json::to_vec(&Labeled::new(Buckle::public(), b"data".to_vec(), &ctx)).unwrap());
+        ROOT_REF.link("f".into(), DirEntry::File(file), &fs, &mut ctx).unwrap();
+
+        // linking rewrote the root already
+        assert_eq!(fs.migrate_encoding().unwrap(), 1);
+        assert_eq!(fs.migrate_encoding().unwrap(), 0);
+        assert!(!is_outdated(&fs.0.get(&file.uid.to_be_bytes()).unwrap()));
+        assert_eq!(fs.read_file(path::Path::parse(":f", &ctx).unwrap(), &mut ctx).unwrap(), b"data".to_vec());
+    }
+}
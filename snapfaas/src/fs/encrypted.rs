@@ -0,0 +1,271 @@
+//! Encryption at rest of any backing store
+//!
+//! `EncryptedStore` seals every value it writes with the key it was given,
+//! authenticating the value's key along with it, so a sealed value moved to
+//! another key fails to authenticate. Sealed values are stored as `MAGIC`
+//! followed by what `crypto::Key::seal` returns, and are opened again on every
+//! read, so callers, e.g., `FS`, only ever see plaintext.
+//!
+//! Values that do not start with `MAGIC` are read as they are, so a store
+//! written before encryption was enabled keeps working until
+//! `encrypt_plaintext` seals its values. Once every value is sealed,
+//! `encrypt_plaintext` writes `STRICT_KEY`, and stores opened from then on
+//! are strict: values that are not sealed are rejected.
+//!
+//! A rejected value, or one that fails to authenticate, is logged and read as
+//! an unreadable value starting with `MAGIC`, which `FS` reports as a corrupted
+//! object, since object encodings never start with `MAGIC`. Keys, the sizes of
+//! values and which values changed are not hidden, and nothing detects a value
+//! being replaced by an older sealed value of the same key.
+use super::cache::CachingStore;
+use super::{BackingStore, ScanIter, Transaction, FS};
+use crate::crypto::Key;
+
+pub const MAGIC: &[u8] = b"\xfa\x00enc";
+
+/// Marks a store whose values are all sealed. Not an object uid, so `FS`
+/// ignores it.
+pub const STRICT_KEY: &[u8] = b"encryption/strict";
+
+const SCAN_PAGE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct EncryptedStore<S> {
+    inner: S,
+    key: Option<Key>,
+    strict: bool,
+}
+
+impl<S: BackingStore> EncryptedStore<S> {
+    /// Seals values written to `inner` with `key`. Values are written in
+    /// plaintext if `key` is `None`.
+    ///
+    /// Panics if `inner` is strict and there is no key, as plaintext written
+    /// to it could not be read.
+    pub fn new(inner: S, key: Option<Key>) -> Self {
+        let strict = inner.get(STRICT_KEY).is_some();
+        if strict && key.is_none() {
+            panic!("the store is encrypted but there is no key");
+        }
+        EncryptedStore { inner, key, strict }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn seal(&self, key: &[u8], value: &[u8]) -> Vec<u8> {
+        match self.key.as_ref() {
+            Some(k) => [MAGIC, &k.seal(value, key)].concat(),
+            None => value.to_vec(),
+        }
+    }
+
+    fn open(&self, key: &[u8], stored: Vec<u8>) -> Vec<u8> {
+        let Some(sealed) = stored.strip_prefix(MAGIC) else {
+            if self.strict {
+                log::error!("Stored value of key {} is not sealed", hex::encode(key));
+                return MAGIC.to_vec();
+            }
+            return stored;
+        };
+        match self.key.as_ref().and_then(|k| k.open(sealed, key)) {
+            Some(value) => value,
+            None => {
+                log::error!("Stored value of key {} failed to authenticate", hex::encode(key));
+                stored
+            }
+        }
+    }
+
+    /// Seals values stored in plaintext, and returns how many were sealed.
+    /// Values that change concurrently are left to their writers. If every
+    /// value is sealed afterwards, writes `STRICT_KEY`, so stores opened from
+    /// then on reject plaintext. Processes still writing plaintext, i.e.,
+    /// without the key, must be stopped first.
+    pub fn encrypt_plaintext(&self) -> usize {
+        if self.key.is_none() {
+            return 0;
+        }
+        let plaintext = |(_, value): &(Vec<u8>, Vec<u8>)| !value.starts_with(MAGIC);
+        let sealed = ScanIter::range(&self.inner, &[], None, SCAN_PAGE_SIZE)
+            .filter(plaintext)
+            .filter(|(key, value)| self.inner.cas(key, Some(value), &self.seal(key, value)).is_ok())
+            .count();
+        if !ScanIter::range(&self.inner, &[], None, SCAN_PAGE_SIZE).any(|pair| plaintext(&pair)) {
+            self.put(STRICT_KEY, &[]);
+        }
+        sealed
+    }
+}
+
+impl<S: BackingStore> BackingStore for EncryptedStore<S> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.inner.get(key).map(|stored| self.open(key, stored))
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) {
+        self.inner.put(key, &self.seal(key, value))
+    }
+
+    fn add(&self, key: &[u8], value: &[u8]) -> bool {
+        self.inner.add(key, &self.seal(key, value))
+    }
+
+    /// Sealing is randomized, so `expected` is compared with the opened
+    /// value, and the stored value it was opened from is swapped, until that
+    /// succeeds or the opened value differs
+    fn cas(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<(), Option<Vec<u8>>> {
+        let sealed = self.seal(key, value);
+        loop {
+            let stored = self.inner.get(key);
+            let current = stored.clone().map(|stored| self.open(key, stored));
+            if current.as_deref() != expected {
+                return Err(current);
+            }
+            if self.inner.cas(key, stored.as_deref(), &sealed).is_ok() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn del(&self, key: &[u8]) {
+        self.inner.del(key)
+    }
+
+    fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.inner.scan(start, end, limit)
+            .into_iter()
+            .map(|(key, stored)| {
+                let value = self.open(&key, stored);
+                (key, value)
+            })
+            .collect()
+    }
+
+    /// Checks are translated like `cas` does, into checks of the stored
+    /// values the expected ones were opened from
+    fn commit(&self, txn: &Transaction) -> bool {
+        let writes: Vec<(&[u8], Option<Vec<u8>>)> = txn.writes().iter()
+            .map(|(key, value)| (key.as_slice(), value.as_ref().map(|value| self.seal(key, value))))
+            .collect();
+        loop {
+            let mut sealed = Transaction::default();
+            for (key, expected) in txn.checks() {
+                let stored = self.inner.get(key);
+                if stored.clone().map(|stored| self.open(key, stored)) != *expected {
+                    return false;
+                }
+                sealed.check(key, stored.as_deref());
+            }
+            for (key, value) in writes.iter() {
+                match value {
+                    Some(value) => sealed.put(key, value),
+                    None => sealed.del(key),
+                }
+            }
+            if self.inner.commit(&sealed) {
+                return true;
+            }
+        }
+    }
+}
+
+impl<S: BackingStore> FS<EncryptedStore<S>> {
+    /// See `EncryptedStore::encrypt_plaintext`
+    pub fn encrypt_plaintext(&self) -> usize {
+        self.0.encrypt_plaintext()
+    }
+}
+
+impl<S: BackingStore> FS<CachingStore<EncryptedStore<S>>> {
+    /// See `EncryptedStore::encrypt_plaintext`. Sealing leaves the values read
+    /// through the cache as they were, so nothing cached goes stale.
+    pub fn encrypt_plaintext(&self) -> usize {
+        self.0.inner().encrypt_plaintext()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+    use super::super::path::Path;
+    use super::super::{DirEntry, FsError, NewEntry, SecurityContext};
+    use labeled::buckle::{Buckle, Component};
+
+    fn path(s: &str) -> Path {
+        Path::parse(s, &SecurityContext::default()).unwrap()
+    }
+
+    #[test]
+    fn test_encrypted_store() {
+        let store = MemoryStore::new();
+        store.put(b"old", b"plain");
+        let key = Key::generate();
+        let encrypted = EncryptedStore::new(store.clone(), Some(key.clone()));
+        encrypted.put(b"a", b"1");
+        assert!(store.get(b"a").unwrap().starts_with(MAGIC));
+        assert_eq!(encrypted.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(encrypted.get(b"old"), Some(b"plain".to_vec()));
+
+        assert_eq!(encrypted.cas(b"a", Some(b"0"), b"2"), Err(Some(b"1".to_vec())));
+        assert_eq!(encrypted.cas(b"a", Some(b"1"), b"2"), Ok(()));
+        let mut txn = Transaction::default();
+        txn.check(b"a", Some(b"2"));
+        txn.check(b"b", None);
+        txn.put(b"b", b"3");
+        assert!(encrypted.commit(&txn));
+        assert!(!encrypted.commit(&txn));
+        assert_eq!(encrypted.scan(b"a", None, 2), vec![(b"a".to_vec(), b"2".to_vec()), (b"b".to_vec(), b"3".to_vec())]);
+
+        assert!(store.get(STRICT_KEY).is_none());
+        assert_eq!(encrypted.encrypt_plaintext(), 1);
+        assert!(store.get(b"old").unwrap().starts_with(MAGIC));
+        assert_eq!(encrypted.get(b"old"), Some(b"plain".to_vec()));
+        assert!(store.get(STRICT_KEY).unwrap().starts_with(MAGIC));
+        assert!(EncryptedStore::new(store, Some(key)).strict);
+    }
+
+    #[test]
+    fn test_unreadable_values_are_corrupted_objects() {
+        let store = MemoryStore::new();
+        let key = Key::generate();
+        let fs = FS::new(EncryptedStore::new(store.clone(), Some(key.clone())));
+        assert!(fs.initialize());
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        let entry = fs.create_and_link(path(":"), "f".into(), NewEntry::File(Buckle::public(), b"secret".to_vec()), &mut ctx).unwrap();
+        let DirEntry::File(file) = entry else { unreachable!() };
+        let uid = file.uid.to_be_bytes();
+        let sealed = store.get(&uid).unwrap();
+        let corrupted = |fs: &FS<EncryptedStore<MemoryStore>>| {
+            matches!(file.get(fs), Err(FsError::CorruptedObject(u)) if u == file.uid)
+        };
+
+        // tampered with, truncated, and opened with another key or none
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        store.put(&uid, &tampered);
+        assert!(matches!(fs.read_file(path(":f"), &mut ctx), Err(FsError::CorruptedObject(u)) if u == file.uid));
+        store.put(&uid, &sealed[..sealed.len() - 1]);
+        assert!(corrupted(&fs));
+        store.put(&uid, &sealed);
+        assert!(!corrupted(&fs));
+        assert!(corrupted(&FS::new(EncryptedStore::new(store.clone(), Some(Key::generate())))));
+        assert!(corrupted(&FS::new(EncryptedStore::new(store.clone(), None))));
+
+        // moved to another key
+        let other = fs.create_and_link(path(":"), "g".into(), NewEntry::File(Buckle::public(), vec![]), &mut ctx).unwrap();
+        store.put(&other.uid().to_be_bytes(), &sealed);
+        assert!(matches!(fs.read_file(path(":g"), &mut ctx), Err(FsError::CorruptedObject(_))));
+
+        // plaintext, once the store is strict
+        let plaintext = fs.0.get(&uid).unwrap();
+        store.put(&uid, &plaintext);
+        assert_eq!(fs.encrypt_plaintext(), 1);
+        let strict = FS::new(EncryptedStore::new(store.clone(), Some(key)));
+        store.put(&uid, &plaintext);
+        assert!(!corrupted(&fs));
+        assert!(corrupted(&strict));
+    }
+}
@@ -7,6 +7,9 @@ pub enum FsError {
     NotABlob,
     NotAGate,
     NotAService,
+    NotAMap,
+    NotALog,
+    NotSubscribable,
     MalformedRedirectTarget,
     ClearanceError,
     LabelError(LabelError),
@@ -19,6 +22,9 @@ pub enum FsError {
     ServiceError(ServiceError),
     NameExists,
     InvalidFd,
+    DanglingReference(u64),
+    CorruptedObject(u64),
+    QuotaExceeded,
 }
 
 impl From<LabelError> for FsError {
@@ -78,6 +84,8 @@ pub enum PrivilegeError {
 pub enum LinkError {
     LabelError(LabelError),
     Exists,
+    /// The linked directory would be within itself
+    Cycle,
 }
 
 #[derive(Debug)]
@@ -0,0 +1,209 @@
+//! Chunked file contents
+//!
+//! A file stores its contents as immutable chunks of `CHUNK_SIZE` bytes
+//! followed by an inline tail shorter than a chunk. Chunks are unlabeled
+//! objects only reachable through their file, so the file's label protects
+//! them as well. Updates stage new chunks in the same transaction that replaces
+//! the file object, and chunks that are no longer referenced are left to the
+//! garbage collector.
+use super::*;
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+pub type Chunk = Vec<u8>;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct File {
+    chunks: Vec<ObjectRef<Chunk>>,
+    /// Shorter than a chunk, except in files written before chunking until
+    /// they are appended to
+    tail: Vec<u8>,
+}
+
+impl File {
+    /// Returns a file of `tail` only, as files were stored before chunking
+    pub(super) fn unchunked(tail: Vec<u8>) -> Self {
+        File { chunks: Vec::new(), tail }
+    }
+
+    /// Returns a file holding `data`, staging its chunks in `txn`
+    pub(super) fn staged(data: &[u8], txn: &mut Transaction) -> Self {
+        let mut file = File::default();
+        file.append(data, txn);
+        file
+    }
+
+    pub fn len(&self) -> u64 {
+        (self.chunks.len() * CHUNK_SIZE + self.tail.len()) as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty() && self.tail.is_empty()
+    }
+
+    pub(super) fn chunks(&self) -> &[ObjectRef<Chunk>] {
+        &self.chunks
+    }
+
+    /// Returns up to `length` bytes starting at `offset`, which are fewer if
+    /// the file ends first
+    pub(super) fn read_range<B: BackingStore>(&self, offset: u64, length: u64, fs: &FS<B>) -> Result<Vec<u8>, FsError> {
+        let len = self.len();
+        let start = offset.min(len) as usize;
+        let end = offset.saturating_add(length).min(len) as usize;
+        let chunked = self.chunks.len() * CHUNK_SIZE;
+
+        let mut res = Vec::with_capacity(end - start);
+        let mut pos = start;
+        while pos < end.min(chunked) {
+            let chunk_ref = &self.chunks[pos / CHUNK_SIZE];
+            let within = pos % CHUNK_SIZE;
+            let n = (CHUNK_SIZE - within).min(end - pos);
+            let chunk = chunk_ref.get(fs)?;
+            res.extend_from_slice(chunk.get(within..within + n).ok_or(FsError::CorruptedObject(chunk_ref.uid))?);
+            pos += n;
+        }
+        if pos < end {
+            res.extend_from_slice(&self.tail[pos - chunked..end - chunked]);
+        }
+        Ok(res)
+    }
+
+    /// Appends `data`, staging the chunks it fills in `txn`
+    pub(super) fn append(&mut self, data: &[u8], txn: &mut Transaction) {
+        self.tail.extend_from_slice(data);
+        if self.tail.len() >= CHUNK_SIZE {
+            let tail = self.tail.split_off(self.tail.len() / CHUNK_SIZE * CHUNK_SIZE);
+            let full = std::mem::replace(&mut self.tail, tail);
+            for chunk in full.chunks(CHUNK_SIZE) {
+                self.chunks.push(ObjectRef::stage_new(&chunk.to_vec(), txn));
+            }
+        }
+    }
+
+    /// Shortens the file to `length` bytes, and returns whether it was longer
+    pub(super) fn truncate<B: BackingStore>(&mut self, length: u64, fs: &FS<B>) -> Result<bool, FsError> {
+        if length >= self.len() {
+            return Ok(false);
+        }
+        let length = length as usize;
+        let kept = length / CHUNK_SIZE;
+        if kept < self.chunks.len() {
+            let chunk_ref = self.chunks[kept];
+            let chunk = chunk_ref.get(fs)?;
+            let tail = chunk.get(..length % CHUNK_SIZE).ok_or(FsError::CorruptedObject(chunk_ref.uid))?;
+            self.tail = tail.to_vec();
+            self.chunks.truncate(kept);
+        } else {
+            self.tail.truncate(length - self.chunks.len() * CHUNK_SIZE);
+        }
+        Ok(true)
+    }
+}
+
+impl ObjectRef<Labeled<File>> {
+    pub fn read<B: BackingStore>(&self, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<Vec<u8>, FsError> {
+        let file = self.get(fs)?;
+        let file = file.unlabel(ctx)?;
+        file.read_range(0, file.len(), fs)
+    }
+
+    /// Reads up to `length` bytes starting at `offset`
+    pub fn read_range<B: BackingStore>(&self, offset: u64, length: u64, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<Vec<u8>, FsError> {
+        self.get(fs)?.unlabel(ctx)?.read_range(offset, length, fs)
+    }
+
+    pub fn write<B: BackingStore>(&self, data: Vec<u8>, fs: &FS<B>, ctx: &SecurityContext) -> Result<(), FsError> {
+        self.update(fs, ctx, data.len() as u64, |file, txn| {
+            *file = File::staged(&data, txn);
+            Ok(true)
+        })
+    }
+
+    pub fn append<B: BackingStore>(&self, data: &[u8], fs: &FS<B>, ctx: &SecurityContext) -> Result<(), FsError> {
+        self.update(fs, ctx, data.len() as u64, |file, txn| {
+            file.append(data, txn);
+            Ok(true)
+        })
+    }
+
+    /// Shortens the file to `length` bytes, or does nothing if it is not longer
+    pub fn truncate<B: BackingStore>(&self, length: u64, fs: &FS<B>, ctx: &SecurityContext) -> Result<(), FsError> {
+        self.update(fs, ctx, 0, |file, _| file.truncate(length, fs))
+    }
+
+    /// Applies `f` to the file and commits the result together with the chunks
+    /// `f` stages, retrying on conflicts, unless `f` returns that it changed
+    /// nothing. Changes in length are charged to the file's creator, and the
+    /// `written` bytes must fit in its quota.
+    ///
+    /// Like `Labeled::write`, updates do not taint the current label, as
+    /// nothing about the old contents is returned. Subscribers are notified of
+    /// each update.
+    fn update<B, F>(&self, fs: &FS<B>, ctx: &SecurityContext, written: u64, mut f: F) -> Result<(), FsError>
+    where
+        B: BackingStore,
+        F: FnMut(&mut File, &mut Transaction) -> Result<bool, FsError>,
+    {
+        let key = self.uid.to_be_bytes();
+        loop {
+            let (prev, mut labeled) = self.get_encoded(fs)?;
+            let mut txn = Transaction::default();
+            let old_len = labeled.data.len();
+            if !labeled.modify_blind(ctx, |file| f(file, &mut txn))?? {
+                return Ok(());
+            }
+            let charge = Charge::blind_file_bytes(old_len, labeled.data.len(), written);
+            fs.stage_charge(&labeled.meta.creator, &charge, &mut txn)?;
+            txn.check(&key, Some(&prev));
+            txn.put(&key, encoding::encode(&labeled).as_slice());
+            if fs.0.commit(&txn) {
+                fs.notify(self.uid, Event::Write, ctx);
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_chunked_read_append_truncate() {
+        let fs = FS::new(MemoryStore::new());
+        let mut ctx = SecurityContext::default();
+        let file: ObjectRef<Labeled<File>> = ObjectRef::create(Buckle::public(), &fs.0, &ctx);
+        let data = pattern(2 * CHUNK_SIZE + 10);
+        file.write(data[..CHUNK_SIZE - 1].to_vec(), &fs, &ctx).unwrap();
+        file.append(&data[CHUNK_SIZE - 1..], &fs, &ctx).unwrap();
+        assert_eq!(file.get(&fs).unwrap().data.chunks.len(), 2);
+        assert_eq!(file.read(&fs, &mut ctx).unwrap(), data);
+
+        let offset = CHUNK_SIZE as u64 - 5;
+        assert_eq!(file.read_range(offset, CHUNK_SIZE as u64, &fs, &mut ctx).unwrap(), data[CHUNK_SIZE - 5..2 * CHUNK_SIZE - 5]);
+        assert_eq!(file.read_range(2 * CHUNK_SIZE as u64, 100, &fs, &mut ctx).unwrap(), data[2 * CHUNK_SIZE..]);
+        assert!(file.read_range(1 << 40, 100, &fs, &mut ctx).unwrap().is_empty());
+
+        file.truncate(CHUNK_SIZE as u64 + 3, &fs, &ctx).unwrap();
+        assert_eq!(file.get(&fs).unwrap().data.chunks.len(), 1);
+        assert_eq!(file.read(&fs, &mut ctx).unwrap(), data[..CHUNK_SIZE + 3]);
+        let before = fs.0.get(&file.uid.to_be_bytes());
+        file.truncate(CHUNK_SIZE as u64 + 3, &fs, &ctx).unwrap();
+        file.truncate(1 << 40, &fs, &ctx).unwrap();
+        assert_eq!(fs.0.get(&file.uid.to_be_bytes()), before);
+    }
+
+    #[test]
+    fn test_reads_unchunked_files() {
+        let legacy = serde_json::to_vec(&Labeled::new(Buckle::public(), pattern(CHUNK_SIZE + 1), &SecurityContext::default())).unwrap();
+        let file: Labeled<File> = encoding::decode(&legacy).unwrap();
+        assert_eq!(file.data.len(), CHUNK_SIZE as u64 + 1);
+        assert!(file.data.chunks.is_empty());
+    }
+}
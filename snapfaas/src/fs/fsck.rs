@@ -0,0 +1,314 @@
+//! Consistency checking
+//!
+//! `FS::fsck` validates every object reachable from `ROOT_REF` the way the
+//! garbage collector marks them, reporting each entry whose object, or an
+//! object it depends on (chunks, segments, the target of a redirect gate or a
+//! blob in the blobstore), is missing or cannot be decoded, and lists the
+//! stored objects that are not reachable. `FS::repair` unlinks the damaged
+//! entries it reported.
+//!
+//! Like the garbage collector, both read and write objects directly without
+//! checking or tainting any label. They must only be run by trusted tooling
+//! (e.g., `admin_fstools`).
+use std::collections::HashSet;
+use std::convert::TryInto;
+
+use super::*;
+
+const SCAN_PAGE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The object does not exist
+    Dangling(u64),
+    /// The object cannot be decoded
+    Corrupted(u64),
+    /// The blob is not in the blobstore
+    MissingBlob(Blob),
+    /// Following redirect gates leads back to this gate
+    RedirectCycle(u64),
+    /// Reading an object failed otherwise, with this error
+    Unreadable(String),
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Dangling(uid) => write!(f, "object {:016x} does not exist", uid),
+            Problem::Corrupted(uid) => write!(f, "object {:016x} cannot be decoded", uid),
+            Problem::MissingBlob(blob) => write!(f, "blob {} is missing", blob),
+            Problem::RedirectCycle(uid) => write!(f, "redirect gates cycle through {:016x}", uid),
+            Problem::Unreadable(err) => write!(f, "object cannot be read: {}", err),
+        }
+    }
+}
+
+/// An entry that cannot be used
+#[derive(Debug, Clone)]
+pub struct Damage {
+    /// Path of the entry from the root. The last component is the entry's name
+    /// in `parent`, or its facet if `parent` is a faceted directory.
+    pub path: Path,
+    pub parent: u64,
+    pub entry: DirEntry,
+    pub problem: Problem,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    /// Number of reachable objects checked
+    pub checked: usize,
+    pub damaged: Vec<Damage>,
+    /// Uids of stored objects that are not reachable from `ROOT_REF`
+    pub orphans: Vec<u64>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.damaged.is_empty() && self.orphans.is_empty()
+    }
+}
+
+/// Returns the entries of `dir`, stored under `parent` at `path`, to be popped
+/// off a stack in name order
+fn children(path: &Path, parent: u64, dir: Directory) -> impl Iterator<Item = (Path, u64, DirEntry)> + '_ {
+    dir.entries.into_iter().rev().map(move |(name, entry)| {
+        let mut path = path.clone();
+        path.push_dscrp(name);
+        (path, parent, entry)
+    })
+}
+
+fn problem(err: FsError) -> Problem {
+    match err {
+        FsError::DanglingReference(uid) => Problem::Dangling(uid),
+        FsError::CorruptedObject(uid) => Problem::Corrupted(uid),
+        e => Problem::Unreadable(format!("{:?}", e)),
+    }
+}
+
+impl<S: BackingStore> FS<S> {
+    /// Checks the objects reachable from `ROOT_REF` and blobs against
+    /// `saved_blobs`, the names of the blobs in the blobstore. Fails if the
+    /// root itself is missing or corrupted.
+    ///
+    /// Entries are visited once, even if linked more than once, and reported
+    /// under the first path they are found at. Objects created but not linked
+    /// yet are reported as orphans.
+    pub fn fsck(&self, saved_blobs: &HashSet<Blob>) -> Result<FsckReport, FsError> {
+        let root = ROOT_REF.get(self)?;
+
+        let mut report = FsckReport::default();
+        let mut marked = HashSet::from([ROOT_REF.uid]);
+        let mut visited = HashSet::new();
+        let mut stack: Vec<(Path, u64, DirEntry)> = Vec::new();
+        stack.extend(children(&Path::root(), ROOT_REF.uid, root.data));
+        while let Some((path, parent, entry)) = stack.pop() {
+            let uid = entry.uid();
+            if !visited.insert(uid) {
+                continue;
+            }
+            marked.insert(uid);
+            report.checked += 1;
+            match self.check(&entry, &mut marked, saved_blobs) {
+                Ok(Some(DirEntry::Directory(dir))) => match dir.get(self) {
+                    Ok(dir) => stack.extend(children(&path, uid, dir.data)),
+                    Err(e) => report.damaged.push(Damage { path, parent, entry, problem: problem(e) }),
+                },
+                Ok(Some(DirEntry::FacetedDirectory(faceted))) => {
+                    // a missing faceted directory has no facets yet
+                    if let Ok(faceted) = faceted.get(self) {
+                        stack.extend(faceted.facets.into_iter().rev().map(|(facet, dir)| {
+                            let mut path = path.clone();
+                            path.push(PathComponent::Facet(facet));
+                            (path, uid, DirEntry::Directory(dir))
+                        }));
+                    }
+                },
+                Ok(_) => (),
+                Err(problem) => report.damaged.push(Damage { path, parent, entry, problem }),
+            }
+        }
+
+        report.orphans = ScanIter::range(&self.0, &[], None, SCAN_PAGE_SIZE).filter_map(|(key, _)| {
+            let uid = u64::from_be_bytes(key.as_slice().try_into().ok()?);
+            if marked.contains(&uid) {
+                None
+            } else {
+                Some(uid)
+            }
+        }).collect();
+        Ok(report)
+    }
+
+    /// Checks the object of `entry`, unless it is a directory, and the objects
+    /// it depends on, marking them. Returns the entry if it is a directory or
+    /// faceted directory to descend into.
+    fn check(&self, entry: &DirEntry, marked: &mut HashSet<u64>, saved_blobs: &HashSet<Blob>) -> Result<Option<DirEntry>, Problem> {
+        let blob_saved = |blob: &Blob| {
+            if blob.is_empty() || saved_blobs.contains(blob) {
+                Ok(())
+            } else {
+                Err(Problem::MissingBlob(blob.clone()))
+            }
+        };
+        match entry {
+            DirEntry::Directory(_) => return Ok(Some(entry.clone())),
+            DirEntry::FacetedDirectory(faceted) => match faceted.get(self) {
+                Ok(_) | Err(FsError::DanglingReference(_)) => return Ok(Some(entry.clone())),
+                Err(e) => return Err(problem(e)),
+            },
+            DirEntry::File(file) => {
+                let file = file.get(self).map_err(problem)?;
+                marked.extend(file.data.chunks().iter().map(|chunk| chunk.uid));
+                for chunk in file.data.chunks() {
+                    chunk.get(self).map_err(problem)?;
+                }
+            },
+            DirEntry::Gate(gate) => {
+                // a broken target breaks every redirect gate leading to it
+                let mut gate = *gate;
+                let mut chain = HashSet::new();
+                loop {
+                    marked.insert(gate.uid);
+                    if !chain.insert(gate.uid) {
+                        return Err(Problem::RedirectCycle(gate.uid));
+                    }
+                    match gate.get(self).map_err(problem)?.data {
+                        Gate::Direct(direct) => {
+                            blob_saved(&direct.function.app_image)?;
+                            blob_saved(&direct.function.runtime_image)?;
+                            blob_saved(&direct.function.kernel)?;
+                            break;
+                        },
+                        Gate::Redirect(redirect) => gate = redirect.gate,
+                    }
+                }
+            },
+            DirEntry::Service(service) => { service.get(self).map_err(problem)?; },
+            DirEntry::Blob(blob) => blob_saved(&blob.get(self).map_err(problem)?.data)?,
+            DirEntry::Map(map) => { map.get(self).map_err(problem)?; },
+            DirEntry::Log(log) => {
+                let log = log.get(self).map_err(problem)?;
+                marked.extend(log.data.segments().map(|segment| segment.uid));
+                for segment in log.data.segments() {
+                    segment.get(self).map_err(problem)?;
+                }
+            },
+        }
+        Ok(None)
+    }
+
+    /// Unlinks the entries `report` found damaged from their parents, unless
+    /// they have been replaced since. Returns the number of entries unlinked.
+    ///
+    /// The objects they refer to are left to the garbage collector.
+    pub fn repair(&self, report: &FsckReport) -> Result<usize, FsError> {
+        let mut unlinked = 0;
+        for damage in report.damaged.iter() {
+            let uid = damage.entry.uid();
+            let removed = match damage.path.last() {
+                Some(PathComponent::Dscrp(name)) => {
+                    let dir = ObjectRef::<Labeled<Directory>>::new(damage.parent);
+                    self.update_unchecked(&dir, |dir| {
+                        if dir.data.entries.get(name).map(DirEntry::uid) == Some(uid) {
+                            dir.data.entries.remove(name);
+                            true
+                        } else {
+                            false
+                        }
+                    })?
+                },
+                Some(PathComponent::Facet(facet)) => {
+                    let faceted = ObjectRef::<FacetedDirectory>::new(damage.parent);
+                    self.update_unchecked(&faceted, |faceted| {
+                        let len = faceted.facets.len();
+                        faceted.facets.retain(|(label, dir)| label != facet || dir.uid != uid);
+                        faceted.facets.len() < len
+                    })?
+                },
+                None => false,
+            };
+            if removed {
+                unlinked += 1;
+            }
+        }
+        Ok(unlinked)
+    }
+
+    /// Applies `f` to the object and stores it if `f` returns true, retrying
+    /// on conflicts. Returns what `f` returned.
+    fn update_unchecked<T, F>(&self, obj: &ObjectRef<T>, mut f: F) -> Result<bool, FsError>
+    where
+        T: Serialize + DeserializeOwned + 'static,
+        F: FnMut(&mut T) -> bool,
+    {
+        loop {
+            let (prev, mut value) = obj.get_encoded(self)?;
+            if !f(&mut value) {
+                return Ok(false);
+            }
+            if obj.cas(Some(&prev), &value, &self.0) {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+
+    #[test]
+    fn test_fsck_and_repair() {
+        let fs = FS::new(MemoryStore::new());
+        fs.initialize();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        let root = Path::root();
+        let direct = fs.create_and_link(root.clone(), "direct".into(), NewEntry::DirectGate(Buckle::public(), DirectGate {
+            privilege: Component::dc_true(),
+            invoker_integrity_clearance: Component::dc_true(),
+            declassify: Component::dc_true(),
+            function: Function { app_image: "saved".into(), ..Default::default() },
+        }), &mut ctx).unwrap();
+        let DirEntry::Gate(direct) = direct else { panic!("not a gate") };
+        fs.create_and_link(root.clone(), "redirect".into(), NewEntry::RedirectGate(Buckle::public(), RedirectGate {
+            privilege: Component::dc_true(),
+            invoker_integrity_clearance: Component::dc_true(),
+            declassify: Component::dc_true(),
+            gate: direct,
+        }), &mut ctx).unwrap();
+        fs.create_and_link(root.clone(), "image".into(), NewEntry::Blob(Buckle::public(), "missing".into()), &mut ctx).unwrap();
+        let DirEntry::File(file) = fs.create_and_link(root.clone(), "f".into(), NewEntry::File(Buckle::public(), vec![]), &mut ctx).unwrap() else { panic!("not a file") };
+        file.write(vec![0; 3 * CHUNK_SIZE], &fs, &ctx).unwrap();
+
+        let saved = HashSet::from(["saved".to_string()]);
+        let report = fs.fsck(&saved).unwrap();
+        let problems: Vec<(String, Problem)> = report.damaged.iter().map(|d| (d.path.to_string(), d.problem.clone())).collect();
+        assert_eq!(problems, vec![(":image".to_string(), Problem::MissingBlob("missing".into()))]);
+        assert!(report.orphans.is_empty());
+
+        // gc-style breakage: delete the direct gate and a chunk, and leave an orphan
+        let chunk = file.get(&fs).unwrap().data.chunks()[1];
+        fs.0.del(&chunk.uid.to_be_bytes());
+        fs.0.del(&direct.uid.to_be_bytes());
+        let orphan = ObjectRef::<Labeled<Directory>>::create(Buckle::public(), &fs.0, &ctx);
+
+        let report = fs.fsck(&saved).unwrap();
+        let problems: Vec<(String, Problem)> = report.damaged.iter().map(|d| (d.path.to_string(), d.problem.clone())).collect();
+        assert_eq!(problems, vec![
+            (":direct".to_string(), Problem::Dangling(direct.uid)),
+            (":f".to_string(), Problem::Dangling(chunk.uid)),
+            (":image".to_string(), Problem::MissingBlob("missing".into())),
+            (":redirect".to_string(), Problem::Dangling(direct.uid)),
+        ]);
+        assert_eq!(report.orphans, vec![orphan.uid]);
+
+        assert_eq!(fs.repair(&report).unwrap(), 4);
+        assert!(ROOT_REF.get(&fs).unwrap().data.entries.is_empty());
+        let report = fs.fsck(&saved).unwrap();
+        assert!(report.damaged.is_empty());
+        assert!(!report.is_clean());
+    }
+}
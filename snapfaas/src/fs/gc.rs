@@ -0,0 +1,186 @@
+//! Mark-and-sweep garbage collection of unreachable objects and blob reference
+//! counting for the blob garbage collector
+//!
+//! The collector reads objects directly without calling `unlabel`, so it never
+//! taints any context's label. It must only be run by trusted tooling
+//! (e.g., the `garbage-collector` binary).
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+
+use super::*;
+
+const SCAN_PAGE_SIZE: usize = 1024;
+
+/// Returns the object read while marking, `None` if the reference dangles, or
+/// the error that makes marking incomplete
+fn followed<T>(res: Result<T, FsError>) -> Result<Option<T>, FsError> {
+    match res {
+        Ok(object) => Ok(Some(object)),
+        Err(FsError::DanglingReference(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl<S: BackingStore> FS<S> {
+    /// Returns the uids of all objects reachable from `ROOT_REF`, or an error
+    /// if the root does not exist.
+    ///
+    /// Directories, every facet of faceted directories and the targets of
+    /// redirect gates are followed. Dangling references are marked but not
+    /// followed. Any other error reading a reachable object is returned, as
+    /// whatever it refers to would look unreachable.
+    pub fn reachable(&self) -> Result<HashSet<u64>, FsError> {
+        self.mark().map(|(marked, _)| marked)
+    }
+
+    /// Returns the number of reachable references to each blob, from blob
+    /// objects and from the functions of direct gates.
+    pub fn blob_references(&self) -> Result<HashMap<Blob, usize>, FsError> {
+        self.mark().map(|(_, blobs)| blobs)
+    }
+
+    fn mark(&self) -> Result<(HashSet<u64>, HashMap<Blob, usize>), FsError> {
+        // Without a root everything would look unreachable. Refuse instead.
+        let root = ROOT_REF.get(self)?;
+
+        let mut marked = HashSet::new();
+        let mut blobs = HashMap::new();
+        let mut reference = |blob: Blob| {
+            if !blob.is_empty() {
+                *blobs.entry(blob).or_insert(0) += 1;
+            }
+        };
+        marked.insert(ROOT_REF.uid);
+        let mut stack: Vec<DirEntry> = root.data.entries.into_values().collect();
+        while let Some(entry) = stack.pop() {
+            match entry {
+                DirEntry::Directory(dir) => {
+                    if marked.insert(dir.uid) {
+                        if let Some(dir) = followed(dir.get(self))? {
+                            stack.extend(dir.data.entries.into_values());
+                        }
+                    }
+                },
+                DirEntry::FacetedDirectory(faceted) => {
+                    if marked.insert(faceted.uid) {
+                        if let Some(faceted) = followed(faceted.get(self))? {
+                            stack.extend(faceted.facets.into_iter().map(|(_, dir)| DirEntry::Directory(dir)));
+                        }
+                    }
+                },
+                DirEntry::Gate(gate) => {
+                    if marked.insert(gate.uid) {
+                        match followed(gate.get(self))?.map(|g| g.data) {
+                            Some(Gate::Redirect(redirect)) => stack.push(DirEntry::Gate(redirect.gate)),
+                            Some(Gate::Direct(direct)) => {
+                                reference(direct.function.app_image);
+                                reference(direct.function.runtime_image);
+                                reference(direct.function.kernel);
+                            },
+                            None => (),
+                        }
+                    }
+                },
+                DirEntry::Blob(blob) => {
+                    if marked.insert(blob.uid) {
+                        if let Some(blob) = followed(blob.get(self))? {
+                            reference(blob.data);
+                        }
+                    }
+                },
+                DirEntry::File(file) => {
+                    if marked.insert(file.uid) {
+                        if let Some(file) = followed(file.get(self))? {
+                            marked.extend(file.data.chunks().iter().map(|chunk| chunk.uid));
+                        }
+                    }
+                },
+                DirEntry::Service(service) => { marked.insert(service.uid); },
+                DirEntry::Map(map) => { marked.insert(map.uid); },
+                DirEntry::Log(log) => {
+                    if marked.insert(log.uid) {
+                        if let Some(log) = followed(log.get(self))? {
+                            marked.extend(log.data.segments().map(|segment| segment.uid));
+                        }
+                    }
+                },
+            }
+        }
+        Ok((marked, blobs))
+    }
+
+    /// Returns the uids of stored objects that are not reachable from `ROOT_REF`.
+    ///
+    /// Keys that are not object uids are ignored.
+    pub fn unreachable(&self) -> Result<Vec<u64>, FsError> {
+        let reachable = self.reachable()?;
+        Ok(ScanIter::range(&self.0, &[], None, SCAN_PAGE_SIZE).filter_map(|(key, _)| {
+            let uid = u64::from_be_bytes(key.as_slice().try_into().ok()?);
+            if reachable.contains(&uid) {
+                None
+            } else {
+                Some(uid)
+            }
+        }).collect())
+    }
+
+    /// Deletes all objects unreachable from `ROOT_REF` and returns their uids.
+    /// Quota charges of deleted objects are refunded.
+    ///
+    /// Objects created but not linked yet are unreachable, so this should only be
+    /// used when there are no concurrent writers. Use `collect_garbage_among` with
+    /// the result of an earlier `unreachable` otherwise.
+    pub fn collect_garbage(&self) -> Result<Vec<u64>, FsError> {
+        self.sweep(self.unreachable()?)
+    }
+
+    /// Deletes objects that are unreachable from `ROOT_REF` and were also in
+    /// `candidates`, i.e., unreachable at some earlier point in time.
+    ///
+    /// Returns the uids of the deleted objects and the uids of the remaining
+    /// unreachable objects, which are the candidates for the next collection.
+    pub fn collect_garbage_among(&self, candidates: &HashSet<u64>) -> Result<(Vec<u64>, HashSet<u64>), FsError> {
+        let (garbage, fresh): (Vec<u64>, Vec<u64>) = self.unreachable()?
+            .into_iter()
+            .partition(|uid| candidates.contains(uid));
+        Ok((self.sweep(garbage)?, fresh.into_iter().collect()))
+    }
+
+    fn sweep(&self, garbage: Vec<u64>) -> Result<Vec<u64>, FsError> {
+        for uid in garbage.iter() {
+            self.delete_object(*uid);
+            self.delete_subscriptions(*uid);
+        }
+        Ok(garbage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+
+    #[test]
+    fn test_collection_aborts_on_unreadable_directory() {
+        let fs = FS::new(MemoryStore::new());
+        assert!(fs.initialize());
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        let root = path::Path::root();
+        let dir = fs.create_and_link(root.clone(), "dir".into(), NewEntry::Directory(Buckle::public()), &mut ctx).unwrap();
+        let dir_path = path::Path::parse(":dir", &ctx).unwrap();
+        fs.create_and_link(dir_path, "f".into(), NewEntry::File(Buckle::public(), b"data".to_vec()), &mut ctx).unwrap();
+        // dangling references do not stop collection
+        fs.link(root, "dangling".into(), DirEntry::Directory(ObjectRef::new(42)), &mut ctx).unwrap();
+        assert!(fs.collect_garbage().unwrap().is_empty());
+
+        let DirEntry::Directory(dir) = dir else { unreachable!() };
+        fs.0.put(&dir.uid.to_be_bytes(), b"garbage");
+        let objects = fs.0.scan(&[], None, usize::MAX);
+        assert!(matches!(fs.collect_garbage(), Err(FsError::CorruptedObject(uid)) if uid == dir.uid));
+        let candidates: HashSet<u64> = objects.iter()
+            .filter_map(|(key, _)| Some(u64::from_be_bytes(key.as_slice().try_into().ok()?)))
+            .collect();
+        assert!(fs.collect_garbage_among(&candidates).is_err());
+        assert_eq!(fs.0.scan(&[], None, usize::MAX), objects);
+    }
+}
@@ -0,0 +1,251 @@
+//! Operation metrics of any backing store
+//!
+//! `InstrumentedStore` counts the operations on the store it wraps, the bytes
+//! they move and their latencies, and how often `cas`, `add` and `commit`
+//! fail because the store changed, which is how often the retry loops of,
+//! e.g., `Directory::link` and `FacetedDirectory::open` go around again.
+//!
+//! Each clone records into metrics of its own, which `take_metrics` returns
+//! and resets, as well as into the totals shared by all clones. A worker owns
+//! a clone and handles one invocation at a time, so taking the metrics before
+//! and after an invocation yields the invocation's. The totals, with their
+//! latency histograms, are logged as JSON every so often if
+//! `log_totals_every` was called. It should be the outermost store, so it
+//! sees what the file system asks of the stores below.
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use super::{BackingStore, Transaction, FS};
+use crate::sched::message::StoreUsage;
+
+pub const LATENCY_BUCKETS: usize = 24;
+
+/// Latencies in power-of-two buckets of microseconds
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Histogram {
+    /// `buckets[i]` counts latencies under 2^i microseconds that are not
+    /// counted by earlier buckets. The last bucket counts all the rest.
+    pub buckets: [u64; LATENCY_BUCKETS],
+    pub sum_micros: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+        self.sum_micros += micros;
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct OpMetrics {
+    pub count: u64,
+    /// Bytes of keys and values read for `get` and `scan`, written otherwise
+    pub bytes: u64,
+    pub latency: Histogram,
+}
+
+impl OpMetrics {
+    fn record(&mut self, bytes: usize, latency: Duration) {
+        self.count += 1;
+        self.bytes += bytes as u64;
+        self.latency.record(latency);
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StoreMetrics {
+    pub get: OpMetrics,
+    pub put: OpMetrics,
+    pub add: OpMetrics,
+    pub cas: OpMetrics,
+    pub del: OpMetrics,
+    pub scan: OpMetrics,
+    pub commit: OpMetrics,
+    /// `cas` calls that failed because the value was not the expected one
+    pub cas_conflicts: u64,
+    /// `add` calls that failed because the key existed
+    pub add_conflicts: u64,
+    /// `commit` calls that failed because a check did not hold
+    pub commit_conflicts: u64,
+}
+
+impl StoreMetrics {
+    fn ops(&self) -> [&OpMetrics; 7] {
+        [&self.get, &self.put, &self.add, &self.cas, &self.del, &self.scan, &self.commit]
+    }
+}
+
+impl From<&StoreMetrics> for StoreUsage {
+    fn from(metrics: &StoreMetrics) -> Self {
+        StoreUsage {
+            operations: metrics.ops().iter().map(|op| op.count).sum(),
+            bytes_read: metrics.get.bytes + metrics.scan.bytes,
+            bytes_written: metrics.ops().iter().map(|op| op.bytes).sum::<u64>() - metrics.get.bytes - metrics.scan.bytes,
+            conflicts: metrics.cas_conflicts + metrics.add_conflicts + metrics.commit_conflicts,
+            latency_micros: metrics.ops().iter().map(|op| op.latency.sum_micros).sum(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InstrumentedStore<S> {
+    inner: S,
+    local: Mutex<StoreMetrics>,
+    totals: Arc<Mutex<StoreMetrics>>,
+}
+
+impl<S: Clone> Clone for InstrumentedStore<S> {
+    /// Returns a store sharing the totals but recording into metrics of its own
+    fn clone(&self) -> Self {
+        InstrumentedStore { inner: self.inner.clone(), local: Default::default(), totals: Arc::clone(&self.totals) }
+    }
+}
+
+impl<S: BackingStore> InstrumentedStore<S> {
+    pub fn new(inner: S) -> Self {
+        InstrumentedStore { inner, local: Default::default(), totals: Default::default() }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the metrics of all clones
+    pub fn totals(&self) -> StoreMetrics {
+        self.totals.lock().unwrap().clone()
+    }
+
+    /// Logs the totals every `interval` from a thread of their own, for as
+    /// long as the process runs
+    pub fn log_totals_every(&self, interval: Duration) {
+        let totals = Arc::clone(&self.totals);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let totals = totals.lock().unwrap().clone();
+            match serde_json::to_string(&totals) {
+                Ok(json) => log::info!("store metrics {}", json),
+                Err(e) => log::error!("Failed to encode store metrics. {:?}", e),
+            }
+        });
+    }
+
+    fn record<F: Fn(&mut StoreMetrics)>(&self, f: F) {
+        f(&mut self.local.lock().unwrap());
+        f(&mut self.totals.lock().unwrap());
+    }
+}
+
+fn pairs_len(pairs: &[(Vec<u8>, Option<Vec<u8>>)]) -> usize {
+    pairs.iter().map(|(key, value)| key.len() + value.as_ref().map_or(0, Vec::len)).sum()
+}
+
+impl<S: BackingStore> BackingStore for InstrumentedStore<S> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let start = Instant::now();
+        let value = self.inner.get(key);
+        let latency = start.elapsed();
+        self.record(|m| m.get.record(key.len() + value.as_ref().map_or(0, Vec::len), latency));
+        value
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) {
+        let start = Instant::now();
+        self.inner.put(key, value);
+        let latency = start.elapsed();
+        self.record(|m| m.put.record(key.len() + value.len(), latency));
+    }
+
+    fn add(&self, key: &[u8], value: &[u8]) -> bool {
+        let start = Instant::now();
+        let added = self.inner.add(key, value);
+        let latency = start.elapsed();
+        self.record(|m| {
+            m.add.record(key.len() + value.len(), latency);
+            m.add_conflicts += !added as u64;
+        });
+        added
+    }
+
+    fn cas(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<(), Option<Vec<u8>>> {
+        let start = Instant::now();
+        let res = self.inner.cas(key, expected, value);
+        let latency = start.elapsed();
+        self.record(|m| {
+            m.cas.record(key.len() + value.len(), latency);
+            m.cas_conflicts += res.is_err() as u64;
+        });
+        res
+    }
+
+    fn del(&self, key: &[u8]) {
+        let start = Instant::now();
+        self.inner.del(key);
+        let latency = start.elapsed();
+        self.record(|m| m.del.record(key.len(), latency));
+    }
+
+    fn scan(&self, start_key: &[u8], end: Option<&[u8]>, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let start = Instant::now();
+        let pairs = self.inner.scan(start_key, end, limit);
+        let latency = start.elapsed();
+        let bytes = pairs.iter().map(|(key, value)| key.len() + value.len()).sum();
+        self.record(|m| m.scan.record(bytes, latency));
+        pairs
+    }
+
+    fn commit(&self, txn: &Transaction) -> bool {
+        let start = Instant::now();
+        let committed = self.inner.commit(txn);
+        let latency = start.elapsed();
+        self.record(|m| {
+            m.commit.record(pairs_len(txn.writes()), latency);
+            m.commit_conflicts += !committed as u64;
+        });
+        committed
+    }
+
+    fn take_metrics(&self) -> Option<StoreMetrics> {
+        Some(std::mem::take(&mut *self.local.lock().unwrap()))
+    }
+}
+
+impl<S: BackingStore> FS<S> {
+    /// Returns and resets the metrics recorded by the store since they were
+    /// last taken, if it records any
+    pub fn take_store_metrics(&self) -> Option<StoreMetrics> {
+        self.0.take_metrics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+
+    #[test]
+    fn test_instrumented_store() {
+        let store = InstrumentedStore::new(MemoryStore::new());
+        let worker = store.clone();
+        worker.put(b"k", b"v1");
+        assert_eq!(worker.get(b"k"), Some(b"v1".to_vec()));
+        assert!(worker.cas(b"k", Some(b"v0"), b"v2").is_err());
+        assert!(worker.cas(b"k", Some(b"v1"), b"v2").is_ok());
+
+        let metrics = worker.take_metrics().unwrap();
+        assert_eq!((metrics.put.count, metrics.get.count, metrics.cas.count), (1, 1, 2));
+        assert_eq!((metrics.get.bytes, metrics.cas_conflicts), (3, 1));
+        assert_eq!(metrics.get.latency.buckets.iter().sum::<u64>(), 1);
+        let usage = StoreUsage::from(&metrics);
+        assert_eq!((usage.operations, usage.bytes_read, usage.bytes_written, usage.conflicts), (4, 3, 9, 1));
+
+        assert_eq!(worker.take_metrics(), Some(StoreMetrics::default()));
+        assert_eq!(store.totals(), metrics);
+        store.get(b"k");
+        assert_eq!(worker.totals().get.count, 2);
+        assert_eq!(MemoryStore::new().take_metrics(), None);
+    }
+}
@@ -1,4 +1,4 @@
-use lmdb::{self, Transaction, WriteFlags};
+use lmdb::{self, Cursor, Transaction, WriteFlags};
 
 pub fn get_dbenv(path: &str) -> lmdb::Environment {
     let path = std::path::Path::new(path);
@@ -65,4 +65,40 @@ impl super::BackingStore for lmdb::Environment {
         let _ = txn.del(db, &key, None);
         txn.commit().unwrap();
     }
+
+    fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let db = self.open_db(None).unwrap();
+        let txn = self.begin_ro_txn().unwrap();
+        let res = {
+            let mut cursor = txn.open_ro_cursor(db).unwrap();
+            // LMDB rejects empty keys, so an empty start means the first key
+            let iter = if start.is_empty() { cursor.iter_start() } else { cursor.iter_from(start) };
+            iter.filter_map(Result::ok)
+                .take_while(|(k, _)| end.is_none_or(|end| *k < end))
+                .take(limit)
+                .map(|(k, v)| (Vec::from(k), Vec::from(v)))
+                .collect()
+        };
+        txn.commit().unwrap();
+        res
+    }
+
+    fn commit(&self, txn: &super::Transaction) -> bool {
+        let db = self.open_db(None).unwrap();
+        let mut rw_txn = self.begin_rw_txn().unwrap();
+        for (key, expected) in txn.checks() {
+            if rw_txn.get(db, key).ok() != expected.as_deref() {
+                rw_txn.abort();
+                return false;
+            }
+        }
+        for (key, value) in txn.writes() {
+            match value {
+                Some(value) => rw_txn.put(db, key, value, WriteFlags::empty()).expect("lmdb put"),
+                None => { let _ = rw_txn.del(db, key, None); },
+            }
+        }
+        rw_txn.commit().unwrap();
+        true
+    }
 }
@@ -0,0 +1,142 @@
+//! Labeled key-value maps
+//!
+//! A map holds many small values under a single label, as one object. Like
+//! file contents, the size of keys and values is charged to the map's creator.
+use super::*;
+
+pub type Map = BTreeMap<String, Vec<u8>>;
+
+pub(super) fn map_size(map: &Map) -> u64 {
+    map.iter().map(|(k, v)| (k.len() + v.len()) as u64).sum()
+}
+
+impl ObjectRef<Labeled<Map>> {
+    /// Returns the value of `key`, if any
+    pub fn lookup<B: BackingStore>(&self, key: &str, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<Option<Vec<u8>>, FsError> {
+        Ok(self.get(fs)?.unlabel(ctx)?.get(key).cloned())
+    }
+
+    pub fn keys<B: BackingStore>(&self, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<Vec<String>, FsError> {
+        Ok(self.get(fs)?.unlabel(ctx)?.keys().cloned().collect())
+    }
+
+    /// Sets `key` to `value`. Does not taint the current label.
+    pub fn insert<B: BackingStore>(&self, key: String, value: Vec<u8>, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<(), FsError> {
+        let written = (key.len() + value.len()) as u64;
+        self.update(fs, ctx, false, written, |map| {
+            map.insert(key.clone(), value.clone());
+            true
+        }).map(|_| ())
+    }
+
+    /// Removes `key`, if it exists. Does not taint the current label.
+    pub fn remove<B: BackingStore>(&self, key: &str, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<(), FsError> {
+        self.update(fs, ctx, false, 0, |map| map.remove(key).is_some()).map(|_| ())
+    }
+
+    /// Sets `key` to `value`, or removes it if `value` is `None`, only if its
+    /// current value is `expected`, where `None` means absent. Returns whether
+    /// the swap happened, which reveals the current value, so the current
+    /// label is tainted with the map's label.
+    pub fn compare_and_swap<B: BackingStore>(
+        &self,
+        key: &str,
+        expected: Option<&[u8]>,
+        value: Option<Vec<u8>>,
+        fs: &FS<B>,
+        ctx: &mut SecurityContext,
+    ) -> Result<bool, FsError> {
+        self.update(fs, ctx, true, 0, |map| {
+            if map.get(key).map(Vec::as_slice) != expected {
+                return false;
+            }
+            match value.clone() {
+                Some(value) => map.insert(key.to_string(), value),
+                None => map.remove(key),
+            };
+            true
+        })
+    }
+
+    /// Applies `f` to the map and commits the result if `f` returns true,
+    /// retrying on conflicts. Returns what `f` returned.
+    ///
+    /// Changes in size are charged to the map's creator. Unless `taint`, the
+    /// `written` bytes must fit in its quota before the bytes they replace
+    /// are refunded, as the writer may not learn about those.
+    fn update<B, F>(&self, fs: &FS<B>, ctx: &mut SecurityContext, taint: bool, written: u64, mut f: F) -> Result<bool, FsError>
+    where
+        B: BackingStore,
+        F: FnMut(&mut Map) -> bool,
+    {
+        let key = self.uid.to_be_bytes();
+        loop {
+            let (prev, mut labeled) = self.get_encoded(fs)?;
+            let old_size = map_size(&labeled.data);
+            let changed = if taint {
+                labeled.modify(ctx, &mut f)?
+            } else {
+                labeled.modify_blind(ctx, &mut f)?
+            };
+            if !changed {
+                return Ok(false);
+            }
+            let mut txn = Transaction::default();
+            let charge = if taint {
+                Charge::file_bytes(old_size, map_size(&labeled.data))
+            } else {
+                Charge::blind_file_bytes(old_size, map_size(&labeled.data), written)
+            };
+            fs.stage_charge(&labeled.meta.creator, &charge, &mut txn)?;
+            txn.check(&key, Some(&prev));
+            txn.put(&key, encoding::encode(&labeled).as_slice());
+            if fs.0.commit(&txn) {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+
+    #[test]
+    fn test_map() {
+        let fs = FS::new(MemoryStore::new());
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        let DirEntry::Map(map) = fs.create_map(Buckle::parse("alice,T").unwrap(), &ctx).unwrap() else {
+            panic!("not a map")
+        };
+
+        map.insert("counter".into(), vec![1], &fs, &mut ctx).unwrap();
+        assert_eq!(ctx.label(), &Buckle::public());
+        assert!(!map.compare_and_swap("counter", Some(&[0]), Some(vec![2]), &fs, &mut ctx).unwrap());
+        assert!(map.compare_and_swap("counter", Some(&[1]), Some(vec![2]), &fs, &mut ctx).unwrap());
+        assert!(map.compare_and_swap("session", None, Some(vec![3]), &fs, &mut ctx).unwrap());
+        assert_eq!(ctx.label(), &Buckle::parse("alice,T").unwrap());
+        map.remove("session", &fs, &mut ctx).unwrap();
+
+        assert_eq!(map.lookup("counter", &fs, &mut ctx).unwrap(), Some(vec![2]));
+        assert_eq!(map.lookup("session", &fs, &mut ctx).unwrap(), None);
+        assert_eq!(map.keys(&fs, &mut ctx).unwrap(), vec!["counter".to_string()]);
+    }
+
+    #[test]
+    fn test_blind_insert_quota() {
+        let fs = FS::new(MemoryStore::new());
+        let alice = Buckle::parse("T,alice").unwrap().integrity;
+        fs.set_quota(&alice, Quota { objects: None, file_bytes: Some(10), blob_bytes: None });
+        let mut ctx = SecurityContext::new(alice);
+        let DirEntry::Map(map) = fs.create_map(Buckle::public(), &ctx).unwrap() else {
+            panic!("not a map")
+        };
+        map.insert("k".into(), vec![0; 8], &fs, &mut ctx).unwrap();
+        // whether the quota is exceeded does not depend on the replaced value
+        assert!(matches!(map.insert("k".into(), vec![1; 8], &fs, &mut ctx), Err(FsError::QuotaExceeded)));
+        assert!(map.compare_and_swap("k", Some(&[0; 8]), Some(vec![1; 8]), &fs, &mut ctx).unwrap());
+        map.remove("k", &fs, &mut ctx).unwrap();
+        map.insert("k".into(), vec![2; 8], &fs, &mut ctx).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+//! In-memory backing store for tests and local development. Nothing persists
+//! beyond the lifetime of the process.
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+/// A thread-safe in-memory `BackingStore`. Clones share the same underlying map.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore(Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>);
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl super::BackingStore for MemoryStore {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.read().unwrap().get(key).cloned()
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) {
+        self.0.write().unwrap().insert(Vec::from(key), Vec::from(value));
+    }
+
+    fn add(&self, key: &[u8], value: &[u8]) -> bool {
+        let mut map = self.0.write().unwrap();
+        if map.contains_key(key) {
+            false
+        } else {
+            map.insert(Vec::from(key), Vec::from(value));
+            true
+        }
+    }
+
+    fn cas(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<(), Option<Vec<u8>>> {
+        let mut map = self.0.write().unwrap();
+        let old = map.get(key);
+        if old.map(Vec::as_slice) == expected {
+            map.insert(Vec::from(key), Vec::from(value));
+            Ok(())
+        } else {
+            Err(old.cloned())
+        }
+    }
+
+    fn del(&self, key: &[u8]) {
+        self.0.write().unwrap().remove(key);
+    }
+
+    fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        self.0
+            .read()
+            .unwrap()
+            .range::<[u8], _>((Bound::Included(start), end))
+            .take(limit)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    fn commit(&self, txn: &super::Transaction) -> bool {
+        let mut map = self.0.write().unwrap();
+        if txn.checks().iter().any(|(key, expected)| map.get(key) != expected.as_ref()) {
+            return false;
+        }
+        for (key, value) in txn.writes() {
+            match value {
+                Some(value) => map.insert(key.clone(), value.clone()),
+                None => map.remove(key),
+            };
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::BackingStore;
+
+    #[test]
+    fn test_add_only_if_absent() {
+        let store = MemoryStore::new();
+        assert!(store.add(b"key", b"first"));
+        assert!(!store.add(b"key", b"second"));
+        assert_eq!(store.get(b"key"), Some(b"first".to_vec()));
+    }
+
+    #[test]
+    fn test_cas() {
+        let store = MemoryStore::new();
+        assert_eq!(store.cas(b"key", Some(b"old"), b"new"), Err(None));
+        assert_eq!(store.cas(b"key", None, b"old"), Ok(()));
+        assert_eq!(store.cas(b"key", None, b"new"), Err(Some(b"old".to_vec())));
+        assert_eq!(store.cas(b"key", Some(b"old"), b"new"), Ok(()));
+        assert_eq!(store.get(b"key"), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_commit_all_or_nothing() {
+        let store = MemoryStore::new();
+        store.put(b"a", b"1");
+
+        let mut txn = crate::fs::Transaction::default();
+        txn.check(b"a", Some(b"0"));
+        txn.put(b"b", b"2");
+        assert!(!store.commit(&txn));
+        assert_eq!(store.get(b"b"), None);
+
+        let mut txn = crate::fs::Transaction::default();
+        txn.check(b"a", Some(b"1"));
+        txn.check(b"b", None);
+        txn.put(b"b", b"2");
+        txn.del(b"a");
+        assert!(store.commit(&txn));
+        assert_eq!(store.get(b"a"), None);
+        assert_eq!(store.get(b"b"), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_clones_share_data() {
+        let store = MemoryStore::new();
+        store.clone().put(b"key", b"value");
+        assert_eq!(store.get(b"key"), Some(b"value".to_vec()));
+        store.del(b"key");
+        assert_eq!(store.clone().get(b"key"), None);
+    }
+
+    #[test]
+    fn test_scan() {
+        let store = MemoryStore::new();
+        for key in [&b"a"[..], b"ab", b"b", b"c"] {
+            store.put(key, key);
+        }
+        let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| pairs.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(keys(store.scan(b"a", Some(b"b"), 10)), vec![b"a".to_vec(), b"ab".to_vec()]);
+        assert_eq!(keys(store.scan(b"ab", None, 2)), vec![b"ab".to_vec(), b"b".to_vec()]);
+    }
+}
@@ -1,26 +1,48 @@
-use std::collections::BTreeMap;
-use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
 
 use labeled::{buckle::{Buckle, Component}, Label, HasPrivilege};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
+mod appendlog;
+mod archive;
+mod context;
+mod encoding;
 mod errors;
+mod file;
+mod fsck;
 mod function;
+mod gc;
+mod map;
+mod quota;
+mod subscription;
+mod walk;
 
 pub mod bootstrap;
+pub mod cache;
+pub mod dirstore;
+pub mod encrypted;
+pub mod instrument;
 pub mod lmdb;
+pub mod memory;
 pub mod path;
+pub mod shard;
 pub mod tikv;
 pub mod utils;
 
+pub use appendlog::*;
+pub use archive::*;
+pub use context::*;
 pub use errors::*;
+pub use file::*;
+pub use fsck::*;
 pub use function::*;
+pub use map::*;
+pub use quota::*;
+pub use subscription::*;
+pub use walk::*;
 
 use self::path::{Path, PathComponent};
 
-thread_local!(pub(crate) static CURRENT_LABEL: RefCell<Buckle> = RefCell::new(Buckle::public()));
-thread_local!(pub(crate) static PRIVILEGE: RefCell<Component> = RefCell::new(Component::dc_true()));
-
 pub const ROOT_REF: ObjectRef<Labeled<Directory>> = ObjectRef::new(0);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -70,18 +92,20 @@ impl<T> ObjectRef<T> {
     const fn new(uid: u64) -> Self {
         ObjectRef { uid, _inner: core::marker::PhantomData }
     }
+}
 
-    #[allow(dead_code)]
-    fn delete<B: BackingStore>(&self, storage: &B) {
-        storage.del(&self.uid.to_be_bytes())
+impl<T: DeserializeOwned + 'static> ObjectRef<T> {
+    /// Returns the object, or `FsError::DanglingReference` if it does not exist
+    /// and `FsError::CorruptedObject` if it cannot be decoded.
+    pub fn get<B: BackingStore>(&self, storage: &FS<B>) -> Result<T, FsError> {
+        self.get_encoded(storage).map(|(_, res)| res)
     }
-}
 
-impl<T: ?Sized + DeserializeOwned> ObjectRef<T> {
-    pub fn get<B: BackingStore>(&self, storage: &FS<B>) -> Option<T> {
-        let bs = storage.0.get(&self.uid.to_be_bytes())?;
-        let res = serde_json::from_slice(bs.as_slice()).ok()?;
-        res
+    /// Returns the object along with its stored encoding, as expected by `cas`
+    fn get_encoded<B: BackingStore>(&self, storage: &FS<B>) -> Result<(Vec<u8>, T), FsError> {
+        let bs = storage.0.get(&self.uid.to_be_bytes()).ok_or(FsError::DanglingReference(self.uid))?;
+        let res = encoding::decode(bs.as_slice()).ok_or(FsError::CorruptedObject(self.uid))?;
+        Ok((bs, res))
     }
 }
 
@@ -100,28 +124,31 @@ impl<T: Serialize> ObjectRef<T> {
         res
     }
 
+    /// Adds creating `value` under a fresh uid to `txn`. Committing `txn` fails
+    /// if the uid is taken by then.
+    fn stage_new(value: &T, txn: &mut Transaction) -> ObjectRef<T> {
+        let uid: u64 = rand::random();
+        txn.check(&uid.to_be_bytes(), None);
+        txn.put(&uid.to_be_bytes(), encoding::encode(value).as_slice());
+        ObjectRef::new(uid)
+    }
+
     fn set<B: BackingStore>(&self, value: &T, storage: &B) {
         storage.put(
             &self.uid.to_be_bytes(),
-            serde_json::to_vec(value).unwrap().as_slice(),
+            encoding::encode(value).as_slice(),
         );
     }
 }
 
-impl<T: ?Sized + Serialize + DeserializeOwned> ObjectRef<T> {
-    fn cas<B: BackingStore>(&self, expected: Option<&T>, value: &T, storage: &B) -> Result<(), Option<T>> {
-        let expected: Option<Vec<u8>> = expected.and_then(|e| serde_json::to_vec(e).ok());
-
-        let res = storage.cas(
+impl<T: Serialize + DeserializeOwned> ObjectRef<T> {
+    /// Replaces the object if it is still stored as `expected`, or returns false
+    fn cas<B: BackingStore>(&self, expected: Option<&[u8]>, value: &T, storage: &B) -> bool {
+        storage.cas(
             &self.uid.to_be_bytes(),
-            expected.as_ref().map(Vec::as_slice),
-            serde_json::to_vec(value).or(Err(None))?.as_slice(),
-        );
-        res.map_err(|e| {
-            e.and_then(|bs| {
-                serde_json::from_slice(bs.as_slice()).ok()
-            })
-        })
+            expected,
+            encoding::encode(value).as_slice(),
+        ).is_ok()
     }
 }
 
@@ -129,60 +156,95 @@ impl<T: ?Sized + Serialize + DeserializeOwned> ObjectRef<T> {
 pub struct Labeled<T> {
     label: Buckle,
     data: T,
+    #[serde(default)]
+    meta: Metadata,
+}
+
+/// Bookkeeping kept with each labeled object and protected by its label.
+/// Objects written before metadata was recorded have zero timestamps and a
+/// `dc_true` creator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Milliseconds since the UNIX epoch
+    pub created: u64,
+    /// Milliseconds since the UNIX epoch
+    pub modified: u64,
+    /// Privilege of the context that created the object
+    pub creator: Component,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata { created: 0, modified: 0, creator: Component::dc_true() }
+    }
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
 }
 
 impl<T> Labeled<T> {
+    /// Returns a new object created now by the context's privilege
+    pub fn new(label: Buckle, data: T, ctx: &SecurityContext) -> Self {
+        let now = now_millis();
+        let creator = ctx.privilege().clone();
+        Labeled { label, data, meta: Metadata { created: now, modified: now, creator } }
+    }
+
     pub fn label(&self) -> &Buckle {
         &self.label
     }
 
-    pub fn unlabel(&self) -> &T {
-        CURRENT_LABEL.with(|current_label| {
-            let new_label = {
-                self.label.clone().lub(current_label.borrow().clone())
-            };
-            *current_label.borrow_mut() = new_label;
-            &self.data
-        })
+    /// Returns the object's metadata, tainting the current label like `unlabel`
+    pub fn metadata(&self, ctx: &mut SecurityContext) -> Result<&Metadata, FsError> {
+        ctx.taint(&self.label)?;
+        Ok(&self.meta)
     }
 
-    pub fn write(&mut self, value: T) -> Result<(), errors::LabelError> {
-        CURRENT_LABEL.with(|current_label| {
-            PRIVILEGE.with(|privilege| {
-                if current_label.borrow().can_flow_to_with_privilege(&self.label, &privilege.borrow()) {
-                    self.data = value;
-                    Ok(())
-                } else {
-                    Err(errors::LabelError::CannotWrite)
-                }
-            })
-        })
+    /// Returns the data, tainting the current label with the object's label,
+    /// or `FsError::ClearanceError` if that would exceed the clearance
+    pub fn unlabel(&self, ctx: &mut SecurityContext) -> Result<&T, FsError> {
+        ctx.taint(&self.label)?;
+        Ok(&self.data)
     }
 
-    fn modify<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> Result<R, errors::LabelError> {
-        CURRENT_LABEL.with(|current_label| {
-            let new_label = {
-                self.label.clone().lub(current_label.borrow().clone())
-            };
-            *current_label.borrow_mut() = new_label;
-            PRIVILEGE.with(|privilege| {
-                if current_label.borrow().can_flow_to_with_privilege(&self.label, &privilege.borrow()) {
-                    Ok(f(&mut self.data))
-                } else {
-                    Err(errors::LabelError::CannotWrite)
-                }
-            })
-        })
+    pub fn write(&mut self, value: T, ctx: &SecurityContext) -> Result<(), errors::LabelError> {
+        if ctx.can_write(&self.label) {
+            self.data = value;
+            self.meta.modified = now_millis();
+            Ok(())
+        } else {
+            Err(errors::LabelError::CannotWrite)
+        }
+    }
+
+    fn modify<R, F: FnOnce(&mut T) -> R>(&mut self, ctx: &mut SecurityContext, f: F) -> Result<R, FsError> {
+        ctx.taint(&self.label)?;
+        if ctx.can_write(&self.label) {
+            self.meta.modified = now_millis();
+            Ok(f(&mut self.data))
+        } else {
+            Err(FsError::LabelError(errors::LabelError::CannotWrite))
+        }
+    }
+
+    /// Like `modify`, but without tainting the current label. Only for updates
+    /// that reveal nothing about the old value.
+    fn modify_blind<R, F: FnOnce(&mut T) -> R>(&mut self, ctx: &SecurityContext, f: F) -> Result<R, errors::LabelError> {
+        if ctx.can_write(&self.label) {
+            self.meta.modified = now_millis();
+            Ok(f(&mut self.data))
+        } else {
+            Err(errors::LabelError::CannotWrite)
+        }
     }
 }
 
 impl<T: Default + Serialize> ObjectRef<Labeled<T>> {
-    pub fn create<B: BackingStore>(label: Buckle, storage: &B) -> Self {
-        let labeled = Labeled {
-            label,
-            data: T::default(),
-        };
-        ObjectRef::set_new_id(&labeled, storage)
+    pub fn create<B: BackingStore>(label: Buckle, storage: &B, ctx: &SecurityContext) -> Self {
+        ObjectRef::set_new_id(&Labeled::new(label, T::default(), ctx), storage)
     }
 }
 
@@ -192,105 +254,198 @@ pub struct Directory {
 }
 
 impl ObjectRef<Labeled<Directory>> {
-    pub fn list<B: BackingStore>(&self, fs: &FS<B>) -> BTreeMap<String, DirEntry> {
-        self.get(fs).unwrap().unlabel().entries.clone()
+    pub fn list<B: BackingStore>(&self, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<BTreeMap<String, DirEntry>, FsError> {
+        Ok(self.get(fs)?.unlabel(ctx)?.entries.clone())
     }
 
-    pub fn link<B: BackingStore>(&self, name: String, entry: DirEntry, fs: &FS<B>) -> Result<bool, errors::LabelError> {
-        let mut prev_dir = self.get(fs).unwrap();
+    pub fn link<B: BackingStore>(&self, name: String, entry: DirEntry, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<bool, FsError> {
         loop {
-            let mut labeled_dir = prev_dir.clone();
-            let existed = labeled_dir.modify(|dir| {
+            let (prev_encoded, mut labeled_dir) = self.get_encoded(fs)?;
+            let existed = labeled_dir.modify(ctx, |dir| {
                 dir.entries.insert(name.clone(), entry.clone()).is_some()
             })?;
             if existed {
                 return Ok(false);
             }
-            if let Err(Some(p)) = self.cas(Some(&prev_dir), &labeled_dir, &fs.0) {
-                prev_dir = p;
-            } else {
+            if self.cas(Some(&prev_encoded), &labeled_dir, &fs.0) {
+                fs.notify(self.uid, Event::Link { name }, ctx);
                 return Ok(true)
             }
         }
     }
 
-    pub fn unlink<B: BackingStore>(&self, name: &String, fs: &FS<B>) -> Result<bool, errors::LabelError> {
-        let mut prev_dir = self.get(fs).unwrap();
+    /// Links the entry returned by `stage` as `name` in the same transaction
+    /// that commits the objects `stage` adds to it. `stage` is called again
+    /// each time the transaction is retried.
+    ///
+    /// If `name` exists, the existing entry is replaced and returned when
+    /// `replace` is true, otherwise linking fails with `FsError::NameExists`.
+    fn link_staged<B: BackingStore, F: FnMut(&mut Transaction, &SecurityContext) -> Result<DirEntry, FsError>>(
+        &self,
+        name: String,
+        replace: bool,
+        mut stage: F,
+        fs: &FS<B>,
+        ctx: &mut SecurityContext,
+    ) -> Result<(DirEntry, Option<DirEntry>), FsError> {
+        let key = self.uid.to_be_bytes();
+        loop {
+            let (prev_dir, mut labeled_dir) = self.get_encoded(fs)?;
+            // check the directory before staging, so staging errors, e.g.,
+            // `FsError::QuotaExceeded`, reveal nothing to those who may not
+            // write it
+            let replaced = labeled_dir.modify(ctx, |dir| {
+                if replace || !dir.entries.contains_key(&name) {
+                    Ok(dir.entries.remove(&name))
+                } else {
+                    Err(FsError::NameExists)
+                }
+            })??;
+            let mut txn = Transaction::default();
+            let entry = stage(&mut txn, ctx)?;
+            labeled_dir.data.entries.insert(name.clone(), entry.clone());
+            txn.check(&key, Some(&prev_dir));
+            txn.put(&key, encoding::encode(&labeled_dir).as_slice());
+            if fs.0.commit(&txn) {
+                fs.notify(self.uid, Event::Link { name }, ctx);
+                return Ok((entry, replaced));
+            }
+        }
+    }
+
+    /// Moves the entry `name` to `new_name` in `dest`, which may be this
+    /// directory, in a single transaction. Fails with `FsError::NameExists` if
+    /// `new_name` exists in `dest`, unless it is `name` in this directory, and
+    /// with `LinkError::Cycle` if `dest` is within the moved directory, which
+    /// would become unreachable.
+    ///
+    /// The current label is tainted with the labels of both directories before
+    /// either is modified, so both must be writable at the combined label.
+    /// The moved directory is searched for `dest` without tainting, like the
+    /// garbage collector reads, so whether a move fails reveals whether `dest`
+    /// is within it.
+    pub fn rename<B: BackingStore>(
+        &self,
+        name: &String,
+        dest: &ObjectRef<Labeled<Directory>>,
+        new_name: String,
+        fs: &FS<B>,
+        ctx: &mut SecurityContext,
+    ) -> Result<(), FsError> {
+        let src_key = self.uid.to_be_bytes();
+        let dest_key = dest.uid.to_be_bytes();
+        loop {
+            let (prev_src, mut src_dir) = self.get_encoded(fs)?;
+            let mut txn = Transaction::default();
+            txn.check(&src_key, Some(&prev_src));
+            if self.uid == dest.uid {
+                let renamed = src_dir.modify(ctx, |dir| {
+                    if !dir.entries.contains_key(name) {
+                        return Err(FsError::UnlinkError(UnlinkError::DoesNotExists));
+                    }
+                    if name == &new_name {
+                        return Ok(false);
+                    }
+                    if dir.entries.contains_key(&new_name) {
+                        return Err(FsError::NameExists);
+                    }
+                    let entry = dir.entries.remove(name).ok_or(FsError::UnlinkError(UnlinkError::DoesNotExists))?;
+                    dir.entries.insert(new_name.clone(), entry);
+                    Ok(true)
+                })??;
+                if !renamed {
+                    return Ok(());
+                }
+            } else {
+                let (prev_dest, mut dest_dir) = dest.get_encoded(fs)?;
+                src_dir.unlabel(ctx)?;
+                dest_dir.unlabel(ctx)?;
+                let entry = src_dir.modify(ctx, |dir| dir.entries.remove(name))?
+                    .ok_or(FsError::UnlinkError(UnlinkError::DoesNotExists))?;
+                if contains_directory(&entry, dest.uid, fs)? {
+                    return Err(FsError::LinkError(LinkError::Cycle));
+                }
+                dest_dir.modify(ctx, |dir| {
+                    if dir.entries.contains_key(&new_name) {
+                        Err(FsError::NameExists)
+                    } else {
+                        dir.entries.insert(new_name.clone(), entry);
+                        Ok(())
+                    }
+                })??;
+                txn.check(&dest_key, Some(&prev_dest));
+                txn.put(&dest_key, encoding::encode(&dest_dir).as_slice());
+            }
+            txn.put(&src_key, encoding::encode(&src_dir).as_slice());
+            if fs.0.commit(&txn) {
+                fs.notify(self.uid, Event::Unlink { name: name.clone() }, ctx);
+                fs.notify(dest.uid, Event::Link { name: new_name }, ctx);
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn unlink<B: BackingStore>(&self, name: &String, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<bool, FsError> {
         loop {
-            let mut labeled_dir = prev_dir.clone();
-            let existed = labeled_dir.modify(|dir| {
+            let (prev_encoded, mut labeled_dir) = self.get_encoded(fs)?;
+            let existed = labeled_dir.modify(ctx, |dir| {
                 dir.entries.remove(name).is_some()
             })?;
             if !existed {
                 return Ok(false);
             }
-            if let Err(Some(p)) = self.cas(Some(&prev_dir), &labeled_dir, &fs.0) {
-                prev_dir = p;
-            } else {
+            if self.cas(Some(&prev_encoded), &labeled_dir, &fs.0) {
+                fs.notify(self.uid, Event::Unlink { name: name.clone() }, ctx);
                 return Ok(true)
             }
         }
     }
 }
 
-type File = Vec<u8>;
-
-impl ObjectRef<Labeled<File>> {
-    pub fn read<B: BackingStore>(&self, fs: &FS<B>) -> File {
-        self.get(fs).unwrap().unlabel().clone()
-    }
-
-    pub fn write<B: BackingStore>(&self, data: Vec<u8>, fs: &FS<B>) -> Result<(), errors::LabelError> {
-        let mut file = self.get(fs).unwrap();
-        file.write(data)?;
-        self.set(&file, &fs.0);
-        Ok(())
-    }
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct FacetedDirectory {
     facets: Vec<(Buckle, ObjectRef<Labeled<Directory>>)>
 }
 
 impl ObjectRef<FacetedDirectory> {
-    pub fn open<B: BackingStore>(&self, facet: &Buckle, fs: &FS<B>) -> ObjectRef<Labeled<Directory>> {
-        let mut mfaceted_dir = self.get(fs);
+    pub fn open<B: BackingStore>(&self, facet: &Buckle, fs: &FS<B>, ctx: &SecurityContext) -> Result<ObjectRef<Labeled<Directory>>, FsError> {
         loop {
-            if let Some(faceted_dir) = mfaceted_dir.as_ref() {
+            // a missing faceted directory has no facets yet
+            let mfaceted_dir = match self.get_encoded(fs) {
+                Ok(faceted_dir) => Some(faceted_dir),
+                Err(FsError::DanglingReference(_)) => None,
+                Err(e) => return Err(e),
+            };
+            if let Some((_, faceted_dir)) = mfaceted_dir.as_ref() {
                 if let Some(res) = faceted_dir.facets.iter().find_map(|(f, value)| if f.eq(facet) { Some(value) } else { None }) {
-                    return *res;
+                    return Ok(*res);
                 }
             }
-            let new_dir = ObjectRef::set_new_id(&Labeled {
-                label: facet.clone(),
-                data: Directory::default(),
-            }, &fs.0);
+            let mut txn = Transaction::default();
+            let new_dir = ObjectRef::stage_new(&Labeled::new(facet.clone(), Directory::default(), ctx), &mut txn);
+            fs.stage_object_charge(ctx.privilege(), new_dir.uid, Charged::Object, 0, &mut txn)?;
 
-            let mut new_faceted_dir = mfaceted_dir.clone().unwrap_or_default();
+            let mut new_faceted_dir = mfaceted_dir.as_ref().map(|(_, d)| d.clone()).unwrap_or_default();
             new_faceted_dir.facets.push((facet.clone(), new_dir));
 
-            match self.cas(mfaceted_dir.as_ref(), &new_faceted_dir, &fs.0) {
-                Ok(()) => return new_dir,
-                Err(d) => mfaceted_dir = d.clone(),
+            let key = self.uid.to_be_bytes();
+            txn.check(&key, mfaceted_dir.as_ref().map(|(e, _)| e.as_slice()));
+            txn.put(&key, encoding::encode(&new_faceted_dir).as_slice());
+            if fs.0.commit(&txn) {
+                return Ok(new_dir);
             }
         }
     }
 
-    pub fn list<B: BackingStore>(&self, fs: &FS<B>, clearance: &Buckle) -> BTreeMap<Buckle, ObjectRef<Labeled<Directory>>> {
-        CURRENT_LABEL.with(|current_label| {
-            let cl = {
-                current_label.borrow().clone().lub(clearance.clone())
-            };
-            *current_label.borrow_mut() = cl;
-        });
-        self.get(fs).unwrap().facets.iter().filter_map(|(label, entry)| {
+    pub fn list<B: BackingStore>(&self, fs: &FS<B>, clearance: &Buckle, ctx: &mut SecurityContext) -> Result<BTreeMap<Buckle, ObjectRef<Labeled<Directory>>>, FsError> {
+        ctx.taint(clearance)?;
+        Ok(self.get(fs)?.facets.iter().filter_map(|(label, entry)| {
             if label.can_flow_to(clearance) {
                 Some((label.clone(), *entry))
             } else {
                 None
             }
-        }).collect()
+        }).collect())
     }
 }
 
@@ -305,21 +460,16 @@ pub struct Service {
 }
 
 impl ObjectRef<Labeled<Service>> {
-    pub fn to_invokable<B: BackingStore>(&self, fs: &FS<B>) -> Service {
-        self.get(fs).unwrap().unlabel().clone()
+    pub fn to_invokable<B: BackingStore>(&self, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<Service, FsError> {
+        Ok(self.get(fs)?.unlabel(ctx)?.clone())
     }
 
-    pub fn replace<B: BackingStore>(&self, new_service: Service, fs: &FS<B>) -> Result<(), FsError> {
-        PRIVILEGE.with(|privilege| {
-            let privilege = privilege.borrow();
-            if !privilege.implies(&new_service.privilege) {
-                Err(FsError::PrivilegeError(PrivilegeError::CannotDelegate))
-            } else {
-                Ok(())
-            }
-        })?;
-        let mut service = self.get(fs).unwrap();
-        service.write(new_service)?;
+    pub fn replace<B: BackingStore>(&self, new_service: Service, fs: &FS<B>, ctx: &SecurityContext) -> Result<(), FsError> {
+        if !ctx.privilege().implies(&new_service.privilege) {
+            return Err(FsError::PrivilegeError(PrivilegeError::CannotDelegate));
+        }
+        let mut service = self.get(fs)?;
+        service.write(new_service, ctx)?;
         Ok(self.set(&service, &fs.0))
     }
 }
@@ -343,8 +493,8 @@ impl ObjectRef<Labeled<Gate>> {
     ///
     /// At each level, both privilege and `invokable_integrity_clearance` are
     /// accumulated.
-    pub fn to_invokable<B: BackingStore>(&self, fs: &FS<B>) -> DirectGate {
-        let mut cur = self.get(fs).unwrap().unlabel().clone();
+    pub fn to_invokable<B: BackingStore>(&self, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<DirectGate, FsError> {
+        let mut cur = self.get(fs)?.unlabel(ctx)?.clone();
         let mut privilege = Component::dc_true();
         let mut declassify = Component::dc_true();
         let mut invoker_integrity_clearance = Component::dc_true();
@@ -353,40 +503,33 @@ impl ObjectRef<Labeled<Gate>> {
                 Gate::Direct(gate) => {
                     privilege = privilege & gate.privilege;
                     invoker_integrity_clearance = invoker_integrity_clearance & gate.invoker_integrity_clearance;
-                    return DirectGate {
+                    return Ok(DirectGate {
                         privilege,
                         invoker_integrity_clearance,
                         declassify,
                         function: gate.function,
-                    }
+                    })
                 },
                 Gate::Redirect(redirect_gate) => {
                     privilege = privilege & redirect_gate.privilege;
                     invoker_integrity_clearance = invoker_integrity_clearance & redirect_gate.invoker_integrity_clearance;
                     declassify = declassify & redirect_gate.declassify;
-                    cur = redirect_gate.gate.get(fs).unwrap().unlabel().clone();
+                    cur = redirect_gate.gate.get(fs)?.unlabel(ctx)?.clone();
                 }
             }
         }
     }
 
-    pub fn replace<B: BackingStore>(&self, new_gate: Gate, fs: &FS<B>) -> Result<(), FsError> {
-        {
-            let new_priv = match &new_gate {
-                Gate::Direct(d) => &d.privilege,
-                Gate::Redirect(r) => &r.privilege,
-            };
-            PRIVILEGE.with(|privilege| {
-                let privilege = privilege.borrow();
-                if !privilege.implies(new_priv) {
-                    Err(FsError::PrivilegeError(PrivilegeError::CannotDelegate))
-                } else {
-                    Ok(())
-                }
-            })?;
+    pub fn replace<B: BackingStore>(&self, new_gate: Gate, fs: &FS<B>, ctx: &SecurityContext) -> Result<(), FsError> {
+        let new_priv = match &new_gate {
+            Gate::Direct(d) => &d.privilege,
+            Gate::Redirect(r) => &r.privilege,
+        };
+        if !ctx.privilege().implies(new_priv) {
+            return Err(FsError::PrivilegeError(PrivilegeError::CannotDelegate));
         }
-        let mut gate = self.get(fs).unwrap();
-        gate.write(new_gate)?;
+        let mut gate = self.get(fs)?;
+        gate.write(new_gate, ctx)?;
         Ok(self.set(&gate, &fs.0))
     }
 }
@@ -400,21 +543,21 @@ pub struct DirectGate {
 }
 
 impl ObjectRef<Labeled<DirectGate>> {
-    pub fn to_invokable<B: BackingStore>(&self, fs: &FS<B>) -> DirectGate {
-        self.get(fs).unwrap().unlabel().clone()
+    pub fn to_invokable<B: BackingStore>(&self, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<DirectGate, FsError> {
+        Ok(self.get(fs)?.unlabel(ctx)?.clone())
     }
 }
 
 pub type Blob = String;
 
 impl ObjectRef<Labeled<Blob>> {
-    pub fn read<B: BackingStore>(&self, fs: &FS<B>) -> Blob {
-        self.get(fs).unwrap().unlabel().clone()
+    pub fn read<B: BackingStore>(&self, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<Blob, FsError> {
+        Ok(self.get(fs)?.unlabel(ctx)?.clone())
     }
 
-    pub fn replace<B: BackingStore>(&self, new_blob: Blob, fs: &FS<B>) -> Result<(), LabelError> {
-        let mut blob = self.get(fs).unwrap();
-        blob.write(new_blob)?;
+    pub fn replace<B: BackingStore>(&self, new_blob: Blob, fs: &FS<B>, ctx: &SecurityContext) -> Result<(), FsError> {
+        let mut blob = self.get(fs)?;
+        blob.write(new_blob, ctx)?;
         Ok(self.set(&blob, &fs.0))
     }
 }
@@ -428,6 +571,125 @@ pub enum DirEntry {
     Gate(ObjectRef<Labeled<Gate>>) = 3,
     Service(ObjectRef<Labeled<Service>>) = 4,
     Blob(ObjectRef<Labeled<Blob>>) = 5,
+    Map(ObjectRef<Labeled<Map>>) = 6,
+    Log(ObjectRef<Labeled<Log>>) = 7,
+}
+
+/// What `DirEntry::stat` returns about an object
+#[derive(Debug, Clone)]
+pub struct Stat {
+    /// The length of a file, the number of entries of a directory or map, or
+    /// the number of records of a log that have not been trimmed.
+    /// Blob sizes are only known to the blobstore.
+    pub size: Option<u64>,
+    /// Faceted directories are unlabeled and have no metadata
+    pub metadata: Option<Metadata>,
+}
+
+impl DirEntry {
+    /// Returns the uid of the object the entry refers to
+    pub fn uid(&self) -> u64 {
+        match self {
+            DirEntry::Directory(obj) => obj.uid,
+            DirEntry::File(obj) => obj.uid,
+            DirEntry::FacetedDirectory(obj) => obj.uid,
+            DirEntry::Gate(obj) => obj.uid,
+            DirEntry::Service(obj) => obj.uid,
+            DirEntry::Blob(obj) => obj.uid,
+            DirEntry::Map(obj) => obj.uid,
+            DirEntry::Log(obj) => obj.uid,
+        }
+    }
+
+    /// Returns the size and metadata of the object, tainting the current
+    /// label with the object's label just like reading it would.
+    pub fn stat<B: BackingStore>(&self, fs: &FS<B>, ctx: &mut SecurityContext) -> Result<Stat, FsError> {
+        fn labeled_stat<T>(labeled: Labeled<T>, size: Option<u64>, ctx: &mut SecurityContext) -> Result<Stat, FsError> {
+            Ok(Stat { size, metadata: Some(labeled.metadata(ctx)?.clone()) })
+        }
+        Ok(match self {
+            DirEntry::Directory(obj) => {
+                let dir = obj.get(fs)?;
+                let size = dir.unlabel(ctx)?.entries.len() as u64;
+                labeled_stat(dir, Some(size), ctx)?
+            },
+            DirEntry::File(obj) => {
+                let file = obj.get(fs)?;
+                let size = file.unlabel(ctx)?.len();
+                labeled_stat(file, Some(size), ctx)?
+            },
+            DirEntry::FacetedDirectory(_) => Stat { size: None, metadata: None },
+            DirEntry::Gate(obj) => labeled_stat(obj.get(fs)?, None, ctx)?,
+            DirEntry::Service(obj) => labeled_stat(obj.get(fs)?, None, ctx)?,
+            DirEntry::Blob(obj) => labeled_stat(obj.get(fs)?, None, ctx)?,
+            DirEntry::Map(obj) => {
+                let map = obj.get(fs)?;
+                let size = map.unlabel(ctx)?.len() as u64;
+                labeled_stat(map, Some(size), ctx)?
+            },
+            DirEntry::Log(obj) => {
+                let log = obj.get(fs)?;
+                let size = log.unlabel(ctx)?.len();
+                labeled_stat(log, Some(size), ctx)?
+            },
+        })
+    }
+}
+
+/// An object to be created and linked in a single transaction by
+/// `FS::create_and_link` or `FS::replace_entry`
+#[derive(Debug, Clone)]
+pub enum NewEntry {
+    Directory(Buckle),
+    File(Buckle, Vec<u8>),
+    FacetedDirectory,
+    Blob(Buckle, Blob),
+    DirectGate(Buckle, DirectGate),
+    RedirectGate(Buckle, RedirectGate),
+    Service(Buckle, Service),
+    Map(Buckle),
+    Log(Buckle),
+}
+
+impl NewEntry {
+    /// Returns what creating the entry is charged for and its file bytes
+    fn charged(&self) -> (Charged, u64) {
+        match self {
+            NewEntry::File(_, data) => (Charged::File, data.len() as u64),
+            NewEntry::Map(_) => (Charged::Map, 0),
+            NewEntry::Log(_) => (Charged::Log, 0),
+            _ => (Charged::Object, 0),
+        }
+    }
+
+    fn stage(&self, txn: &mut Transaction, ctx: &SecurityContext) -> DirEntry {
+        match self {
+            NewEntry::Directory(label) => DirEntry::Directory(ObjectRef::stage_new(&Labeled::new(label.clone(), Directory::default(), ctx), txn)),
+            NewEntry::File(label, data) => {
+                let file = File::staged(data, txn);
+                DirEntry::File(ObjectRef::stage_new(&Labeled::new(label.clone(), file, ctx), txn))
+            },
+            NewEntry::FacetedDirectory => DirEntry::FacetedDirectory(ObjectRef::stage_new(&FacetedDirectory::default(), txn)),
+            NewEntry::Blob(label, blob) => DirEntry::Blob(ObjectRef::stage_new(&Labeled::new(label.clone(), blob.clone(), ctx), txn)),
+            NewEntry::DirectGate(label, gate) => DirEntry::Gate(ObjectRef::stage_new(&Labeled::new(label.clone(), Gate::Direct(gate.clone()), ctx), txn)),
+            NewEntry::RedirectGate(label, gate) => DirEntry::Gate(ObjectRef::stage_new(&Labeled::new(label.clone(), Gate::Redirect(gate.clone()), ctx), txn)),
+            NewEntry::Service(label, service) => DirEntry::Service(ObjectRef::stage_new(&Labeled::new(label.clone(), service.clone(), ctx), txn)),
+            NewEntry::Map(label) => DirEntry::Map(ObjectRef::stage_new(&Labeled::new(label.clone(), Map::default(), ctx), txn)),
+            NewEntry::Log(label) => DirEntry::Log(ObjectRef::stage_new(&Labeled::new(label.clone(), Log::default(), ctx), txn)),
+        }
+    }
+}
+
+/// Checks that the current label can flow to `label`, the label of an object
+/// being created, and that the privilege implies each of `delegated`
+fn check_create(label: &Buckle, delegated: &[&Component], ctx: &SecurityContext) -> Result<(), FsError> {
+    if !ctx.can_write(label) {
+        Err(FsError::LabelError(LabelError::CannotWrite))
+    } else if !delegated.iter().all(|d| ctx.privilege().implies(d)) {
+        Err(FsError::PrivilegeError(PrivilegeError::CannotDelegate))
+    } else {
+        Ok(())
+    }
 }
 
 // FS definition
@@ -444,97 +706,124 @@ impl<S> FS<S> {
 impl<S: BackingStore> FS<S> {
     /// true, the root is newly created; false, the root already exists
     pub fn initialize(&self) -> bool {
-        let root = Labeled {
-            label: Buckle::new(true, false),
-            data: Directory {
-                entries: Default::default(),
-            }
-        };
+        let root = Labeled::new(Buckle::new(true, false), Directory::default(), &SecurityContext::default());
         self.0
-            .add(&ROOT_REF.uid.to_be_bytes(), &serde_json::ser::to_vec(&root).unwrap())
+            .add(&ROOT_REF.uid.to_be_bytes(), &encoding::encode(&root))
     }
 
     pub fn root(&self) -> Labeled<Directory> {
-        ROOT_REF.get(self).unwrap_or(Labeled {
+        ROOT_REF.get(self).unwrap_or_else(|_| Labeled {
             label: Buckle::new(true, false),
-            data: Directory {
-                entries: Default::default(),
-            }
+            data: Directory::default(),
+            meta: Metadata::default(),
         })
     }
 
     /// Returns the directory entry at a path or an error if the path doesn't exist.
     ///
-    /// The thread's current label is tainted for each path component, meaning path
-    /// traversal never fails when the path exists, but may increase the current
-    /// label arbitrarily high.
+    /// The context's current label is tainted for each path component, meaning
+    /// path traversal never fails when the path exists, but may increase the
+    /// current label up to the context's clearance. Traversal fails with
+    /// `FsError::ClearanceError` rather than exceed it.
     pub fn read_path<P: Into<Path>>(
         &self,
         path: P,
+        ctx: &mut SecurityContext,
     ) -> Result<DirEntry, FsError> {
         let mut path: Path = path.into();
-
-        let mut cur_entry;
-        if let Some(PathComponent::Dscrp(comp)) = path.pop_front() {
-            cur_entry = self.root().unlabel().entries.get(&comp).cloned();
-        } else {
-            return Ok(DirEntry::Directory(ROOT_REF));
+        match path.pop_front() {
+            Some(PathComponent::Dscrp(comp)) => {
+                let entry = self.root().unlabel(ctx)?.entries.get(&comp).cloned().ok_or(FsError::BadPath)?;
+                self.resolve_path(entry, path, ctx)
+            },
+            _ => Ok(DirEntry::Directory(ROOT_REF)),
         }
+    }
 
-        while let Some(comp) = path.pop_front() {
-            match (cur_entry, comp) {
-                (Some(DirEntry::Directory(ref dir_obj)), PathComponent::Dscrp(ref dscrp)) => {
-                    cur_entry = dir_obj.list(self).get(dscrp).cloned();
+    /// Returns the directory entry at `path` relative to `base`, tainting the
+    /// current label for each path component like `read_path`.
+    pub fn resolve_path<P: Into<Path>>(&self, base: DirEntry, path: P, ctx: &mut SecurityContext) -> Result<DirEntry, FsError> {
+        let mut cur_entry = base;
+        for comp in path.into() {
+            cur_entry = match (cur_entry, comp) {
+                (DirEntry::Directory(dir_obj), PathComponent::Dscrp(dscrp)) => {
+                    dir_obj.list(self, ctx)?.remove(&dscrp).ok_or(FsError::BadPath)?
                 },
-                (Some(DirEntry::FacetedDirectory(ref facet_obj)), PathComponent::Facet(ref facet)) => {
-                    cur_entry = Some(DirEntry::Directory(facet_obj.open(facet, self)));
+                (DirEntry::FacetedDirectory(facet_obj), PathComponent::Facet(facet)) => {
+                    DirEntry::Directory(facet_obj.open(&facet, self, ctx)?)
                 },
                 _ => return Err(FsError::BadPath),
-            }
+            };
         }
-        cur_entry.ok_or(FsError::BadPath)
+        Ok(cur_entry)
     }
 
     /// Lists the contents of a directory
     ///
-    /// The thread's current label is tainted for each path component, meaning path
-    /// traversal never fails when the path exists, but may increase the current
-    /// label arbitrarily high.
+    /// The context's current label is tainted for each path component, meaning
+    /// path traversal never fails when the path exists, but may increase the
+    /// current label up to the context's clearance. Traversal fails with
+    /// `FsError::ClearanceError` rather than exceed it.
     pub fn list_dir<P: Into<Path>>(
         &self,
         path: P,
+        ctx: &mut SecurityContext,
     ) -> Result<BTreeMap<String, DirEntry>, FsError> {
-        match self.read_path(path)? {
-            DirEntry::Directory(dir_obj) => {
-                Ok(dir_obj.list(self))
-            },
+        match self.read_path(path, ctx)? {
+            DirEntry::Directory(dir_obj) => dir_obj.list(self, ctx),
             _ => Err(FsError::NotADir)
         }
     }
 
     /// Unlinks `name` from the directory at `dir`, or returns an error if the
-    /// directory doesn't exist or the thread's current label and privilege are
-    /// not sufficient to write to the directory.
+    /// directory doesn't exist or the context's current label and privilege
+    /// are not sufficient to write to the directory.
     ///
-    /// The thread's current label is tainted for each path component, meaning path
-    /// traversal never fails when the path exists, but may increase the current
-    /// label arbitrarily high.
-    pub fn rm<P: Into<Path>>(&self, dir: P, name: &String) -> Result<bool, FsError> {
-        match self.read_path(dir)? {
+    /// The context's current label is tainted for each path component, meaning
+    /// path traversal never fails when the path exists, but may increase the
+    /// current label up to the context's clearance. Traversal fails with
+    /// `FsError::ClearanceError` rather than exceed it.
+    pub fn rm<P: Into<Path>>(&self, dir: P, name: &String, ctx: &mut SecurityContext) -> Result<bool, FsError> {
+        match self.read_path(dir, ctx)? {
             DirEntry::Directory(dir_obj) => {
-                dir_obj.unlink(name, self).map_err(Into::into)
+                dir_obj.unlink(name, self, ctx)
             },
             _ => Err(FsError::NotADir)
         }
     }
 
+    /// Moves the entry `name` in the directory at `src_dir` to `new_name` in the
+    /// directory at `dest_dir` atomically, or returns an error if either path
+    /// is not a directory, `name` does not exist, `new_name` exists, or the
+    /// context's current label and privilege are not sufficient to write to
+    /// both directories.
+    ///
+    /// The context's current label is tainted for each path component, meaning
+    /// path traversal never fails when the path exists, but may increase the
+    /// current label up to the context's clearance. Traversal fails with
+    /// `FsError::ClearanceError` rather than exceed it.
+    pub fn rename<P: Into<Path>, Q: Into<Path>>(
+        &self,
+        src_dir: P,
+        name: &String,
+        dest_dir: Q,
+        new_name: String,
+        ctx: &mut SecurityContext,
+    ) -> Result<(), FsError> {
+        match (self.read_path(src_dir, ctx)?, self.read_path(dest_dir, ctx)?) {
+            (DirEntry::Directory(src_obj), DirEntry::Directory(dest_obj)) => {
+                src_obj.rename(name, &dest_obj, new_name, self, ctx)
+            },
+            _ => Err(FsError::NotADir)
+        }
+    }
 
     /// Lists the contents of a faceted directory up to a clearance label
     ///
-    /// The thread's current label is tainted for each path component,
+    /// The context's current label is tainted for each path component,
     /// diregarding the provided clearance, meaning path traversal never fails
-    /// when the path exists, but may increase the current label arbitrarily
-    /// high. The results, though, contain only initialized facets that are
+    /// when the path exists, but may increase the current label up to the
+    /// context's clearance. The results, though, contain only initialized facets that are
     /// readable up to the provided clearance. If listing the facet is
     /// successful, the current label is always raised to the provided
     /// clearance.
@@ -542,143 +831,138 @@ impl<S: BackingStore> FS<S> {
         &self,
         path: P,
         clearance: &Buckle,
+        ctx: &mut SecurityContext,
     ) -> Result<BTreeMap<Buckle, ObjectRef<Labeled<Directory>>>, FsError> {
-        match self.read_path(path)? {
-            DirEntry::FacetedDirectory(dir_obj) => {
-                Ok(dir_obj.list(self, clearance))
-            },
+        match self.read_path(path, ctx)? {
+            DirEntry::FacetedDirectory(dir_obj) => dir_obj.list(self, clearance, ctx),
             _ => Err(FsError::NotADir)
         }
     }
 
+    /// Returns the size and metadata of the object at `path`
+    ///
+    /// The context's current label is tainted for each path component, meaning
+    /// path traversal never fails when the path exists, but may increase the
+    /// current label up to the context's clearance. Traversal fails with
+    /// `FsError::ClearanceError` rather than exceed it.
+    pub fn stat<P: Into<Path>>(&self, path: P, ctx: &mut SecurityContext) -> Result<Stat, FsError> {
+        self.read_path(path, ctx)?.stat(self, ctx)
+    }
+
     /// Reads and returns the data of the file at `path`
     ///
-    /// The thread's current label is tainted for each path component, meaning path
-    /// traversal never fails when the path exists, but may increase the current
-    /// label arbitrarily high.
-    pub fn read_file<P: Into<Path>>(&self, path: P) -> Result<File, FsError> {
-        match self.read_path(path)? {
-            DirEntry::File(file_obj) => {
-                Ok(file_obj.read(self))
-            },
+    /// The context's current label is tainted for each path component, meaning
+    /// path traversal never fails when the path exists, but may increase the
+    /// current label up to the context's clearance. Traversal fails with
+    /// `FsError::ClearanceError` rather than exceed it.
+    pub fn read_file<P: Into<Path>>(&self, path: P, ctx: &mut SecurityContext) -> Result<Vec<u8>, FsError> {
+        match self.read_path(path, ctx)? {
+            DirEntry::File(file_obj) => file_obj.read(self, ctx),
             _ => Err(FsError::NotAFile),
         }
     }
 
     /// Writes `data` to the file at `path`, or returns an error if the file
-    /// doesn't exist or the current thread's label and privilege aren't
+    /// doesn't exist or the context's current label and privilege aren't
     /// sufficient for writing to it.
     ///
-    /// The thread's current label is tainted for each path component, meaning path
-    /// traversal never fails when the path exists, but may increase the current
-    /// label arbitrarily high.
-    pub fn write_file<P: Into<Path>>(&self, path: P, data: Vec<u8>) -> Result<(), FsError> {
-        match self.read_path(path)? {
+    /// The context's current label is tainted for each path component, meaning
+    /// path traversal never fails when the path exists, but may increase the
+    /// current label up to the context's clearance. Traversal fails with
+    /// `FsError::ClearanceError` rather than exceed it.
+    pub fn write_file<P: Into<Path>>(&self, path: P, data: Vec<u8>, ctx: &mut SecurityContext) -> Result<(), FsError> {
+        match self.read_path(path, ctx)? {
             DirEntry::File(file_obj) => {
-                file_obj.write(data, self).map_err(Into::into)
+                file_obj.write(data, self, ctx)
             },
             _ => Err(FsError::NotAFile),
         }
     }
 
+    /// Creates `value` under a fresh uid, charging it to the context's
+    /// privilege in the same transaction
+    fn create_charged<T: Serialize>(&self, value: &T, charged: Charged, ctx: &SecurityContext) -> Result<ObjectRef<T>, FsError> {
+        loop {
+            let mut txn = Transaction::default();
+            let new = ObjectRef::stage_new(value, &mut txn);
+            self.stage_object_charge(ctx.privilege(), new.uid, charged, 0, &mut txn)?;
+            if self.0.commit(&txn) {
+                return Ok(new);
+            }
+        }
+    }
+
     /// Creates an empty file object
-    pub fn create_file(&self, label: Buckle) -> DirEntry {
-        let new_file = ObjectRef::create(label, &self.0);
-        DirEntry::File(new_file)
+    pub fn create_file(&self, label: Buckle, ctx: &SecurityContext) -> Result<DirEntry, FsError> {
+        let new_file = self.create_charged(&Labeled::new(label, File::default(), ctx), Charged::File, ctx)?;
+        Ok(DirEntry::File(new_file))
     }
 
     /// Creates a labeled Blob object
-    pub fn create_blob(&self, label: Buckle, blob_name: String) -> Result<DirEntry, FsError> {
-        let new_blob: ObjectRef<Labeled<Blob>> = ObjectRef::create(label, &self.0);
-        new_blob.replace(blob_name, self)?;
+    pub fn create_blob(&self, label: Buckle, blob_name: String, ctx: &SecurityContext) -> Result<DirEntry, FsError> {
+        let mut labeled = Labeled::new(label, Blob::default(), ctx);
+        labeled.write(blob_name, ctx)?;
+        let new_blob = self.create_charged(&labeled, Charged::Object, ctx)?;
         Ok(DirEntry::Blob(new_blob))
     }
 
 
 
     /// Creates an empty directory object
-    pub fn create_directory(&self, label: Buckle) -> DirEntry {
-        let new_dir = ObjectRef::create(label, &self.0);
-        DirEntry::Directory(new_dir)
+    pub fn create_directory(&self, label: Buckle, ctx: &SecurityContext) -> Result<DirEntry, FsError> {
+        let new_dir = self.create_charged(&Labeled::new(label, Directory::default(), ctx), Charged::Object, ctx)?;
+        Ok(DirEntry::Directory(new_dir))
+    }
+
+    /// Creates an empty map object
+    pub fn create_map(&self, label: Buckle, ctx: &SecurityContext) -> Result<DirEntry, FsError> {
+        let new_map = self.create_charged(&Labeled::new(label, Map::default(), ctx), Charged::Map, ctx)?;
+        Ok(DirEntry::Map(new_map))
+    }
+
+    /// Creates an empty log object
+    pub fn create_log(&self, label: Buckle, ctx: &SecurityContext) -> Result<DirEntry, FsError> {
+        let new_log = self.create_charged(&Labeled::new(label, Log::default(), ctx), Charged::Log, ctx)?;
+        Ok(DirEntry::Log(new_log))
     }
 
     /// Creates an empty faceted directory object
-    pub fn create_faceted_directory(&self) -> DirEntry {
-        let new_dir = ObjectRef::set_new_id(&Default::default(), &self.0);
-        DirEntry::FacetedDirectory(new_dir)
-    }
-
-    pub fn create_direct_gate(&self, label: Buckle, direct_gate: DirectGate) -> Result<DirEntry, FsError> {
-        PRIVILEGE.with(|privilege| {
-            let privilege = privilege.borrow();
-            if !CURRENT_LABEL.with(|current_label| current_label.borrow().can_flow_to_with_privilege(&label, &privilege)) {
-                Err(FsError::LabelError(LabelError::CannotWrite))
-            } else if !privilege.implies(&direct_gate.privilege) {
-                Err(FsError::PrivilegeError(PrivilegeError::CannotDelegate))
-            } else if !privilege.implies(&direct_gate.declassify) {
-                Err(FsError::PrivilegeError(PrivilegeError::CannotDelegate))
-            } else {
-                Ok(())
-            }
-        })?;
-        let labeled = Labeled {
-            label,
-            data: Gate::Direct(direct_gate),
-        };
-        let new_gate = ObjectRef::set_new_id(&labeled, &self.0);
-        Ok(DirEntry::Gate(new_gate))
+    pub fn create_faceted_directory(&self, ctx: &SecurityContext) -> Result<DirEntry, FsError> {
+        let new_dir = self.create_charged(&FacetedDirectory::default(), Charged::Object, ctx)?;
+        Ok(DirEntry::FacetedDirectory(new_dir))
     }
 
-    pub fn create_redirect_gate(&self, label: Buckle, redirect_gate: RedirectGate) -> Result<DirEntry, FsError> {
-        PRIVILEGE.with(|privilege| {
-            let privilege = privilege.borrow();
-            if !CURRENT_LABEL.with(|current_label| current_label.borrow().can_flow_to_with_privilege(&label, &privilege)) {
-                Err(FsError::LabelError(LabelError::CannotWrite))
-            } else if !privilege.implies(&redirect_gate.privilege) {
-                Err(FsError::PrivilegeError(PrivilegeError::CannotDelegate))
-            } else if !privilege.implies(&redirect_gate.declassify) {
-                Err(FsError::PrivilegeError(PrivilegeError::CannotDelegate))
-            } else {
-                Ok(())
-            }
-        })?;
-        let labeled = Labeled {
-            label,
-            data: Gate::Redirect(redirect_gate),
-        };
-        let new_gate = ObjectRef::set_new_id(&labeled, &self.0);
+    pub fn create_direct_gate(&self, label: Buckle, direct_gate: DirectGate, ctx: &SecurityContext) -> Result<DirEntry, FsError> {
+        check_create(&label, &[&direct_gate.privilege, &direct_gate.declassify], ctx)?;
+        let labeled = Labeled::new(label, Gate::Direct(direct_gate), ctx);
+        let new_gate = self.create_charged(&labeled, Charged::Object, ctx)?;
         Ok(DirEntry::Gate(new_gate))
     }
 
-    pub fn create_service(&self, label: Buckle, service: Service) -> Result<DirEntry, FsError> {
-        PRIVILEGE.with(|privilege| {
-            let privilege = privilege.borrow();
-            if !CURRENT_LABEL.with(|current_label| current_label.borrow().can_flow_to_with_privilege(&label, &privilege)) {
-                Err(FsError::LabelError(LabelError::CannotWrite))
-            } else if !privilege.implies(&service.privilege) {
-                Err(FsError::PrivilegeError(PrivilegeError::CannotDelegate))
-            } else {
-                Ok(())
-            }
-        })?;
+    pub fn create_redirect_gate(&self, label: Buckle, redirect_gate: RedirectGate, ctx: &SecurityContext) -> Result<DirEntry, FsError> {
+        check_create(&label, &[&redirect_gate.privilege, &redirect_gate.declassify], ctx)?;
+        let labeled = Labeled::new(label, Gate::Redirect(redirect_gate), ctx);
+        let new_gate = self.create_charged(&labeled, Charged::Object, ctx)?;
+        Ok(DirEntry::Gate(new_gate))
+    }
 
-        let labeled = Labeled {
-            label,
-            data: service,
-        };
-        let new_service = ObjectRef::set_new_id(&labeled, &self.0);
+    pub fn create_service(&self, label: Buckle, service: Service, ctx: &SecurityContext) -> Result<DirEntry, FsError> {
+        check_create(&label, &[&service.privilege], ctx)?;
+        let labeled = Labeled::new(label, service, ctx);
+        let new_service = self.create_charged(&labeled, Charged::Object, ctx)?;
         Ok(DirEntry::Service(new_service))
     }
 
     /// Links an directory entry in `base_dir`.
     ///
-    /// The thread's current label is tainted for each path component, meaning path
-    /// traversal never fails when the path exists, but may increase the current
-    /// label arbitrarily high.
-    pub fn link<P: Into<Path>>(&self, base_dir: P, name: String, direntry: DirEntry) -> Result<(), FsError> {
-        match self.read_path(base_dir.into())? {
+    /// The context's current label is tainted for each path component, meaning
+    /// path traversal never fails when the path exists, but may increase the
+    /// current label up to the context's clearance. Traversal fails with
+    /// `FsError::ClearanceError` rather than exceed it.
+    pub fn link<P: Into<Path>>(&self, base_dir: P, name: String, direntry: DirEntry, ctx: &mut SecurityContext) -> Result<(), FsError> {
+        match self.read_path(base_dir.into(), ctx)? {
             DirEntry::Directory(dir_obj) => {
-                dir_obj.link(name, direntry, &self).map_err(Into::into).and_then(|success| {
+                dir_obj.link(name, direntry, self, ctx).and_then(|success| {
                     if success {
                         Ok(())
                     } else {
@@ -690,19 +974,73 @@ impl<S: BackingStore> FS<S> {
         }
     }
 
-    pub fn open_blob<P: Into<Path>>(&self, path: P) -> Result<Blob, FsError> {
-        match self.read_path(path)? {
-            DirEntry::Blob(blob_obj) => {
-                Ok(blob_obj.read(self))
+    /// Creates `new_entry` and links it as `name` in `base_dir` in a single
+    /// transaction, or returns `FsError::NameExists` without creating anything
+    /// if `name` exists.
+    ///
+    /// The context's current label is tainted for each path component, meaning
+    /// path traversal never fails when the path exists, but may increase the
+    /// current label up to the context's clearance. Traversal fails with
+    /// `FsError::ClearanceError` rather than exceed it.
+    pub fn create_and_link<P: Into<Path>>(&self, base_dir: P, name: String, new_entry: NewEntry, ctx: &mut SecurityContext) -> Result<DirEntry, FsError> {
+        match self.read_path(base_dir, ctx)? {
+            DirEntry::Directory(dir_obj) => {
+                self.create_in(&dir_obj, name, new_entry, false, ctx).map(|(entry, _)| entry)
             },
+            _ => Err(FsError::NotADir),
+        }
+    }
+
+    /// Creates `new_entry` and links it as `name` in `base_dir` in a single
+    /// transaction, replacing the existing entry, if any. Returns the new entry
+    /// and the replaced one. The replaced object itself is left to the
+    /// garbage collector, as it may be linked elsewhere.
+    ///
+    /// The context's current label is tainted for each path component, meaning
+    /// path traversal never fails when the path exists, but may increase the
+    /// current label up to the context's clearance. Traversal fails with
+    /// `FsError::ClearanceError` rather than exceed it.
+    pub fn replace_entry<P: Into<Path>>(&self, base_dir: P, name: String, new_entry: NewEntry, ctx: &mut SecurityContext) -> Result<(DirEntry, Option<DirEntry>), FsError> {
+        match self.read_path(base_dir, ctx)? {
+            DirEntry::Directory(dir_obj) => self.create_in(&dir_obj, name, new_entry, true, ctx),
+            _ => Err(FsError::NotADir),
+        }
+    }
+
+    fn create_in(
+        &self,
+        dir: &ObjectRef<Labeled<Directory>>,
+        name: String,
+        new_entry: NewEntry,
+        replace: bool,
+        ctx: &mut SecurityContext,
+    ) -> Result<(DirEntry, Option<DirEntry>), FsError> {
+        match &new_entry {
+            NewEntry::Directory(_) | NewEntry::FacetedDirectory => (),
+            NewEntry::File(label, _) | NewEntry::Blob(label, _) | NewEntry::Map(label) | NewEntry::Log(label) => check_create(label, &[], ctx)?,
+            NewEntry::DirectGate(label, gate) => check_create(label, &[&gate.privilege, &gate.declassify], ctx)?,
+            NewEntry::RedirectGate(label, gate) => check_create(label, &[&gate.privilege, &gate.declassify], ctx)?,
+            NewEntry::Service(label, service) => check_create(label, &[&service.privilege], ctx)?,
+        }
+        dir.link_staged(name, replace, |txn, ctx| {
+            let entry = new_entry.stage(txn, ctx);
+            let (charged, file_bytes) = new_entry.charged();
+            self.stage_object_charge(ctx.privilege(), entry.uid(), charged, file_bytes, txn)?;
+            Ok(entry)
+        }, self, ctx)
+    }
+
+    pub fn open_blob<P: Into<Path>>(&self, path: P, ctx: &mut SecurityContext) -> Result<Blob, FsError> {
+        match self.read_path(path, ctx)? {
+            DirEntry::Blob(blob_obj) => blob_obj.read(self, ctx),
             _ => Err(FsError::NotABlob),
         }
     }
 
-    pub fn replace_blob<P: Into<Path>>(&self, path: P, new_blob: Blob) -> Result<(), FsError> {
-        match self.read_path(path)? {
+    pub fn replace_blob<P: Into<Path>>(&self, path: P, new_blob: Blob, ctx: &mut SecurityContext) -> Result<(), FsError> {
+        match self.read_path(path, ctx)? {
             DirEntry::Blob(blob_obj) => {
-                blob_obj.replace(new_blob, self).map_err(Into::into)
+                blob_obj.replace(new_blob, self, ctx)
             },
             _ => Err(FsError::NotABlob),
         }
@@ -719,6 +1057,111 @@ pub trait BackingStore {
     fn cas(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8])
         -> Result<(), Option<Vec<u8>>>;
     fn del(&self, key: &[u8]);
+    /// Returns at most `limit` key-value pairs with keys in [`start`, `end`),
+    /// or [`start`, ∞) if `end` is `None`, in ascending key order.
+    fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)>;
+    /// Atomically applies the writes of `txn` if all of its checks hold.
+    /// Returns false, without writing anything, otherwise.
+    fn commit(&self, txn: &Transaction) -> bool;
+    /// Returns and resets the metrics recorded since they were last taken, if
+    /// the store records any, like `instrument::InstrumentedStore`
+    fn take_metrics(&self) -> Option<instrument::StoreMetrics> {
+        None
+    }
+}
+
+/// A set of writes to be committed atomically, conditioned on the values of
+/// some keys at commit time.
+#[derive(Debug, Default, Clone)]
+pub struct Transaction {
+    checks: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl Transaction {
+    /// Requires `key` to hold `expected` at commit time, or to not exist if
+    /// `expected` is `None`
+    pub fn check(&mut self, key: &[u8], expected: Option<&[u8]>) {
+        self.checks.push((Vec::from(key), expected.map(Vec::from)));
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.push((Vec::from(key), Some(Vec::from(value))));
+    }
+
+    pub fn del(&mut self, key: &[u8]) {
+        self.writes.push((Vec::from(key), None));
+    }
+
+    /// Keys paired with their expected values
+    pub fn checks(&self) -> &[(Vec<u8>, Option<Vec<u8>>)] {
+        &self.checks
+    }
+
+    /// Keys paired with their new values, `None` for deletions, in order
+    pub fn writes(&self) -> &[(Vec<u8>, Option<Vec<u8>>)] {
+        &self.writes
+    }
+}
+
+/// Returns the smallest key greater than all keys starting with `prefix`, or
+/// `None` if there is no such key.
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Iterates over the key-value pairs of a key range in ascending key order,
+/// fetching at most `page_size` pairs from the store at a time.
+pub struct ScanIter<'a, B: ?Sized> {
+    store: &'a B,
+    next: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    page_size: usize,
+    page: std::collections::VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<'a, B: BackingStore + ?Sized> ScanIter<'a, B> {
+    /// Iterates over keys in [`start`, `end`), or [`start`, ∞) if `end` is `None`
+    pub fn range(store: &'a B, start: &[u8], end: Option<&[u8]>, page_size: usize) -> Self {
+        ScanIter {
+            store,
+            next: Some(start.to_vec()),
+            end: end.map(Vec::from),
+            page_size: page_size.max(1),
+            page: Default::default(),
+        }
+    }
+
+    /// Iterates over keys starting with `prefix`
+    pub fn prefix(store: &'a B, prefix: &[u8], page_size: usize) -> Self {
+        let end = prefix_end(prefix);
+        Self::range(store, prefix, end.as_deref(), page_size)
+    }
+}
+
+impl<'a, B: BackingStore + ?Sized> Iterator for ScanIter<'a, B> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() {
+            let start = self.next.take()?;
+            self.page = self.store.scan(&start, self.end.as_deref(), self.page_size).into();
+            if self.page.len() == self.page_size {
+                // the smallest key strictly greater than the last one
+                let mut next = self.page.back().unwrap().0.clone();
+                next.push(0);
+                self.next = Some(next);
+            }
+        }
+        self.page.pop_front()
+    }
 }
 
 impl<B: BackingStore> BackingStore for &B {
@@ -742,6 +1185,15 @@ impl<B: BackingStore> BackingStore for &B {
     fn del(&self, key: &[u8]) {
         (*self).del(key)
     }
+    fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (*self).scan(start, end, limit)
+    }
+    fn commit(&self, txn: &Transaction) -> bool {
+        (*self).commit(txn)
+    }
+    fn take_metrics(&self) -> Option<instrument::StoreMetrics> {
+        (*self).take_metrics()
+    }
 }
 
 impl<B: BackingStore + ?Sized> BackingStore for Box<B> {
@@ -765,4 +1217,300 @@ impl<B: BackingStore + ?Sized> BackingStore for Box<B> {
     fn del(&self, key: &[u8]) {
         self.as_ref().del(key)
     }
+    fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.as_ref().scan(start, end, limit)
+    }
+    fn commit(&self, txn: &Transaction) -> bool {
+        self.as_ref().commit(txn)
+    }
+    fn take_metrics(&self) -> Option<instrument::StoreMetrics> {
+        self.as_ref().take_metrics()
+    }
+}
+
+/// Returns whether the directory `uid` is `entry` or within it, reading
+/// directories and the facets of faceted directories without tainting.
+/// Dangling references are skipped.
+fn contains_directory<B: BackingStore>(entry: &DirEntry, uid: u64, fs: &FS<B>) -> Result<bool, FsError> {
+    let mut visited = HashSet::new();
+    let mut stack = vec![entry.clone()];
+    while let Some(entry) = stack.pop() {
+        if !matches!(entry, DirEntry::Directory(_) | DirEntry::FacetedDirectory(_)) || !visited.insert(entry.uid()) {
+            continue;
+        }
+        if entry.uid() == uid {
+            return Ok(true);
+        }
+        match entry {
+            DirEntry::Directory(dir) => match dir.get(fs) {
+                Ok(dir) => stack.extend(dir.data.entries.into_values()),
+                Err(FsError::DanglingReference(_)) => (),
+                Err(e) => return Err(e),
+            },
+            DirEntry::FacetedDirectory(faceted) => match faceted.get(fs) {
+                Ok(faceted) => stack.extend(faceted.facets.into_iter().map(|(_, dir)| DirEntry::Directory(dir))),
+                Err(FsError::DanglingReference(_)) => (),
+                Err(e) => return Err(e),
+            },
+            _ => (),
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::memory::MemoryStore;
+
+    fn new_fs() -> FS<MemoryStore> {
+        let fs = FS::new(MemoryStore::new());
+        assert!(fs.initialize());
+        fs
+    }
+
+    fn path(s: &str) -> Path {
+        Path::parse(s, &SecurityContext::default()).unwrap()
+    }
+
+    fn principal(name: &str) -> Component {
+        Buckle::parse(&format!("T,{}", name)).unwrap().integrity
+    }
+
+    fn direct_gate(privilege: Component) -> DirectGate {
+        DirectGate {
+            privilege,
+            invoker_integrity_clearance: Component::dc_true(),
+            declassify: Component::dc_true(),
+            function: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_modify_taints_even_if_write_fails() {
+        let mut ctx = SecurityContext::default();
+        let mut labeled = Labeled::new(Buckle::parse("alice,alice").unwrap(), 0, &ctx);
+        let res = labeled.modify(&mut ctx, |data| *data = 1);
+        assert!(matches!(res, Err(FsError::LabelError(LabelError::CannotWrite))));
+        assert_eq!(labeled.data, 0);
+        assert_eq!(ctx.label(), &Buckle::parse("alice,T").unwrap());
+
+        let mut ctx = SecurityContext::new(principal("alice"));
+        assert!(labeled.modify(&mut ctx, |data| *data = 1).is_ok());
+        assert_eq!(labeled.data, 1);
+    }
+
+    #[test]
+    fn test_link_into_root_requires_root_privilege() {
+        let fs = new_fs();
+        let mut ctx = SecurityContext::default();
+        let dir = fs.create_directory(Buckle::public(), &ctx).unwrap();
+        let res = fs.link(path(":"), "home".into(), dir.clone(), &mut ctx);
+        assert!(matches!(res, Err(FsError::LabelError(LabelError::CannotWrite))));
+        assert!(fs.list_dir(path(":"), &mut ctx).unwrap().is_empty());
+
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        assert!(fs.link(path(":"), "home".into(), dir.clone(), &mut ctx).is_ok());
+        assert!(matches!(fs.link(path(":"), "home".into(), dir, &mut ctx), Err(FsError::NameExists)));
+    }
+
+    #[test]
+    fn test_link_checks_directory_integrity() {
+        let fs = new_fs();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        let dir = fs.create_directory(Buckle::parse("alice,alice").unwrap(), &ctx).unwrap();
+        fs.link(path(":"), "alice".into(), dir, &mut ctx).unwrap();
+
+        let mut ctx = SecurityContext::new(principal("bob"));
+        let file = fs.create_file(Buckle::public(), &ctx).unwrap();
+        let res = fs.link(path(":alice"), "f".into(), file.clone(), &mut ctx);
+        assert!(matches!(res, Err(FsError::LabelError(LabelError::CannotWrite))));
+
+        let mut ctx = SecurityContext::new(principal("alice"));
+        assert!(fs.link(path(":alice"), "f".into(), file, &mut ctx).is_ok());
+        assert!(fs.list_dir(path(":alice"), &mut ctx).unwrap().contains_key("f"));
+    }
+
+    #[test]
+    fn test_reading_secret_prevents_public_write() {
+        let fs = new_fs();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        let secret = fs.create_file(Buckle::parse("alice,T").unwrap(), &ctx).unwrap();
+        fs.link(path(":"), "secret".into(), secret, &mut ctx).unwrap();
+        let public = fs.create_file(Buckle::public(), &ctx).unwrap();
+        fs.link(path(":"), "public".into(), public, &mut ctx).unwrap();
+
+        let mut ctx = SecurityContext::default();
+        assert!(fs.write_file(path(":public"), b"hello".to_vec(), &mut ctx).is_ok());
+        fs.read_file(path(":secret"), &mut ctx).unwrap();
+        let res = fs.write_file(path(":public"), b"leak".to_vec(), &mut ctx);
+        assert!(matches!(res, Err(FsError::LabelError(LabelError::CannotWrite))));
+        assert_eq!(fs.read_file(path(":public"), &mut ctx).unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_contexts_are_independent() {
+        let fs = new_fs();
+        let mut root = SecurityContext::new(Component::dc_false());
+        fs.create_and_link(path(":"), "secret".into(), NewEntry::File(Buckle::parse("alice,T").unwrap(), vec![]), &mut root).unwrap();
+        fs.create_and_link(path(":"), "public".into(), NewEntry::File(Buckle::public(), vec![]), &mut root).unwrap();
+
+        let mut tainted = SecurityContext::default();
+        let mut clean = SecurityContext::default();
+        fs.read_file(path(":secret"), &mut tainted).unwrap();
+        assert_eq!(tainted.label(), &Buckle::parse("alice,T").unwrap());
+        assert_eq!(clean.label(), &Buckle::public());
+        assert!(fs.write_file(path(":public"), b"hello".to_vec(), &mut clean).is_ok());
+        assert!(fs.write_file(path(":public"), b"leak".to_vec(), &mut tainted).is_err());
+    }
+
+    #[test]
+    fn test_create_and_link_is_atomic() {
+        let fs = new_fs();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        fs.create_and_link(path(":"), "f".into(), NewEntry::File(Buckle::public(), b"one".to_vec()), &mut ctx).unwrap();
+        assert_eq!(fs.read_file(path(":f"), &mut ctx).unwrap(), b"one".to_vec());
+        let objects = fs.0.scan(&[], None, usize::MAX).len();
+
+        let res = fs.create_and_link(path(":"), "f".into(), NewEntry::File(Buckle::public(), b"two".to_vec()), &mut ctx);
+        assert!(matches!(res, Err(FsError::NameExists)));
+        let mut ctx = SecurityContext::default();
+        let res = fs.create_and_link(path(":"), "g".into(), NewEntry::Directory(Buckle::public()), &mut ctx);
+        assert!(matches!(res, Err(FsError::LabelError(LabelError::CannotWrite))));
+        assert_eq!(fs.0.scan(&[], None, usize::MAX).len(), objects);
+    }
+
+    #[test]
+    fn test_replace_entry() {
+        let fs = new_fs();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        let old = fs.create_and_link(path(":"), "f".into(), NewEntry::Directory(Buckle::public()), &mut ctx).unwrap();
+        let (new, replaced) = fs.replace_entry(path(":"), "f".into(), NewEntry::File(Buckle::public(), b"data".to_vec()), &mut ctx).unwrap();
+        match (old, replaced) {
+            (DirEntry::Directory(old), Some(DirEntry::Directory(replaced))) => assert_eq!(old.uid, replaced.uid),
+            _ => panic!("unexpected replaced entry"),
+        }
+        assert!(matches!(new, DirEntry::File(_)));
+        assert_eq!(fs.read_file(path(":f"), &mut ctx).unwrap(), b"data".to_vec());
+    }
+
+    #[test]
+    fn test_rename() {
+        let fs = new_fs();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        fs.create_and_link(path(":"), "a".into(), NewEntry::Directory(Buckle::public()), &mut ctx).unwrap();
+        fs.create_and_link(path(":"), "b".into(), NewEntry::Directory(Buckle::public()), &mut ctx).unwrap();
+        fs.create_and_link(path(":a"), "f".into(), NewEntry::File(Buckle::public(), b"data".to_vec()), &mut ctx).unwrap();
+        fs.create_and_link(path(":b"), "g".into(), NewEntry::File(Buckle::public(), vec![]), &mut ctx).unwrap();
+
+        fs.rename(path(":a"), &"f".into(), path(":a"), "f2".into(), &mut ctx).unwrap();
+        let res = fs.rename(path(":a"), &"f2".into(), path(":b"), "g".into(), &mut ctx);
+        assert!(matches!(res, Err(FsError::NameExists)));
+        fs.rename(path(":a"), &"f2".into(), path(":b"), "f".into(), &mut ctx).unwrap();
+        assert!(fs.list_dir(path(":a"), &mut ctx).unwrap().is_empty());
+        assert_eq!(fs.read_file(path(":b:f"), &mut ctx).unwrap(), b"data".to_vec());
+        let res = fs.rename(path(":a"), &"f2".into(), path(":b"), "h".into(), &mut ctx);
+        assert!(matches!(res, Err(FsError::UnlinkError(UnlinkError::DoesNotExists))));
+        fs.rename(path(":b"), &"f".into(), path(":b"), "f".into(), &mut ctx).unwrap();
+        assert_eq!(fs.read_file(path(":b:f"), &mut ctx).unwrap(), b"data".to_vec());
+        let res = fs.rename(path(":b"), &"h".into(), path(":b"), "h".into(), &mut ctx);
+        assert!(matches!(res, Err(FsError::UnlinkError(UnlinkError::DoesNotExists))));
+    }
+
+    #[test]
+    fn test_rename_into_own_subtree() {
+        let fs = new_fs();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        fs.create_and_link(path(":"), "a".into(), NewEntry::Directory(Buckle::public()), &mut ctx).unwrap();
+        fs.create_and_link(path(":a"), "b".into(), NewEntry::FacetedDirectory, &mut ctx).unwrap();
+        fs.create_and_link(path(":a:b:<T,T>"), "c".into(), NewEntry::Directory(Buckle::public()), &mut ctx).unwrap();
+
+        for dest in [":a", ":a:b:<T,T>", ":a:b:<T,T>:c"] {
+            let res = fs.rename(path(":"), &"a".into(), path(dest), "a".into(), &mut ctx);
+            assert!(matches!(res, Err(FsError::LinkError(LinkError::Cycle))));
+        }
+        assert!(fs.list_dir(path(":a:b:<T,T>"), &mut ctx).unwrap().contains_key("c"));
+        fs.rename(path(":a:b:<T,T>"), &"c".into(), path(":"), "c".into(), &mut ctx).unwrap();
+        fs.rename(path(":"), &"a".into(), path(":c"), "a".into(), &mut ctx).unwrap();
+        assert!(fs.list_dir(path(":c:a"), &mut ctx).unwrap().contains_key("b"));
+    }
+
+    #[test]
+    fn test_rename_checks_both_directories() {
+        let fs = new_fs();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        fs.create_and_link(path(":"), "alice".into(), NewEntry::Directory(Buckle::parse("alice,alice").unwrap()), &mut ctx).unwrap();
+        fs.create_and_link(path(":"), "bob".into(), NewEntry::Directory(Buckle::parse("T,bob").unwrap()), &mut ctx).unwrap();
+        fs.create_and_link(path(":alice"), "f".into(), NewEntry::File(Buckle::public(), vec![]), &mut ctx).unwrap();
+
+        // alice can modify her own directory, but not bob's
+        let mut ctx = SecurityContext::new(principal("alice"));
+        let res = fs.rename(path(":alice"), &"f".into(), path(":bob"), "f".into(), &mut ctx);
+        assert!(matches!(res, Err(FsError::LabelError(LabelError::CannotWrite))));
+        assert!(fs.list_dir(path(":alice"), &mut ctx).unwrap().contains_key("f"));
+    }
+
+    #[test]
+    fn test_stat() {
+        let fs = new_fs();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        fs.create_and_link(path(":"), "f".into(), NewEntry::File(Buckle::parse("alice,T").unwrap(), b"data".to_vec()), &mut ctx).unwrap();
+        let created = fs.stat(path(":f"), &mut ctx).unwrap().metadata.unwrap();
+        assert_eq!(created.creator, Component::dc_false());
+
+        let mut ctx = SecurityContext::new(principal("alice"));
+        fs.write_file(path(":f"), b"longer data".to_vec(), &mut ctx).unwrap();
+        let mut ctx = SecurityContext::new(principal("alice"));
+        let stat = fs.stat(path(":f"), &mut ctx).unwrap();
+        assert_eq!(stat.size, Some(11));
+        let meta = stat.metadata.unwrap();
+        assert_eq!(meta.created, created.created);
+        assert!(meta.modified >= created.modified);
+        assert_eq!(ctx.label(), &Buckle::parse("alice,T").unwrap());
+    }
+
+    #[test]
+    fn test_dangling_and_corrupt_references() {
+        let fs = new_fs();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        let dangling = ObjectRef::<Labeled<File>>::new(42);
+        fs.link(path(":"), "dangling".into(), DirEntry::File(dangling), &mut ctx).unwrap();
+        assert!(matches!(fs.read_file(path(":dangling"), &mut ctx), Err(FsError::DanglingReference(42))));
+
+        let file = fs.create_and_link(path(":"), "corrupt".into(), NewEntry::File(Buckle::public(), vec![]), &mut ctx).unwrap();
+        if let DirEntry::File(file) = file {
+            fs.0.put(&file.uid.to_be_bytes(), b"garbage");
+            assert!(matches!(fs.read_file(path(":corrupt"), &mut ctx), Err(FsError::CorruptedObject(uid)) if uid == file.uid));
+        }
+    }
+
+    #[test]
+    fn test_create_direct_gate_checks() {
+        let fs = new_fs();
+        let ctx = SecurityContext::new(principal("alice"));
+        let res = fs.create_direct_gate(Buckle::public(), direct_gate(principal("bob")), &ctx);
+        assert!(matches!(res, Err(FsError::PrivilegeError(PrivilegeError::CannotDelegate))));
+
+        let res = fs.create_direct_gate(Buckle::parse("T,bob").unwrap(), direct_gate(principal("alice")), &ctx);
+        assert!(matches!(res, Err(FsError::LabelError(LabelError::CannotWrite))));
+
+        let res = fs.create_direct_gate(Buckle::parse("T,alice").unwrap(), direct_gate(principal("alice")), &ctx);
+        assert!(matches!(res, Ok(DirEntry::Gate(_))));
+    }
+
+    #[test]
+    fn test_clearance_bounds_traversal() {
+        let fs = new_fs();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        fs.create_and_link(path(":"), "alice".into(), NewEntry::Directory(Buckle::parse("alice,T").unwrap()), &mut ctx).unwrap();
+        fs.create_and_link(path(":"), "bob".into(), NewEntry::Directory(Buckle::parse("bob,T").unwrap()), &mut ctx).unwrap();
+
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        ctx.set_clearance(Buckle::parse("alice,T").unwrap()).unwrap();
+        assert!(matches!(fs.list_dir(path(":bob"), &mut ctx), Err(FsError::ClearanceError)));
+        assert_eq!(ctx.label(), &Buckle::public());
+        assert!(fs.list_dir(path(":alice"), &mut ctx).unwrap().is_empty());
+        assert_eq!(ctx.label(), &Buckle::parse("alice,T").unwrap());
+        assert!(ctx.set_clearance(Buckle::public()).is_err());
+    }
 }
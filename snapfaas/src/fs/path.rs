@@ -1,59 +1,263 @@
-use std::collections::VecDeque;
+//! Faasten paths
+//!
+//! A path is a sequence of components separated by `:`, optionally starting
+//! and ending with any number of `:`, where `:` alone (or the empty string) is
+//! the root. A component is one of
+//!
+//! * a name, e.g. `home`. Backslash escapes any character, and `:`, `<`, `>`,
+//!   `"` and `\` must be escaped,
+//! * a quoted name, e.g. `"a:b"`, where only `"` and `\` must be escaped,
+//! * a facet, e.g. `<alice,T>`. Backslash escapes any character in the
+//!   principals of the label, and `,`, `&`, `|`, `/`, `>` and `\` must be
+//!   escaped,
+//! * `%`, the facet of the current label,
+//! * `~`, only as the first component, which is `home:<p,p>` for the privilege
+//!   `p`.
+//!
+//! `%` and `~` are expanded with the `SecurityContext` passed to the parser,
+//! so printing a `Path` never produces them, and parsing a printed `Path`
+//! returns the same `Path`. A path starting with `~` starts at the root, even
+//! where other paths are resolved relative to a directory.
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt::{self, Write};
 
-use labeled::buckle::Buckle;
+use labeled::buckle::{Buckle, Clause, Component};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
-pub enum Error {
-    InvalidName,
+use super::SecurityContext;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    EmptyComponent,
+    UnexpectedChar(char),
+    DanglingEscape,
+    UnterminatedQuote,
+    UnterminatedFacet,
     InvalidFacet,
+    MisplacedHome,
+}
+
+/// A parse error at a byte offset of the input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub position: usize,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ErrorKind::EmptyComponent => write!(f, "empty path component")?,
+            ErrorKind::UnexpectedChar(c) => write!(f, "unexpected {:?}", c)?,
+            ErrorKind::DanglingEscape => write!(f, "nothing to escape")?,
+            ErrorKind::UnterminatedQuote => write!(f, "unterminated quoted name")?,
+            ErrorKind::UnterminatedFacet => write!(f, "unterminated facet")?,
+            ErrorKind::InvalidFacet => write!(f, "invalid facet label")?,
+            ErrorKind::MisplacedHome => write!(f, "`~` is only allowed as the first component")?,
+        }
+        write!(f, " at offset {}", self.position)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum PathComponent {
     Dscrp(String),
     Facet(Buckle),
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Path {
     components: VecDeque<PathComponent>,
 }
 
-impl Path {
-    // FIXME need a proper parser
-    /// The root should be written as ":" in string, though the function below
-    /// does not enforce it.
-    pub fn parse(input: &str) -> Result<Self, Error> {
-        let input = input.trim_matches(':');
-        let mut components = VecDeque::new();
-        let mut cs = input.split(":").peekable();
-        match cs.peek().unwrap() {
-            &"" => return Ok(Path { components }),
-            &"~" => {
-                components.push_back(PathComponent::Dscrp("home".to_string()));
-                components.push_back(PathComponent::Facet(super::utils::get_ufacet()));
-                let _ = cs.next();
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    ctx: &'a SecurityContext,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    /// Whether nothing but `:` is left, which a path may end with
+    fn at_end(&self) -> bool {
+        self.input[self.pos..].chars().all(|c| c == ':')
+    }
+
+    fn error(&self, kind: ErrorKind, position: usize) -> Error {
+        Error { kind, position }
+    }
+
+    /// Reads up to the unescaped `end`, which is consumed, and returns the
+    /// text read with escapes removed if `unescape`, or as is otherwise
+    fn delimited(&mut self, end: char, unescape: bool, unterminated: ErrorKind, start: usize) -> Result<String, Error> {
+        let mut res = String::new();
+        loop {
+            match self.bump() {
+                None => return Err(self.error(unterminated, start)),
+                Some(c) if c == end => return Ok(res),
+                Some('\\') => {
+                    let escaped = self.bump().ok_or(self.error(ErrorKind::DanglingEscape, self.pos - 1))?;
+                    if !unescape {
+                        res.push('\\');
+                    }
+                    res.push(escaped);
+                },
+                Some(c) => res.push(c),
             }
-            &_ => (),
         }
+    }
 
-        let label_re = regex::Regex::new(r"^<(?P<lbl>.+)>$").unwrap();
-        for c in cs {
-            if c == "%" {
-                components.push_back(PathComponent::Facet(super::utils::get_current_label()));
-            } else if label_re.is_match(c) {
-                let lblstr = label_re
-                    .captures(c)
-                    .and_then(|cap| cap.name("lbl").map(|lbl| lbl.as_str()))
-                    .ok_or(Error::InvalidFacet)?;
-                let f = Buckle::parse(lblstr).map_err(|_| Error::InvalidFacet)?;
-                components.push_back(PathComponent::Facet(f));
-            } else {
-                components.push_back(PathComponent::Dscrp(c.to_string()));
+    /// Reads a bare name and returns it along with whether it had escapes
+    fn bare(&mut self) -> Result<(String, bool), Error> {
+        let mut res = String::new();
+        let mut escaped = false;
+        while let Some(c) = self.peek() {
+            match c {
+                ':' => break,
+                '<' | '>' | '"' => return Err(self.error(ErrorKind::UnexpectedChar(c), self.pos)),
+                '\\' => {
+                    self.bump();
+                    res.push(self.bump().ok_or(self.error(ErrorKind::DanglingEscape, self.pos - 1))?);
+                    escaped = true;
+                },
+                c => {
+                    self.bump();
+                    res.push(c);
+                },
+            }
+        }
+        Ok((res, escaped))
+    }
+
+    fn component(&mut self, first: bool) -> Result<Vec<PathComponent>, Error> {
+        let start = self.pos;
+        match self.peek() {
+            None | Some(':') => Err(self.error(ErrorKind::EmptyComponent, start)),
+            Some('<') => {
+                self.bump();
+                let label = self.delimited('>', false, ErrorKind::UnterminatedFacet, start)?;
+                let label = parse_label(&label).ok_or(self.error(ErrorKind::InvalidFacet, start + 1))?;
+                Ok(vec![PathComponent::Facet(label)])
+            },
+            Some('"') => {
+                self.bump();
+                Ok(vec![PathComponent::Dscrp(self.delimited('"', true, ErrorKind::UnterminatedQuote, start)?)])
+            },
+            Some(_) => match self.bare()? {
+                (name, false) if name == "~" => {
+                    if !first {
+                        return Err(self.error(ErrorKind::MisplacedHome, start));
+                    }
+                    Ok(vec![
+                        PathComponent::Dscrp("home".to_string()),
+                        PathComponent::Facet(self.ctx.ufacet()),
+                    ])
+                },
+                (name, false) if name == "%" => Ok(vec![PathComponent::Facet(self.ctx.label().clone())]),
+                (name, _) => Ok(vec![PathComponent::Dscrp(name)]),
+            },
+        }
+    }
+}
+
+/// Splits `s` at each `sep` that is not escaped, keeping escapes
+fn split_unescaped(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == sep {
+            parts.push(&s[start..i]);
+            start = i + 1;
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unescape(s: &str) -> String {
+    let mut res = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        res.push(if c == '\\' { chars.next().unwrap_or(c) } else { c });
+    }
+    res
+}
+
+/// Parses a label component as printed by `fmt_component`
+fn parse_component(s: &str) -> Option<Component> {
+    match s.trim() {
+        "T" => return Some(Component::dc_true()),
+        "F" => return Some(Component::dc_false()),
+        _ => (),
+    }
+    let mut clauses = BTreeSet::new();
+    for clause in split_unescaped(s, '&') {
+        let mut principals = BTreeSet::new();
+        for principal in split_unescaped(clause, '|') {
+            let tokens: Vec<String> = split_unescaped(principal, '/').into_iter().map(unescape).collect();
+            if tokens.iter().any(String::is_empty) {
+                return None;
+            }
+            principals.insert(tokens);
+        }
+        clauses.insert(Clause(principals));
+    }
+    Some(Component::DCFormula(clauses))
+}
+
+/// Parses the label of a facet, with escapes as printed by `fmt_component`
+fn parse_label(s: &str) -> Option<Buckle> {
+    match split_unescaped(s, ',')[..] {
+        [secrecy, integrity] => Some(Buckle::new(parse_component(secrecy)?, parse_component(integrity)?)),
+        _ => None,
+    }
+}
+
+impl Path {
+    /// Parses a path as described in the module documentation, expanding `~`
+    /// and `%` with the context's privilege and current label.
+    pub fn parse(input: &str, ctx: &SecurityContext) -> Result<Self, Error> {
+        Path::parse_relative(input, ctx).map(|(path, _)| path)
+    }
+
+    /// Like `parse`, but also returns whether the path starts with `~`, in
+    /// which case it starts at the root wherever it is resolved from
+    pub fn parse_relative(input: &str, ctx: &SecurityContext) -> Result<(Self, bool), Error> {
+        let mut parser = Parser { input, pos: 0, ctx };
+        while parser.peek() == Some(':') {
+            parser.bump();
+        }
+        let mut components = VecDeque::new();
+        if parser.at_end() {
+            return Ok((Path { components }, false));
+        }
+        let rest = &input[parser.pos..];
+        let home = rest == "~" || rest.starts_with("~:");
+        loop {
+            components.extend(parser.component(components.is_empty())?);
+            match parser.bump() {
+                None => return Ok((Path { components }, home)),
+                Some(':') if parser.at_end() => return Ok((Path { components }, home)),
+                Some(':') => (),
+                Some(c) => return Err(parser.error(ErrorKind::UnexpectedChar(c), parser.pos - c.len_utf8())),
             }
         }
-        Ok(Self { components })
     }
 
     /// The root is represented as an empty vector of path::Component's
@@ -87,6 +291,89 @@ impl Path {
     pub fn push_dscrp(&mut self, s: String) {
         self.components.push_back(PathComponent::Dscrp(s));
     }
+
+    pub fn push(&mut self, component: PathComponent) {
+        self.components.push_back(component);
+    }
+
+    pub fn last(&self) -> Option<&PathComponent> {
+        self.components.back()
+    }
+}
+
+fn fmt_component(component: &Component, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match component {
+        Component::DCFalse => f.write_char('F'),
+        Component::DCFormula(clauses) if clauses.is_empty() => f.write_char('T'),
+        Component::DCFormula(clauses) => {
+            for (i, clause) in clauses.iter().enumerate() {
+                if i > 0 {
+                    f.write_char('&')?;
+                }
+                for (j, principal) in clause.0.iter().enumerate() {
+                    if j > 0 {
+                        f.write_char('|')?;
+                    }
+                    for (k, token) in principal.iter().enumerate() {
+                        if k > 0 {
+                            f.write_char('/')?;
+                        }
+                        for c in token.chars() {
+                            if matches!(c, ',' | '&' | '|' | '/' | '\\' | '>') {
+                                f.write_char('\\')?;
+                            }
+                            f.write_char(c)?;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        },
+    }
+}
+
+impl fmt::Display for PathComponent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathComponent::Dscrp(name) => {
+                let plain = !name.is_empty()
+                    && name != "~"
+                    && name != "%"
+                    && !name.contains([':', '<', '>', '"', '\\']);
+                if plain {
+                    return f.write_str(name);
+                }
+                f.write_char('"')?;
+                for c in name.chars() {
+                    if c == '"' || c == '\\' {
+                        f.write_char('\\')?;
+                    }
+                    f.write_char(c)?;
+                }
+                f.write_char('"')
+            },
+            PathComponent::Facet(label) => {
+                f.write_char('<')?;
+                fmt_component(&label.secrecy, f)?;
+                f.write_char(',')?;
+                fmt_component(&label.integrity, f)?;
+                f.write_char('>')
+            },
+        }
+    }
+}
+
+/// Prints the path with a leading `:`, which is all the root prints as
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.components.is_empty() {
+            return f.write_char(':');
+        }
+        for component in self.components.iter() {
+            write!(f, ":{}", component)?;
+        }
+        Ok(())
+    }
 }
 
 impl IntoIterator for Path {
@@ -121,3 +408,77 @@ impl<'a> IntoIterator for &'a Path {
         Self { components }
     }
 }*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<Path, Error> {
+        Path::parse(input, &SecurityContext::default())
+    }
+
+    fn parse_error(input: &str) -> (ErrorKind, usize) {
+        let Error { kind, position } = parse(input).unwrap_err();
+        (kind, position)
+    }
+
+    #[test]
+    fn test_parse_print_round_trip() {
+        let ctx = SecurityContext::new(Buckle::parse("T,alice").unwrap().integrity);
+        let path = Path::parse(r#"~:"a:b":c\<d:"~":<T,faasten>:"":%"#, &ctx).unwrap();
+        assert_eq!(path.components, vec![
+            PathComponent::Dscrp("home".into()),
+            PathComponent::Facet(Buckle::parse("alice,alice").unwrap()),
+            PathComponent::Dscrp("a:b".into()),
+            PathComponent::Dscrp("c<d".into()),
+            PathComponent::Dscrp("~".into()),
+            PathComponent::Facet(Buckle::parse("T,faasten").unwrap()),
+            PathComponent::Dscrp("".into()),
+            PathComponent::Facet(Buckle::public()),
+        ]);
+        let printed = path.to_string();
+        assert_eq!(printed, r#":home:<alice,alice>:"a:b":"c<d":"~":<T,faasten>:"":<T,T>"#);
+        assert_eq!(parse(&printed).unwrap(), path);
+
+        assert_eq!(parse(":").unwrap(), Path::root());
+        assert_eq!(parse("").unwrap(), Path::root());
+        assert_eq!(Path::root().to_string(), ":");
+        assert_eq!(parse("home:x").unwrap(), parse(":home:x").unwrap());
+        assert_eq!(parse("::home:x::").unwrap(), parse(":home:x").unwrap());
+        assert_eq!(parse("home:x\\:").unwrap().file_name(), Some("x:".into()));
+        assert!(Path::parse_relative("~:x", &ctx).unwrap().1);
+        assert!(!Path::parse_relative(r"\~:x", &ctx).unwrap().1);
+    }
+
+    #[test]
+    fn test_escaped_facet_round_trip() {
+        let label = Buckle::new(
+            Component::DCFormula([Clause([vec!["a,b".to_string(), "c/d".into()], vec!["e|f>".into()]].into())].into()),
+            Component::DCFormula([Clause([vec!["g&h\\".to_string()]].into()), Clause([vec!["T".to_string()]].into())].into()),
+        );
+        let mut path = Path::root();
+        path.push(PathComponent::Facet(label.clone()));
+        let printed = path.to_string();
+        assert_eq!(printed, r":<a\,b/c\/d|e\|f\>,T&g\&h\\>");
+        assert_eq!(parse(&printed).unwrap(), path);
+        assert_eq!(parse("<alice|bob,T>").unwrap().last(), Some(&PathComponent::Facet(Buckle::parse("alice|bob,T").unwrap())));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse_error(":home::x"), (ErrorKind::EmptyComponent, 6));
+        assert_eq!(parse_error("a:\"b"), (ErrorKind::UnterminatedQuote, 2));
+        assert_eq!(parse_error("a:<T,x"), (ErrorKind::UnterminatedFacet, 2));
+        assert_eq!(parse_error("a:<>"), (ErrorKind::InvalidFacet, 3));
+        assert_eq!(parse_error("a:<x,y,z>"), (ErrorKind::InvalidFacet, 3));
+        assert_eq!(parse("a:<x\\,y,z>").unwrap().last(), Some(&PathComponent::Facet(Buckle::new(
+            Component::DCFormula([Clause([vec!["x,y".to_string()]].into())].into()),
+            Component::DCFormula([Clause([vec!["z".to_string()]].into())].into()),
+        ))));
+        assert_eq!(parse_error("a:\"b\"c"), (ErrorKind::UnexpectedChar('c'), 5));
+        assert_eq!(parse_error("a>b"), (ErrorKind::UnexpectedChar('>'), 1));
+        assert_eq!(parse_error("home:~"), (ErrorKind::MisplacedHome, 5));
+        assert_eq!(parse_error("a\\"), (ErrorKind::DanglingEscape, 1));
+        assert_eq!(parse("a:\"b").unwrap_err().to_string(), "unterminated quoted name at offset 2");
+    }
+}
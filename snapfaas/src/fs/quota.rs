@@ -0,0 +1,376 @@
+//! Per-principal storage quotas
+//!
+//! Objects and blob bytes are charged to the privilege of the context that
+//! creates them. File bytes, which include the keys and values of maps and
+//! the records of logs, are charged to the creator of the file, map or log,
+//! whoever writes it, and refunded when it shrinks. Charges are staged in the
+//! same transaction as the change they pay for.
+//!
+//! Usage is only tracked for principals with a quota, from the time their
+//! quota is set, so other principals never contend for an account. Objects
+//! and blobs charged to a quota are recorded, and the garbage collector
+//! refunds them, along with the file bytes of objects, when it deletes them.
+//!
+//! Writers that may not learn what they overwrite are charged the bytes they
+//! write before what they replace is refunded, so whether the quota is
+//! exceeded does not depend on the old contents of the object written. It
+//! does depend on the creator's usage, though, which sums the file bytes of
+//! everything the creator created, at any label. Whether a blind write fails
+//! with `FsError::QuotaExceeded` thus reveals to its writer something about
+//! objects it may not read, e.g., a high-secrecy file of the same creator
+//! growing. This channel is not closed, so quotas should only be set for
+//! principals that accept it. Errors about labels are always returned before
+//! `FsError::QuotaExceeded`.
+//!
+//! Accounts are stored in the backing store under `ACCOUNT_PREFIX`, and the
+//! records of charged objects and blobs under `OBJECT_CHARGE_PREFIX` and
+//! `BLOB_CHARGE_PREFIX`. These keys are never 8 bytes long, so they are never
+//! mistaken for objects.
+use super::*;
+
+const ACCOUNT_PREFIX: &[u8] = b"quota/";
+const OBJECT_CHARGE_PREFIX: &[u8] = b"quota-object/";
+const BLOB_CHARGE_PREFIX: &[u8] = b"quota-blob/";
+
+/// Limits of a principal's usage, where `None` is unlimited
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    pub objects: Option<u64>,
+    pub file_bytes: Option<u64>,
+    pub blob_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub objects: u64,
+    pub file_bytes: u64,
+    pub blob_bytes: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Account {
+    quota: Quota,
+    usage: Usage,
+}
+
+/// A change of usage. Negative amounts are refunds.
+#[derive(Debug, Default, Clone, Copy)]
+pub(super) struct Charge {
+    pub objects: i64,
+    pub file_bytes: i64,
+    pub blob_bytes: i64,
+    /// File bytes that must fit in the quota before `file_bytes` is applied,
+    /// for blind writes, which may refund bytes the writer must not learn of
+    pub file_bytes_written: u64,
+}
+
+impl Charge {
+    pub(super) const OBJECT: Charge = Charge { objects: 1, file_bytes: 0, blob_bytes: 0, file_bytes_written: 0 };
+
+    /// Replacing `old` file bytes with `new` ones
+    pub(super) fn file_bytes(old: u64, new: u64) -> Self {
+        Charge { file_bytes: new as i64 - old as i64, ..Default::default() }
+    }
+
+    /// Like `file_bytes`, for writers that may not learn `old`, where the
+    /// `written` bytes must fit in the quota before `old` is refunded
+    pub(super) fn blind_file_bytes(old: u64, new: u64, written: u64) -> Self {
+        Charge { file_bytes_written: written, ..Charge::file_bytes(old, new) }
+    }
+
+    fn is_zero(&self) -> bool {
+        self.objects == 0 && self.file_bytes == 0 && self.blob_bytes == 0 && self.file_bytes_written == 0
+    }
+
+    fn refund(&self) -> Self {
+        Charge { objects: -self.objects, file_bytes: -self.file_bytes, blob_bytes: -self.blob_bytes, file_bytes_written: 0 }
+    }
+}
+
+/// What the creation of an object was charged for, and thus what to refund
+/// when it is deleted
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(super) enum Charged {
+    Object,
+    /// An object whose file bytes are charged to its creator
+    File,
+    Map,
+    Log,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ObjectCharge {
+    principal: Component,
+    charged: Charged,
+}
+
+impl Account {
+    /// Applies `charge`, or fails if it increases usage beyond the quota.
+    /// Refunds always succeed.
+    fn apply(&mut self, charge: &Charge) -> Result<(), FsError> {
+        fn apply_one(used: &mut u64, delta: i64, written: u64, limit: Option<u64>) -> Result<(), FsError> {
+            let new = if delta >= 0 {
+                used.saturating_add(delta as u64)
+            } else {
+                used.saturating_sub(delta.unsigned_abs())
+            };
+            let needed = written.max(delta.max(0) as u64);
+            if needed > 0 && limit.is_some_and(|limit| used.saturating_add(needed) > limit) {
+                return Err(FsError::QuotaExceeded);
+            }
+            *used = new;
+            Ok(())
+        }
+        apply_one(&mut self.usage.objects, charge.objects, 0, self.quota.objects)?;
+        apply_one(&mut self.usage.file_bytes, charge.file_bytes, charge.file_bytes_written, self.quota.file_bytes)?;
+        apply_one(&mut self.usage.blob_bytes, charge.blob_bytes, 0, self.quota.blob_bytes)
+    }
+}
+
+fn account_key(principal: &Component) -> Vec<u8> {
+    let mut key = ACCOUNT_PREFIX.to_vec();
+    key.extend(serde_json::to_vec(principal).expect("serialize principal"));
+    key
+}
+
+fn object_charge_key(uid: u64) -> Vec<u8> {
+    [OBJECT_CHARGE_PREFIX, &uid.to_be_bytes()].concat()
+}
+
+fn blob_charge_key(blob: &str) -> Vec<u8> {
+    [BLOB_CHARGE_PREFIX, blob.as_bytes()].concat()
+}
+
+impl<S: BackingStore> FS<S> {
+    /// Returns the quota and usage of `principal`
+    pub fn quota(&self, principal: &Component) -> (Quota, Usage) {
+        let account = self.account(principal).1.unwrap_or_default();
+        (account.quota, account.usage)
+    }
+
+    /// Sets the quota of `principal`, keeping its usage. Lowering the quota
+    /// below the usage only prevents further charges.
+    pub fn set_quota(&self, principal: &Component, quota: Quota) {
+        self.update_account(principal, |account| account.quota = quota.clone())
+    }
+
+    /// Resets the usage of `principal`, e.g., to correct the usage of objects
+    /// created before its quota was set
+    pub fn reset_usage(&self, principal: &Component, usage: Usage) {
+        self.update_account(principal, |account| account.usage = usage.clone())
+    }
+
+    /// Charges `len` bytes of the saved blob `blob` to the context's
+    /// privilege, or returns `FsError::QuotaExceeded`
+    pub fn charge_blob_bytes(&self, blob: &str, len: u64, ctx: &SecurityContext) -> Result<(), FsError> {
+        let principal = ctx.privilege();
+        let charge = Charge { blob_bytes: len as i64, ..Default::default() };
+        let key = blob_charge_key(blob);
+        loop {
+            let mut txn = Transaction::default();
+            if self.stage_charge(principal, &charge, &mut txn)? {
+                let prev = self.0.get(&key);
+                let mut charges: Vec<(Component, u64)> = prev.as_deref().and_then(encoding::decode).unwrap_or_default();
+                charges.push((principal.clone(), len));
+                txn.check(&key, prev.as_deref());
+                txn.put(&key, encoding::encode(&charges).as_slice());
+            }
+            if self.0.commit(&txn) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Refunds what saving the blob `blob` was charged, once it is deleted
+    pub fn refund_blob(&self, blob: &str) {
+        let key = blob_charge_key(blob);
+        loop {
+            let Some(prev) = self.0.get(&key) else {
+                return;
+            };
+            let charges: Vec<(Component, u64)> = encoding::decode(&prev).unwrap_or_default();
+            let mut refunds: Vec<(Component, u64)> = Vec::new();
+            for (principal, len) in charges {
+                match refunds.iter_mut().find(|(p, _)| *p == principal) {
+                    Some((_, total)) => *total += len,
+                    None => refunds.push((principal, len)),
+                }
+            }
+            let mut txn = Transaction::default();
+            for (principal, len) in refunds {
+                let refund = Charge { blob_bytes: -(len as i64), ..Default::default() };
+                self.stage_charge(&principal, &refund, &mut txn).expect("refunds always succeed");
+            }
+            txn.check(&key, Some(&prev));
+            txn.del(&key);
+            if self.0.commit(&txn) {
+                return;
+            }
+        }
+    }
+
+    /// Adds charging `principal` for the creation of the object `uid` with
+    /// `file_bytes` to `txn`, recording the charge for its refund, or returns
+    /// `FsError::QuotaExceeded`
+    pub(super) fn stage_object_charge(
+        &self,
+        principal: &Component,
+        uid: u64,
+        charged: Charged,
+        file_bytes: u64,
+        txn: &mut Transaction,
+    ) -> Result<(), FsError> {
+        let charge = Charge { file_bytes: file_bytes as i64, ..Charge::OBJECT };
+        if self.stage_charge(principal, &charge, txn)? {
+            let key = object_charge_key(uid);
+            txn.check(&key, None);
+            txn.put(&key, encoding::encode(&ObjectCharge { principal: principal.clone(), charged }).as_slice());
+        }
+        Ok(())
+    }
+
+    /// Adds charging `principal` to `txn`, or returns `FsError::QuotaExceeded`.
+    /// Returns whether anything is charged, which it is not if `principal`
+    /// has no quota. Committing `txn` fails if the account changes in the
+    /// meantime.
+    pub(super) fn stage_charge(&self, principal: &Component, charge: &Charge, txn: &mut Transaction) -> Result<bool, FsError> {
+        if charge.is_zero() {
+            return Ok(false);
+        }
+        let key = account_key(principal);
+        let (prev, account) = self.account(principal);
+        let Some(mut account) = account else {
+            txn.check(&key, None);
+            return Ok(false);
+        };
+        account.apply(charge)?;
+        txn.check(&key, prev.as_deref());
+        txn.put(&key, encoding::encode(&account).as_slice());
+        Ok(true)
+    }
+
+    /// Deletes the object `uid`, which must be unreachable, refunding what its
+    /// creation was charged and the file bytes it holds in the same transaction
+    pub(super) fn delete_object(&self, uid: u64) {
+        let key = uid.to_be_bytes();
+        let charge_key = object_charge_key(uid);
+        loop {
+            let mut txn = Transaction::default();
+            let prev = self.0.get(&key);
+            txn.check(&key, prev.as_deref());
+            txn.del(&key);
+            let charge = self.0.get(&charge_key);
+            if let Some(ObjectCharge { principal, charged }) = charge.as_deref().and_then(encoding::decode) {
+                let file_bytes = match charged {
+                    Charged::Object => 0,
+                    Charged::File => ObjectRef::<Labeled<File>>::new(uid).get(self).map_or(0, |file| file.data.len()),
+                    Charged::Map => ObjectRef::<Labeled<Map>>::new(uid).get(self).map_or(0, |map| map_size(&map.data)),
+                    Charged::Log => ObjectRef::<Labeled<Log>>::new(uid).get(self).map_or(0, |log| log.data.size()),
+                };
+                let refund = Charge { file_bytes: file_bytes as i64, ..Charge::OBJECT }.refund();
+                self.stage_charge(&principal, &refund, &mut txn).expect("refunds always succeed");
+            }
+            txn.check(&charge_key, charge.as_deref());
+            if charge.is_some() {
+                txn.del(&charge_key);
+            }
+            if self.0.commit(&txn) {
+                return;
+            }
+        }
+    }
+
+    fn update_account<F: Fn(&mut Account)>(&self, principal: &Component, f: F) {
+        let key = account_key(principal);
+        loop {
+            let (prev, account) = self.account(principal);
+            let mut account = account.unwrap_or_default();
+            f(&mut account);
+            let mut txn = Transaction::default();
+            txn.check(&key, prev.as_deref());
+            txn.put(&key, encoding::encode(&account).as_slice());
+            if self.0.commit(&txn) {
+                return;
+            }
+        }
+    }
+
+    /// Returns the stored account of `principal`, if it has a quota
+    fn account(&self, principal: &Component) -> (Option<Vec<u8>>, Option<Account>) {
+        let prev = self.0.get(&account_key(principal));
+        let account = prev.as_deref().map(|prev| encoding::decode(prev).unwrap_or_default());
+        (prev, account)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+
+    #[test]
+    fn test_quota() {
+        let fs = FS::new(MemoryStore::new());
+        fs.initialize();
+        let mut root = SecurityContext::new(Component::dc_false());
+        let home = Path::parse(":home", &root).unwrap();
+        fs.create_and_link(Path::root(), "home".into(), NewEntry::Directory(Buckle::public()), &mut root).unwrap();
+
+        let alice = Buckle::parse("T,alice").unwrap().integrity;
+        fs.set_quota(&alice, Quota { objects: Some(2), file_bytes: Some(10), blob_bytes: None });
+        let mut ctx = SecurityContext::new(alice.clone());
+        let file = fs.create_and_link(home.clone(), "f".into(), NewEntry::File(Buckle::public(), b"hello".to_vec()), &mut ctx).unwrap();
+        let res = fs.create_and_link(home.clone(), "g".into(), NewEntry::File(Buckle::public(), b"world!".to_vec()), &mut ctx);
+        assert!(matches!(res, Err(FsError::QuotaExceeded)));
+        assert!(!fs.list_dir(home.clone(), &mut ctx).unwrap().contains_key("g"));
+
+        let DirEntry::File(file) = file else { panic!("not a file") };
+        assert!(matches!(file.append(b" world", &fs, &ctx), Err(FsError::QuotaExceeded)));
+        file.truncate(1, &fs, &ctx).unwrap();
+        file.append(b"i there", &fs, &ctx).unwrap();
+        fs.create_directory(Buckle::public(), &ctx).unwrap();
+        assert!(matches!(fs.create_directory(Buckle::public(), &ctx), Err(FsError::QuotaExceeded)));
+        assert_eq!(fs.quota(&alice).1, Usage { objects: 2, file_bytes: 8, blob_bytes: 0 });
+
+        // file bytes are charged to the creator, not the writer
+        assert!(matches!(file.append(b"!!!", &fs, &root), Err(FsError::QuotaExceeded)));
+        // blind writes must fit without refunding what they replace
+        assert!(matches!(file.write(b"123".to_vec(), &fs, &ctx), Err(FsError::QuotaExceeded)));
+        // principals without a quota are not accounted for
+        assert!(fs.0.get(&account_key(root.privilege())).is_none());
+    }
+
+    #[test]
+    fn test_refunds() {
+        let fs = FS::new(MemoryStore::new());
+        fs.initialize();
+        let mut root = SecurityContext::new(Component::dc_false());
+        let home = Path::parse(":home", &root).unwrap();
+        fs.create_and_link(Path::root(), "home".into(), NewEntry::Directory(Buckle::parse("T,bob").unwrap()), &mut root).unwrap();
+
+        let alice = Buckle::parse("T,alice").unwrap().integrity;
+        fs.set_quota(&alice, Quota { objects: Some(1), file_bytes: None, blob_bytes: Some(10) });
+        let mut ctx = SecurityContext::new(alice.clone());
+        // label errors come first, as the directory is not writable
+        let res = fs.create_and_link(home.clone(), "f".into(), NewEntry::File(Buckle::public(), b"hello".to_vec()), &mut ctx);
+        assert!(matches!(res, Err(FsError::LabelError(_))));
+        fs.create_faceted_directory(&ctx).unwrap();
+        let res = fs.create_and_link(home, "f".into(), NewEntry::File(Buckle::public(), b"hello".to_vec()), &mut ctx);
+        assert!(matches!(res, Err(FsError::LabelError(_))));
+        assert!(matches!(fs.create_file(Buckle::public(), &ctx), Err(FsError::QuotaExceeded)));
+        assert_eq!(fs.collect_garbage().unwrap().len(), 1);
+        let DirEntry::File(file) = fs.create_file(Buckle::public(), &ctx).unwrap() else { panic!("not a file") };
+        file.write(b"hello".to_vec(), &fs, &ctx).unwrap();
+        assert_eq!(fs.quota(&alice).1, Usage { objects: 1, file_bytes: 5, blob_bytes: 0 });
+        fs.collect_garbage().unwrap();
+        assert_eq!(fs.quota(&alice).1, Usage::default());
+
+        fs.charge_blob_bytes("blob", 6, &ctx).unwrap();
+        assert!(matches!(fs.charge_blob_bytes("blob", 6, &ctx), Err(FsError::QuotaExceeded)));
+        fs.charge_blob_bytes("blob", 4, &ctx).unwrap();
+        fs.refund_blob("blob");
+        assert_eq!(fs.quota(&alice).1, Usage::default());
+        // only the root, home and the account are left
+        assert_eq!(fs.0.scan(&[], None, usize::MAX).len(), 3);
+    }
+}
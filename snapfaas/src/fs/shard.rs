@@ -0,0 +1,521 @@
+//! Store combinators for scaling out without a distributed store
+//!
+//! `ShardedStore` spreads keys over several stores, e.g., LMDB environments on
+//! different disks, by a stable hash of the key. Objects are placed by their
+//! uids, so the objects a transaction touches, e.g., a directory and a new
+//! file, are usually on different shards. Such transactions are committed
+//! with two-phase commit:
+//!
+//! 1. Each shard the transaction touches checks its part of the transaction
+//!    and locks every key it checks or writes, in a single commit of the
+//!    shard. A lock is stored next to its key under `LOCK_PREFIX` and holds
+//!    the transaction's id, when it was taken and the key's new value.
+//! 2. The transaction commits once a decision record is added under
+//!    `DECISION_PREFIX` and its id. Then each shard writes the new values and
+//!    drops the locks, and the decision record is deleted.
+//!
+//! Reads of a locked key return its new value if the transaction committed,
+//! and every other operation on a locked key waits for the lock to be
+//! dropped, finishing the writes of committed transactions itself. A lock of
+//! an undecided transaction older than `LOCK_TIMEOUT` is presumed abandoned by
+//! a crashed process, and is dropped after deciding the transaction aborted,
+//! which fails the transaction if its process is merely slow. A transaction
+//! is thus atomic with respect to every process using the store, and
+//! survives crashes. Transactions within a single shard are as atomic as the
+//! shard.
+//!
+//! `ReplicatedStore` synchronously copies every key written to a replica as
+//! well, and reads from the primary. With read repair, reads also read the
+//! replica and overwrite it if it differs, e.g., after it missed writes while
+//! it was being restored.
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::time::Duration;
+
+use super::{now_millis, BackingStore, Transaction};
+
+/// Prefix of every key the two-phase commit keeps its records under
+const RECORD_PREFIX: &[u8] = b"\xffshard/";
+/// Just past every key starting with `RECORD_PREFIX`
+const RECORD_END: &[u8] = b"\xffshard0";
+const LOCK_PREFIX: &[u8] = b"\xffshard/lock/";
+const DECISION_PREFIX: &[u8] = b"\xffshard/txn/";
+const COMMITTED: &[u8] = b"commit";
+const ABORTED: &[u8] = b"abort";
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is stable across builds, as
+/// key placement must be
+fn fnv1a(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn lock_key(key: &[u8]) -> Vec<u8> {
+    [LOCK_PREFIX, key].concat()
+}
+
+fn decision_key(txn: u128) -> Vec<u8> {
+    [DECISION_PREFIX, &txn.to_be_bytes()].concat()
+}
+
+/// A key's lock, taken by the transaction `txn`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Lock {
+    txn: u128,
+    /// Milliseconds since the UNIX epoch
+    taken: u64,
+    /// The key's new value, if the transaction writes it
+    write: Option<Option<Vec<u8>>>,
+}
+
+impl Lock {
+    fn encode(&self) -> Vec<u8> {
+        let (tag, value): (u8, &[u8]) = match &self.write {
+            None => (0, &[]),
+            Some(Some(value)) => (1, value),
+            Some(None) => (2, &[]),
+        };
+        [&self.txn.to_be_bytes()[..], &self.taken.to_be_bytes(), &[tag], value].concat()
+    }
+
+    fn decode(bs: &[u8]) -> Option<Lock> {
+        let txn = u128::from_be_bytes(bs.get(..16)?.try_into().ok()?);
+        let taken = u64::from_be_bytes(bs.get(16..24)?.try_into().ok()?);
+        let write = match (bs.get(24)?, &bs[25..]) {
+            (0, _) => None,
+            (1, value) => Some(Some(value.to_vec())),
+            (2, _) => Some(None),
+            _ => return None,
+        };
+        Some(Lock { txn, taken, write })
+    }
+
+    fn is_stale(&self) -> bool {
+        now_millis().saturating_sub(self.taken) > LOCK_TIMEOUT.as_millis() as u64
+    }
+}
+
+/// The keys a transaction locked on a shard, with their locks encoded
+type Held<'a> = Vec<(&'a [u8], Vec<u8>)>;
+
+#[derive(Debug, Clone)]
+pub struct ShardedStore<S> {
+    shards: Vec<S>,
+}
+
+impl<S: BackingStore> ShardedStore<S> {
+    /// Panics if `shards` is empty. Keys are placed by the number of shards,
+    /// so a store must always be opened with the same shards in the same order.
+    pub fn new(shards: Vec<S>) -> Self {
+        assert!(!shards.is_empty(), "a sharded store needs at least one shard");
+        ShardedStore { shards }
+    }
+
+    /// Locks are placed with the keys they lock
+    fn shard_index(&self, key: &[u8]) -> usize {
+        let key = key.strip_prefix(LOCK_PREFIX).unwrap_or(key);
+        (fnv1a(key) % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, key: &[u8]) -> &S {
+        &self.shards[self.shard_index(key)]
+    }
+
+    fn decision(&self, txn: u128) -> Option<Vec<u8>> {
+        let key = decision_key(txn);
+        self.shard(&key).get(&key)
+    }
+
+    /// Decides `txn` as `decision` unless it is decided already, and returns
+    /// the decision
+    fn decide(&self, txn: u128, decision: &[u8]) -> Vec<u8> {
+        let key = decision_key(txn);
+        let shard = self.shard(&key);
+        loop {
+            if shard.add(&key, decision) {
+                return decision.to_vec();
+            }
+            if let Some(decided) = shard.get(&key) {
+                return decided;
+            }
+        }
+    }
+
+    /// Drops the lock of `key`, if any, once its transaction is decided,
+    /// writing the key first if it committed. Returns false if the lock is
+    /// held by an undecided transaction that is not stale.
+    fn settle(&self, key: &[u8]) -> bool {
+        let shard = self.shard(key);
+        let lock_key = lock_key(key);
+        let Some(stored) = shard.get(&lock_key) else {
+            return true;
+        };
+        let lock = Lock::decode(&stored).unwrap_or_else(|| panic!("corrupted lock of key {}", hex::encode(key)));
+        let decision = match self.decision(lock.txn) {
+            Some(decision) => decision,
+            None if lock.is_stale() => self.decide(lock.txn, ABORTED),
+            None => return false,
+        };
+        let mut txn = Transaction::default();
+        txn.check(&lock_key, Some(&stored));
+        txn.del(&lock_key);
+        match lock.write {
+            Some(Some(value)) if decision == COMMITTED => txn.put(key, &value),
+            Some(None) if decision == COMMITTED => txn.del(key),
+            _ => (),
+        }
+        shard.commit(&txn);
+        true
+    }
+
+    /// Waits for the locks of `keys`, if any, to be dropped
+    fn wait_unlocked<'a, I: IntoIterator<Item = &'a [u8]>>(&self, keys: I) {
+        for key in keys {
+            while !self.settle(key) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    fn is_locked(&self, key: &[u8]) -> bool {
+        self.shard(key).get(&lock_key(key)).is_some()
+    }
+
+    /// Commits `txn`, whose keys are all on shard `index`, unless one of them
+    /// is locked, in which case it waits for the locks and retries
+    fn commit_unlocked(&self, index: usize, txn: &Transaction) -> bool {
+        let keys: Vec<&[u8]> = txn.checks().iter().chain(txn.writes()).map(|(key, _)| key.as_slice()).collect();
+        let mut guarded = txn.clone();
+        for key in keys.iter() {
+            guarded.check(&lock_key(key), None);
+        }
+        loop {
+            if self.shards[index].commit(&guarded) {
+                return true;
+            }
+            if !keys.iter().any(|key| self.is_locked(key)) {
+                return false;
+            }
+            self.wait_unlocked(keys.iter().copied());
+        }
+    }
+
+    /// Commits a transaction spanning shards with two-phase commit
+    fn commit_spanning(&self, txn: &Transaction) -> bool {
+        let id: u128 = rand::random();
+        let taken = now_millis();
+        let mut locks: BTreeMap<&[u8], Lock> = BTreeMap::new();
+        for (key, _) in txn.checks() {
+            locks.entry(key).or_insert(Lock { txn: id, taken, write: None });
+        }
+        for (key, value) in txn.writes() {
+            locks.entry(key).or_insert(Lock { txn: id, taken, write: None }).write = Some(value.clone());
+        }
+        let mut parts: BTreeMap<usize, (Transaction, Held)> = BTreeMap::new();
+        for (key, expected) in txn.checks() {
+            parts.entry(self.shard_index(key)).or_default().0.check(key, expected.as_deref());
+        }
+        for (key, lock) in locks.iter() {
+            let (prepare, held) = parts.entry(self.shard_index(key)).or_default();
+            let encoded = lock.encode();
+            prepare.check(&lock_key(key), None);
+            prepare.put(&lock_key(key), &encoded);
+            held.push((key, encoded));
+        }
+
+        let mut prepared = Vec::new();
+        for (index, (prepare, held)) in parts.iter() {
+            if !self.shards[*index].commit(prepare) {
+                self.unlock(prepared, false);
+                // the transaction conflicted with another one, which is
+                // finished or aborted before retrying
+                self.wait_unlocked(held.iter().map(|(key, _)| *key));
+                return false;
+            }
+            prepared.push((*index, held));
+        }
+        if self.decide(id, COMMITTED) != COMMITTED {
+            self.unlock(prepared, false);
+            let key = decision_key(id);
+            self.shard(&key).del(&key);
+            return false;
+        }
+        self.unlock(prepared, true);
+        let key = decision_key(id);
+        self.shard(&key).del(&key);
+        true
+    }
+
+    /// Drops the locks `held` on each shard, after writing the new values of
+    /// the keys if `committed`
+    fn unlock(&self, held: Vec<(usize, &Held)>, committed: bool) {
+        for (index, held) in held {
+            let mut txn = Transaction::default();
+            for (key, encoded) in held.iter() {
+                txn.check(&lock_key(key), Some(encoded));
+                txn.del(&lock_key(key));
+                match Lock::decode(encoded).and_then(|lock| lock.write) {
+                    Some(Some(value)) if committed => txn.put(key, &value),
+                    Some(None) if committed => txn.del(key),
+                    _ => (),
+                }
+            }
+            // fails only if another process settled the locks already
+            self.shards[index].commit(&txn);
+        }
+    }
+}
+
+impl<S: BackingStore> BackingStore for ShardedStore<S> {
+    /// The lock is read before the key, so a transaction that committed by
+    /// then is seen whole
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let shard = self.shard(key);
+        if let Some(Lock { txn, write: Some(value), .. }) = shard.get(&lock_key(key)).and_then(|lock| Lock::decode(&lock)) {
+            if self.decision(txn).as_deref() == Some(COMMITTED) {
+                return value;
+            }
+        }
+        shard.get(key)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) {
+        let mut txn = Transaction::default();
+        txn.put(key, value);
+        self.commit_unlocked(self.shard_index(key), &txn);
+    }
+
+    fn add(&self, key: &[u8], value: &[u8]) -> bool {
+        let mut txn = Transaction::default();
+        txn.check(key, None);
+        txn.put(key, value);
+        self.commit_unlocked(self.shard_index(key), &txn)
+    }
+
+    fn cas(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<(), Option<Vec<u8>>> {
+        let mut txn = Transaction::default();
+        txn.check(key, expected);
+        txn.put(key, value);
+        if self.commit_unlocked(self.shard_index(key), &txn) {
+            Ok(())
+        } else {
+            Err(self.get(key))
+        }
+    }
+
+    fn del(&self, key: &[u8]) {
+        let mut txn = Transaction::default();
+        txn.del(key);
+        self.commit_unlocked(self.shard_index(key), &txn);
+    }
+
+    /// Skips the records of the two-phase commit. Keys written by a
+    /// transaction spanning shards show up once each shard has written them.
+    fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        // the first `limit` keys overall are among the first `limit` of each shard
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        for shard in self.shards.iter() {
+            if start < RECORD_PREFIX {
+                let before = end.filter(|end| *end < RECORD_PREFIX).unwrap_or(RECORD_PREFIX);
+                pairs.extend(shard.scan(start, Some(before), limit));
+            }
+            if end.is_none_or(|end| end > RECORD_END) {
+                pairs.extend(shard.scan(start.max(RECORD_END), end, limit));
+            }
+        }
+        pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        pairs.truncate(limit);
+        pairs
+    }
+
+    fn commit(&self, txn: &Transaction) -> bool {
+        let mut indices = txn.checks().iter().chain(txn.writes()).map(|(key, _)| self.shard_index(key));
+        let first = indices.next();
+        if indices.all(|index| Some(index) == first) {
+            return first.is_none_or(|index| self.commit_unlocked(index, txn));
+        }
+        self.commit_spanning(txn)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplicatedStore<S> {
+    primary: S,
+    replica: Option<S>,
+    read_repair: bool,
+}
+
+impl<S: BackingStore> ReplicatedStore<S> {
+    /// Replicates `primary` to `replica`, if any
+    pub fn new(primary: S, replica: Option<S>) -> Self {
+        ReplicatedStore { primary, replica, read_repair: false }
+    }
+
+    pub fn with_read_repair(mut self) -> Self {
+        self.read_repair = true;
+        self
+    }
+
+    /// Copies the primary's value of `key` to the replica until both hold the
+    /// same value. Every writer copies the keys it wrote, and checks again
+    /// after changing the replica, so whichever copies last copies the latest
+    /// value, however writers interleave.
+    fn sync(primary: &S, replica: &S, key: &[u8]) {
+        loop {
+            let value = primary.get(key);
+            let found = replica.get(key);
+            if found == value {
+                return;
+            }
+            let mut txn = Transaction::default();
+            txn.check(key, found.as_deref());
+            match value {
+                Some(value) => txn.put(key, &value),
+                None => txn.del(key),
+            }
+            replica.commit(&txn);
+        }
+    }
+
+    fn replicate(&self, key: &[u8]) {
+        if let Some(replica) = self.replica.as_ref() {
+            Self::sync(&self.primary, replica, key);
+        }
+    }
+}
+
+impl<S: BackingStore> BackingStore for ReplicatedStore<S> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.primary.get(key);
+        if let Some(replica) = self.replica.as_ref().filter(|_| self.read_repair) {
+            if replica.get(key) != value {
+                Self::sync(&self.primary, replica, key);
+            }
+        }
+        value
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) {
+        self.primary.put(key, value);
+        self.replicate(key);
+    }
+
+    fn add(&self, key: &[u8], value: &[u8]) -> bool {
+        let added = self.primary.add(key, value);
+        if added {
+            self.replicate(key);
+        }
+        added
+    }
+
+    fn cas(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<(), Option<Vec<u8>>> {
+        let res = self.primary.cas(key, expected, value);
+        if res.is_ok() {
+            self.replicate(key);
+        }
+        res
+    }
+
+    fn del(&self, key: &[u8]) {
+        self.primary.del(key);
+        self.replicate(key);
+    }
+
+    fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.primary.scan(start, end, limit)
+    }
+
+    fn commit(&self, txn: &Transaction) -> bool {
+        if !self.primary.commit(txn) {
+            return false;
+        }
+        for (key, _) in txn.writes() {
+            self.replicate(key);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+
+    #[test]
+    fn test_sharded_store() {
+        let shards = vec![MemoryStore::new(), MemoryStore::new(), MemoryStore::new()];
+        let store = ShardedStore::new(shards.clone());
+        for i in 0u64..30 {
+            store.put(&i.to_be_bytes(), b"v");
+        }
+        assert!(shards.iter().all(|shard| !shard.scan(&[], None, 1).is_empty()));
+        let keys: Vec<Vec<u8>> = store.scan(&5u64.to_be_bytes(), Some(&25u64.to_be_bytes()), 10)
+            .into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, (5u64..15).map(|i| i.to_be_bytes().to_vec()).collect::<Vec<_>>());
+
+        // a transaction spanning shards whose last part fails is undone
+        let (a, b) = (0u64.to_be_bytes(), (1u64..30).map(u64::to_be_bytes)
+            .find(|b| store.shard_index(b) > store.shard_index(&0u64.to_be_bytes())).unwrap());
+        let mut txn = Transaction::default();
+        txn.put(&a, b"new");
+        txn.check(&b, Some(b"other"));
+        txn.put(&b, b"new");
+        assert!(!store.commit(&txn));
+        assert_eq!(store.get(&a), Some(b"v".to_vec()));
+        let mut txn = Transaction::default();
+        txn.put(&a, b"new");
+        txn.check(&b, Some(b"v"));
+        txn.del(&b);
+        assert!(store.commit(&txn));
+        assert_eq!((store.get(&a), store.get(&b)), (Some(b"new".to_vec()), None));
+        assert!(shards.iter().all(|shard| shard.scan(RECORD_PREFIX, Some(RECORD_END), 1).is_empty()));
+
+        // a committed transaction whose process crashed before writing `a` is
+        // seen whole, and finished by the next write
+        let lock = |write: Option<Option<Vec<u8>>>, taken| Lock { txn: 1, taken, write }.encode();
+        store.shard(&a).put(&lock_key(&a), &lock(Some(Some(b"committed".to_vec())), now_millis()));
+        store.shard(&decision_key(1)).put(&decision_key(1), COMMITTED);
+        assert_eq!(store.get(&a), Some(b"committed".to_vec()));
+        assert_eq!(store.cas(&a, Some(b"committed"), b"next"), Ok(()));
+        assert_eq!(store.get(&a), Some(b"next".to_vec()));
+        assert_eq!(store.scan(&a, Some(&1u64.to_be_bytes()), 10), vec![(a.to_vec(), b"next".to_vec())]);
+
+        // an undecided transaction abandoned by a crashed process is aborted
+        let taken = now_millis() - 2 * LOCK_TIMEOUT.as_millis() as u64;
+        store.shard(&a).put(&lock_key(&a), &lock(Some(None), taken));
+        store.shard(&decision_key(1)).del(&decision_key(1));
+        assert_eq!(store.get(&a), Some(b"next".to_vec()));
+        store.put(&a, b"after");
+        assert_eq!(store.get(&a), Some(b"after".to_vec()));
+        assert_eq!(store.shard(&decision_key(1)).get(&decision_key(1)), Some(ABORTED.to_vec()));
+    }
+
+    #[test]
+    fn test_replicated_store() {
+        let (primary, replica) = (MemoryStore::new(), MemoryStore::new());
+        let store = ReplicatedStore::new(primary.clone(), Some(replica.clone()));
+        assert!(store.add(b"a", b"1"));
+        assert_eq!(store.cas(b"a", Some(b"1"), b"2"), Ok(()));
+        let mut txn = Transaction::default();
+        txn.check(b"a", Some(b"2"));
+        txn.put(b"b", b"3");
+        assert!(store.commit(&txn));
+        assert_eq!(replica.scan(&[], None, 10), primary.scan(&[], None, 10));
+
+        replica.del(b"b");
+        assert_eq!(store.get(b"b"), Some(b"3".to_vec()));
+        assert_eq!(replica.get(b"b"), None);
+        let store = store.with_read_repair();
+        assert_eq!(store.get(b"b"), Some(b"3".to_vec()));
+        assert_eq!(replica.get(b"b"), Some(b"3".to_vec()));
+
+        // the replica gets the primary's latest value, even if a concurrent
+        // writer's older value reached it in between
+        primary.put(b"a", b"4");
+        store.cas(b"a", Some(b"4"), b"5").unwrap();
+        assert_eq!(replica.get(b"a"), Some(b"5".to_vec()));
+        replica.put(b"a", b"4");
+        store.replicate(b"a");
+        assert_eq!(replica.get(b"a"), Some(b"5".to_vec()));
+    }
+}
@@ -0,0 +1,223 @@
+//! Change notifications
+//!
+//! A subscription attaches a gate to a directory or file. Linking or unlinking
+//! entries of a subscribed directory, or writing a subscribed file, queues a
+//! `Notification` for each of its gates on the writer's `SecurityContext`.
+//! The syscall server takes them with `SecurityContext::take_notifications`
+//! and invokes the gates asynchronously, labeled with the writer's label, so
+//! the invocation learns no more than the writer could tell it.
+//!
+//! Invocations caused by notifications can change subscribed objects in turn,
+//! possibly their own. Notifications thus count how many led to them, and
+//! changes more than `MAX_NOTIFICATION_DEPTH` deep notify no one.
+//!
+//! Subscriptions are stored under `SUBSCRIPTION_PREFIX` followed by the uid of
+//! the object, which is never 8 bytes long, and deleted along with the object
+//! by the garbage collector. They do not keep their gates reachable.
+use super::*;
+
+const SUBSCRIPTION_PREFIX: &[u8] = b"subscriptions/";
+
+pub const MAX_NOTIFICATION_DEPTH: u32 = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub gate: ObjectRef<Labeled<Gate>>,
+    /// Privilege of the context that subscribed, which must imply the gate's
+    /// invoker integrity clearance
+    pub subscriber: Component,
+}
+
+/// A change to a subscribed object, which is the payload of the notification
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event {
+    Link { name: String },
+    Unlink { name: String },
+    Write,
+}
+
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub subscription: Subscription,
+    /// The writer's label right after the change
+    pub label: Buckle,
+    pub event: Event,
+    /// How many notifications led to this one, including itself
+    pub depth: u32,
+}
+
+impl Notification {
+    /// Resolves the gate to invoke and the label to invoke it with, which is
+    /// the writer's label tainted by the gates resolved on the way, in a
+    /// context of its own with an unbounded clearance.
+    pub fn resolve<B: BackingStore>(&self, fs: &FS<B>) -> Result<(DirectGate, Buckle), FsError> {
+        let mut ctx = SecurityContext::with_label(self.label.clone(), self.subscription.subscriber.clone());
+        let gate = self.subscription.gate.to_invokable(fs, &mut ctx)?;
+        if !self.subscription.subscriber.implies(&gate.invoker_integrity_clearance) {
+            return Err(FsError::GateError(GateError::CannotInvoke));
+        }
+        Ok((gate, ctx.label().clone()))
+    }
+}
+
+fn subscription_key(uid: u64) -> Vec<u8> {
+    let mut key = SUBSCRIPTION_PREFIX.to_vec();
+    key.extend(uid.to_be_bytes());
+    key
+}
+
+/// Returns the uid and label of a directory or file
+fn subscribable<B: BackingStore>(target: &DirEntry, fs: &FS<B>) -> Result<(u64, Buckle), FsError> {
+    match target {
+        DirEntry::Directory(dir) => Ok((dir.uid, dir.get(fs)?.label().clone())),
+        DirEntry::File(file) => Ok((file.uid, file.get(fs)?.label().clone())),
+        _ => Err(FsError::NotSubscribable),
+    }
+}
+
+impl<S: BackingStore> FS<S> {
+    /// Subscribes `gate` to changes of `target`, a directory or file. The
+    /// current label must be able to write `target`, and the privilege must
+    /// imply the gate's invoker integrity clearance. Subscribing a gate again
+    /// does nothing.
+    pub fn subscribe(&self, target: &DirEntry, gate: ObjectRef<Labeled<Gate>>, ctx: &mut SecurityContext) -> Result<(), FsError> {
+        let (uid, label) = subscribable(target, self)?;
+        if !ctx.can_write(&label) {
+            return Err(FsError::LabelError(LabelError::CannotWrite));
+        }
+        let subscriber = ctx.privilege().clone();
+        if !subscriber.implies(&gate.to_invokable(self, ctx)?.invoker_integrity_clearance) {
+            return Err(FsError::GateError(GateError::CannotInvoke));
+        }
+        self.update_subscriptions(uid, |subscriptions| {
+            if subscriptions.iter().any(|s| s.gate.uid == gate.uid) {
+                return false;
+            }
+            subscriptions.push(Subscription { gate, subscriber: subscriber.clone() });
+            true
+        });
+        Ok(())
+    }
+
+    /// Removes the subscription of `gate` to `target`, and returns whether it
+    /// existed. The current label must be able to write `target`.
+    pub fn unsubscribe(&self, target: &DirEntry, gate: ObjectRef<Labeled<Gate>>, ctx: &SecurityContext) -> Result<bool, FsError> {
+        let (uid, label) = subscribable(target, self)?;
+        if !ctx.can_write(&label) {
+            return Err(FsError::LabelError(LabelError::CannotWrite));
+        }
+        Ok(self.update_subscriptions(uid, |subscriptions| {
+            let len = subscriptions.len();
+            subscriptions.retain(|s| s.gate.uid != gate.uid);
+            subscriptions.len() < len
+        }))
+    }
+
+    /// Returns the subscriptions to the object `uid`
+    pub fn subscriptions(&self, uid: u64) -> Vec<Subscription> {
+        self.0.get(&subscription_key(uid)).as_deref().and_then(encoding::decode).unwrap_or_default()
+    }
+
+    /// Queues a notification of `event` on the context, labeled with its
+    /// current label, for each subscription to the object `uid`
+    pub(super) fn notify(&self, uid: u64, event: Event, ctx: &SecurityContext) {
+        let subscriptions = self.subscriptions(uid);
+        if subscriptions.is_empty() {
+            return;
+        }
+        let depth = ctx.notification_depth() + 1;
+        if depth > MAX_NOTIFICATION_DEPTH {
+            log::warn!("Dropped notifications of object {} {} deep", uid, depth);
+            return;
+        }
+        let label = ctx.label().clone();
+        ctx.queue_notifications(subscriptions.into_iter().map(|subscription| Notification {
+            subscription,
+            label: label.clone(),
+            event: event.clone(),
+            depth,
+        }));
+    }
+
+    pub(super) fn delete_subscriptions(&self, uid: u64) {
+        self.0.del(&subscription_key(uid))
+    }
+
+    /// Applies `f` to the subscriptions of `uid` and commits them if `f`
+    /// returns true, retrying on conflicts. Returns what `f` returned.
+    fn update_subscriptions<F: FnMut(&mut Vec<Subscription>) -> bool>(&self, uid: u64, mut f: F) -> bool {
+        let key = subscription_key(uid);
+        loop {
+            let prev = self.0.get(&key);
+            let mut subscriptions: Vec<Subscription> = prev.as_deref().and_then(encoding::decode).unwrap_or_default();
+            if !f(&mut subscriptions) {
+                return false;
+            }
+            let mut txn = Transaction::default();
+            txn.check(&key, prev.as_deref());
+            if subscriptions.is_empty() {
+                txn.del(&key);
+            } else {
+                txn.put(&key, encoding::encode(&subscriptions).as_slice());
+            }
+            if self.0.commit(&txn) {
+                return true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+
+    #[test]
+    fn test_notifications() {
+        let fs = FS::new(MemoryStore::new());
+        fs.initialize();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        let gate = fs.create_direct_gate(Buckle::public(), DirectGate {
+            privilege: Component::dc_true(),
+            invoker_integrity_clearance: Component::dc_true(),
+            declassify: Component::dc_true(),
+            function: Function::default(),
+        }, &ctx).unwrap();
+        let DirEntry::Gate(gate) = gate else { panic!("not a gate") };
+        let home = fs.create_and_link(Path::root(), "home".into(), NewEntry::Directory(Buckle::public()), &mut ctx).unwrap();
+        fs.subscribe(&home, gate, &mut ctx).unwrap();
+        fs.subscribe(&home, gate, &mut ctx).unwrap();
+        let DirEntry::Directory(home_ref) = home else { panic!("not a directory") };
+        assert_eq!(fs.subscriptions(home_ref.uid).len(), 1);
+        assert!(matches!(fs.subscribe(&DirEntry::Gate(gate), gate, &mut ctx), Err(FsError::NotSubscribable)));
+
+        let alice = Buckle::parse("alice,T").unwrap();
+        let mut ctx = SecurityContext::with_label(alice.clone(), Component::dc_false());
+        let file = fs.create_and_link(Path::parse(":home", &ctx).unwrap(), "f".into(), NewEntry::File(alice.clone(), vec![]), &mut ctx).unwrap();
+        let DirEntry::File(file_ref) = file.clone() else { panic!("not a file") };
+        fs.subscribe(&file, gate, &mut ctx).unwrap();
+        file_ref.write(b"data".to_vec(), &fs, &ctx).unwrap();
+
+        let notifications = ctx.take_notifications();
+        let events: Vec<Event> = notifications.iter().map(|n| n.event.clone()).collect();
+        assert_eq!(events, vec![Event::Link { name: "f".into() }, Event::Write]);
+        assert!(notifications.iter().all(|n| n.label == alice && n.depth == 1));
+        let (_, label) = notifications[0].resolve(&fs).unwrap();
+        assert_eq!(label, alice);
+
+        assert!(fs.unsubscribe(&file, gate, &ctx).unwrap());
+        file_ref.write(b"more".to_vec(), &fs, &ctx).unwrap();
+        assert!(ctx.take_notifications().is_empty());
+
+        // changes by invocations that notifications caused notify too, but
+        // only up to a depth
+        fs.subscribe(&file, gate, &mut ctx).unwrap();
+        let mut ctx = ctx.with_notification_depth(MAX_NOTIFICATION_DEPTH - 1);
+        file_ref.write(b"again".to_vec(), &fs, &ctx).unwrap();
+        assert_eq!(ctx.take_notifications()[0].depth, MAX_NOTIFICATION_DEPTH);
+        let mut ctx = ctx.with_notification_depth(MAX_NOTIFICATION_DEPTH);
+        file_ref.write(b"again".to_vec(), &fs, &ctx).unwrap();
+        assert!(ctx.take_notifications().is_empty());
+    }
+}
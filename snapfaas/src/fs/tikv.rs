@@ -1,29 +1,74 @@
+//! TiKV backing store
+//!
+//! All operations go through TiKV's transactional API, so that multi-key
+//! `commit`s are atomic. TiKV keeps the keys of its raw and transactional APIs
+//! apart, so stores written before this switched from the raw API look empty
+//! until `migrate_from_raw` copies their keys over.
 use std::sync::Arc;
 
-use tikv_client::RawClient;
+use tikv_client::{KvPair, RawClient, Snapshot, TransactionClient, TransactionOptions};
+
+/// Maximum number of pairs requested from TiKV per scan
+const SCAN_LIMIT: u32 = 1024;
 
 #[derive(Clone)]
 pub struct TikvClient {
     tokio_runtime: Arc<tokio::runtime::Runtime>,
-    client: RawClient,
+    client: Arc<TransactionClient>,
 }
 
 impl TikvClient {
-    pub const fn new(client: RawClient, tokio_runtime: Arc<tokio::runtime::Runtime> ) -> Self {
-        TikvClient { tokio_runtime, client }
+    pub fn new(client: TransactionClient, tokio_runtime: Arc<tokio::runtime::Runtime> ) -> Self {
+        TikvClient { tokio_runtime, client: Arc::new(client) }
+    }
+
+    /// Copies the keys written with the raw API through `raw` to the
+    /// transactional API, keeping keys that exist there already, and returns
+    /// how many were copied. The raw keys are left in place.
+    pub fn migrate_from_raw(&self, raw: &RawClient) -> usize {
+        use super::BackingStore;
+
+        let mut copied = 0;
+        let mut start = Vec::new();
+        loop {
+            let batch = self.tokio_runtime.block_on(raw.scan(start.clone().., SCAN_LIMIT)).expect("tikv raw scan");
+            let full = batch.len() == SCAN_LIMIT as usize;
+            for KvPair(key, value) in batch {
+                let key: Vec<u8> = key.into();
+                copied += self.add(&key, &value) as usize;
+                // the smallest key strictly greater than `key`
+                start = key;
+                start.push(0);
+            }
+            if !full {
+                return copied;
+            }
+        }
+    }
+
+    /// Returns a read-only snapshot at the latest timestamp, which reads
+    /// without taking locks and needs no rollback
+    async fn snapshot(&self) -> Snapshot {
+        let timestamp = self.client.current_timestamp().await.expect("tikv timestamp");
+        self.client.snapshot(timestamp, TransactionOptions::new_optimistic().read_only())
     }
 }
 
+// Reads use read-only snapshots at the latest timestamp. Writes use
+// pessimistic transactions, which lock keys as they are read or written, so
+// they do not fail to commit because of conflicts.
 impl super::BackingStore  for TikvClient {
     fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
         self.tokio_runtime.block_on(async {
-            self.client.get(Vec::from(key)).await.expect("tikv get")
+            self.snapshot().await.get(Vec::from(key)).await.expect("tikv get")
         })
     }
 
     fn put(&self, key: &[u8], value: &[u8]) {
         self.tokio_runtime.block_on(async {
-            self.client.put(Vec::from(key), value).await.expect("tikv put")
+            let mut txn = self.client.begin_pessimistic().await.expect("tikv begin");
+            txn.put(Vec::from(key), value).await.expect("tikv put");
+            txn.commit().await.expect("tikv commit");
         })
     }
 
@@ -32,19 +77,72 @@ impl super::BackingStore  for TikvClient {
     }
 
     fn cas(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<(), Option<Vec<u8>>> {
-        let (orig, success) = self.tokio_runtime.block_on(async {
-            self.client.with_atomic_for_cas().compare_and_swap(Vec::from(key), expected.map(Vec::from), value).await.expect("tikv cas")
-        });
-        if success {
-            Ok(())
-        } else {
-            Err(orig)
-        }
+        self.tokio_runtime.block_on(async {
+            let mut txn = self.client.begin_pessimistic().await.expect("tikv begin");
+            let orig = txn.get_for_update(Vec::from(key)).await.expect("tikv get");
+            if orig.as_deref() == expected {
+                txn.put(Vec::from(key), value).await.expect("tikv put");
+                txn.commit().await.expect("tikv commit");
+                Ok(())
+            } else {
+                txn.rollback().await.expect("tikv rollback");
+                Err(orig)
+            }
+        })
     }
 
     fn del(&self, key: &[u8]) {
         self.tokio_runtime.block_on(async {
-            self.client.delete(Vec::from(key)).await.expect("tikv del")
+            let mut txn = self.client.begin_pessimistic().await.expect("tikv begin");
+            txn.delete(Vec::from(key)).await.expect("tikv del");
+            txn.commit().await.expect("tikv commit");
         });
     }
+
+    fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.tokio_runtime.block_on(async {
+            let mut snapshot = self.snapshot().await;
+            let mut res: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+            let mut start = Vec::from(start);
+            while res.len() < limit {
+                let batch_limit = (limit - res.len()).min(SCAN_LIMIT as usize) as u32;
+                let batch: Vec<KvPair> = match end {
+                    Some(end) => snapshot.scan(start.clone()..Vec::from(end), batch_limit).await.expect("tikv scan").collect(),
+                    None => snapshot.scan(start.clone().., batch_limit).await.expect("tikv scan").collect(),
+                };
+                let full = batch.len() == batch_limit as usize;
+                res.extend(batch.into_iter().map(|KvPair(k, v)| (k.into(), v)));
+                match res.last() {
+                    Some((last, _)) if full => {
+                        // the smallest key strictly greater than `last`
+                        start = last.clone();
+                        start.push(0);
+                    },
+                    _ => break,
+                }
+            }
+            res
+        })
+    }
+
+    fn commit(&self, transaction: &super::Transaction) -> bool {
+        self.tokio_runtime.block_on(async {
+            let mut txn = self.client.begin_pessimistic().await.expect("tikv begin");
+            for (key, expected) in transaction.checks() {
+                let orig = txn.get_for_update(key.clone()).await.expect("tikv get");
+                if orig != *expected {
+                    txn.rollback().await.expect("tikv rollback");
+                    return false;
+                }
+            }
+            for (key, value) in transaction.writes() {
+                match value {
+                    Some(value) => txn.put(key.clone(), value.clone()).await.expect("tikv put"),
+                    None => txn.delete(key.clone()).await.expect("tikv del"),
+                }
+            }
+            txn.commit().await.expect("tikv commit");
+            true
+        })
+    }
 }
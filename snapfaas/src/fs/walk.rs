@@ -0,0 +1,221 @@
+//! Recursive walks
+//!
+//! `FS::walk` enumerates the entries below a directory depth-first and in
+//! name order, like `find`. Each directory is listed just before its entries
+//! are visited, tainting the context's current label with the directory's
+//! label, so a walk raises the current label no more than listing the same
+//! directories one by one would. Directories that would raise it above the
+//! walk's clearance, or the context's, are yielded but not descended into.
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    Directory,
+    File,
+    FacetedDirectory,
+    Gate,
+    Service,
+    Blob,
+    Map,
+    Log,
+}
+
+impl DirEntry {
+    pub fn kind(&self) -> EntryKind {
+        match self {
+            DirEntry::Directory(_) => EntryKind::Directory,
+            DirEntry::File(_) => EntryKind::File,
+            DirEntry::FacetedDirectory(_) => EntryKind::FacetedDirectory,
+            DirEntry::Gate(_) => EntryKind::Gate,
+            DirEntry::Service(_) => EntryKind::Service,
+            DirEntry::Blob(_) => EntryKind::Blob,
+            DirEntry::Map(_) => EntryKind::Map,
+            DirEntry::Log(_) => EntryKind::Log,
+        }
+    }
+}
+
+/// Which entries `FS::walk` visits and which of them it yields
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    /// Entries deeper than this are neither visited nor listed. Entries of the
+    /// directory the walk starts at are at depth 1.
+    pub max_depth: Option<usize>,
+    /// Only entries of these kinds are yielded, or entries of any kind if
+    /// empty. Directories of other kinds are still descended into.
+    pub kinds: Vec<EntryKind>,
+    /// Only entries whose names match are yielded. Facets are named by their
+    /// printed path component, e.g., `<alice,T>`.
+    pub name: Option<glob::Pattern>,
+    /// Bounds how high the walk may raise the current label. Faceted
+    /// directories are only descended into given a clearance, and then into
+    /// the facets that flow to it, raising the current label to it.
+    pub clearance: Option<Buckle>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    /// Path relative to where the walk started
+    pub path: Path,
+    pub depth: usize,
+    pub entry: DirEntry,
+}
+
+pub struct Walk<'a, S: BackingStore> {
+    fs: &'a FS<S>,
+    ctx: &'a mut SecurityContext,
+    options: WalkOptions,
+    /// Entries left to visit, the next one last
+    pending: Vec<WalkEntry>,
+    /// Error listing the last entry yielded, returned next
+    error: Option<FsError>,
+}
+
+impl<'a, S: BackingStore> Walk<'a, S> {
+    /// Queues the entries of `parent`, if it is a directory within the
+    /// clearances
+    fn descend(&mut self, parent: &WalkEntry) -> Result<(), FsError> {
+        let entries: Vec<(PathComponent, DirEntry)> = match &parent.entry {
+            DirEntry::Directory(dir) => {
+                let dir = dir.get(self.fs)?;
+                let label = dir.label().clone().lub(self.ctx.label().clone());
+                if self.options.clearance.as_ref().is_some_and(|clearance| !label.can_flow_to(clearance)) {
+                    return Ok(());
+                }
+                match dir.unlabel(self.ctx) {
+                    Ok(dir) => dir.entries.iter().map(|(name, entry)| {
+                        (PathComponent::Dscrp(name.clone()), entry.clone())
+                    }).collect(),
+                    Err(FsError::ClearanceError) => return Ok(()),
+                    Err(e) => return Err(e),
+                }
+            },
+            DirEntry::FacetedDirectory(faceted) => {
+                let Some(clearance) = self.options.clearance.as_ref() else {
+                    return Ok(());
+                };
+                match faceted.list(self.fs, clearance, self.ctx) {
+                    Ok(facets) => facets.into_iter().map(|(label, dir)| {
+                        (PathComponent::Facet(label), DirEntry::Directory(dir))
+                    }).collect(),
+                    // no facet has been opened yet
+                    Err(FsError::DanglingReference(_)) | Err(FsError::ClearanceError) => return Ok(()),
+                    Err(e) => return Err(e),
+                }
+            },
+            _ => return Ok(()),
+        };
+        self.pending.extend(entries.into_iter().rev().map(|(component, entry)| {
+            let mut path = parent.path.clone();
+            path.push(component);
+            WalkEntry { path, depth: parent.depth + 1, entry }
+        }));
+        Ok(())
+    }
+
+    fn matches(&self, visited: &WalkEntry) -> bool {
+        if !self.options.kinds.is_empty() && !self.options.kinds.contains(&visited.entry.kind()) {
+            return false;
+        }
+        match (&self.options.name, visited.path.last()) {
+            (Some(pattern), Some(PathComponent::Dscrp(name))) => pattern.matches(name),
+            (Some(pattern), Some(facet)) => pattern.matches(&facet.to_string()),
+            _ => true,
+        }
+    }
+}
+
+impl<'a, S: BackingStore> Iterator for Walk<'a, S> {
+    type Item = Result<WalkEntry, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        loop {
+            let visited = self.pending.pop()?;
+            if self.options.max_depth.is_none_or(|max| visited.depth < max) {
+                if let Err(e) = self.descend(&visited) {
+                    self.error = Some(e);
+                }
+            }
+            if self.matches(&visited) {
+                return Some(Ok(visited));
+            }
+            if let Some(e) = self.error.take() {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+impl<S: BackingStore> FS<S> {
+    /// Walks the entries below the directory or faceted directory at `path`,
+    /// not including it, as described in the module documentation.
+    ///
+    /// The context's current label is tainted for each path component, meaning
+    /// path traversal never fails when the path exists, but may increase the
+    /// current label up to the context's clearance. Traversal fails with
+    /// `FsError::ClearanceError` rather than exceed it.
+    pub fn walk<'a, P: Into<Path>>(&'a self, path: P, options: WalkOptions, ctx: &'a mut SecurityContext) -> Result<Walk<'a, S>, FsError> {
+        let start = self.read_path(path, ctx)?;
+        self.walk_entry(start, options, ctx)
+    }
+
+    /// Walks the entries below `start`, a directory or faceted directory, like
+    /// `walk`
+    pub fn walk_entry<'a>(&'a self, start: DirEntry, options: WalkOptions, ctx: &'a mut SecurityContext) -> Result<Walk<'a, S>, FsError> {
+        if !matches!(start, DirEntry::Directory(_) | DirEntry::FacetedDirectory(_)) {
+            return Err(FsError::NotADir);
+        }
+        let start = WalkEntry { path: Path::root(), depth: 0, entry: start };
+        let mut walk = Walk { fs: self, ctx, options, pending: vec![], error: None };
+        if walk.options.max_depth != Some(0) {
+            walk.descend(&start)?;
+        }
+        Ok(walk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::MemoryStore;
+
+    fn paths<S: BackingStore>(walk: Walk<S>) -> Vec<String> {
+        walk.map(|visited| visited.unwrap().path.to_string()).collect()
+    }
+
+    #[test]
+    fn test_walk() {
+        let fs = FS::new(MemoryStore::new());
+        fs.initialize();
+        let mut ctx = SecurityContext::new(Component::dc_false());
+        let root = Path::root();
+        fs.create_and_link(root.clone(), "a".into(), NewEntry::Directory(Buckle::public()), &mut ctx).unwrap();
+        fs.create_and_link(root.clone(), "b.txt".into(), NewEntry::File(Buckle::public(), vec![]), &mut ctx).unwrap();
+        fs.create_and_link(Path::parse(":a", &ctx).unwrap(), "f.txt".into(), NewEntry::File(Buckle::public(), vec![]), &mut ctx).unwrap();
+        fs.create_and_link(Path::parse(":a", &ctx).unwrap(), "secret".into(), NewEntry::Directory(Buckle::parse("alice,T").unwrap()), &mut ctx).unwrap();
+        fs.create_and_link(Path::parse(":a:secret", &ctx).unwrap(), "g.txt".into(), NewEntry::File(Buckle::parse("alice,T").unwrap(), vec![]), &mut ctx).unwrap();
+
+        let mut ctx = SecurityContext::default();
+        let walk = fs.walk(root.clone(), WalkOptions::default(), &mut ctx).unwrap();
+        assert_eq!(paths(walk), vec![":a", ":a:f.txt", ":a:secret", ":a:secret:g.txt", ":b.txt"]);
+        assert_eq!(ctx.label(), &Buckle::parse("alice,T").unwrap());
+
+        let mut ctx = SecurityContext::default();
+        let options = WalkOptions { max_depth: Some(1), ..Default::default() };
+        assert_eq!(paths(fs.walk(root.clone(), options, &mut ctx).unwrap()), vec![":a", ":b.txt"]);
+        assert_eq!(ctx.label(), &Buckle::public());
+
+        let mut ctx = SecurityContext::default();
+        let options = WalkOptions {
+            kinds: vec![EntryKind::File],
+            name: Some(glob::Pattern::new("*.txt").unwrap()),
+            clearance: Some(Buckle::public()),
+            ..Default::default()
+        };
+        assert_eq!(paths(fs.walk(root, options, &mut ctx).unwrap()), vec![":a:f.txt", ":b.txt"]);
+        assert_eq!(ctx.label(), &Buckle::public());
+    }
+}
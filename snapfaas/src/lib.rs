@@ -6,6 +6,7 @@ pub mod worker;
 //pub mod metrics;
 pub mod blobstore;
 pub mod cli;
+pub mod crypto;
 pub mod firecracker_wrapper;
 pub mod fs;
 pub mod sched;
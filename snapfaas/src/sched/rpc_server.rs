@@ -4,7 +4,7 @@ use std::net::{TcpListener, TcpStream};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 
-use crate::fs;
+use labeled::buckle::Buckle;
 
 use super::message;
 use super::resource_manager::ResourceManager;
@@ -99,7 +99,8 @@ impl RpcServer {
                             let ret = message::TaskReturn {
                                 code: message::ReturnCode::QueueFull as i32,
                                 payload: None,
-                                label: Some(fs::utils::get_current_label().into()),
+                                label: Some(Buckle::public().into()),
+                                store_usage: None,
                             };
                             let _ = message::write(&mut stream, &ret);
                         }
@@ -5,15 +5,14 @@ use std::net::TcpStream;
 
 use crate::blobstore::{self, Blob, Blobstore};
 use crate::fs::{
-    self, BackingStore, DirEntry, DirectGate, FsError, Function, Gate, RedirectGate, Service,
-    CURRENT_LABEL, FS, PRIVILEGE,
+    self, BackingStore, DirEntry, DirectGate, FsError, Function, Gate, RedirectGate,
+    SecurityContext, Service, FS,
 };
 use crate::sched::message::{ReturnCode, TaskReturn};
 use crate::sched::{self, message};
 use crate::syscalls::DentInvoke;
 use crate::syscalls::{self, syscall::Syscall as SC};
 use labeled::buckle::{Buckle, Component};
-use labeled::Label;
 
 #[derive(Debug)]
 pub enum SyscallChannelError {
@@ -57,6 +56,7 @@ pub struct SyscallProcessor<'a, B: BackingStore> {
     create_blobs: HashMap<u64, blobstore::NewBlob>,
     blobs: HashMap<u64, blobstore::Blob>,
     dents: HashMap<u64, fs::DirEntry>,
+    ctx: SecurityContext,
     max_blob_id: u64,
     max_dent_id: u64,
     http_client: reqwest::blocking::Client,
@@ -64,13 +64,6 @@ pub struct SyscallProcessor<'a, B: BackingStore> {
 
 impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
     pub fn new(env: &'a mut SyscallGlobalEnv<B>, label: Buckle, privilege: Component) -> Self {
-        {
-            // set up label & privilege
-            fs::utils::clear_label();
-            fs::utils::taint_with_label(label);
-            fs::utils::set_my_privilge(privilege);
-        }
-
         let mut dents: HashMap<u64, fs::DirEntry> = Default::default();
         dents.insert(0, DirEntry::Directory(fs::ROOT_REF));
 
@@ -79,6 +72,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
             create_blobs: Default::default(),
             blobs: Default::default(),
             dents,
+            ctx: SecurityContext::with_label(label, privilege),
             max_dent_id: 1,
             max_blob_id: 1,
             http_client: reqwest::blocking::Client::new(),
@@ -91,6 +85,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
             create_blobs: Default::default(),
             blobs: Default::default(),
             dents: Default::default(),
+            ctx: Default::default(),
             max_blob_id: 0,
             max_dent_id: 0,
             http_client: reqwest::blocking::Client::new(),
@@ -144,7 +139,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                 .cloned()
                 .and_then(|base| match (base, entry) {
                     (DirEntry::Directory(base_dir), syscalls::dent_open::Entry::Name(name)) => {
-                        base_dir.list(&self.env.fs).get(&name).map(|dent| {
+                        base_dir.list(&self.env.fs, &mut self.ctx).ok()?.get(&name).map(|dent| {
                             let res_id = self.max_dent_id;
                             let _ = self.dents.insert(self.max_dent_id, dent.clone());
                             self.max_dent_id += 1;
@@ -155,7 +150,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                         DirEntry::FacetedDirectory(base_dir),
                         syscalls::dent_open::Entry::Facet(label),
                     ) => {
-                        let dent = DirEntry::Directory(base_dir.open(&label.into(), &self.env.fs));
+                        let dent = DirEntry::Directory(base_dir.open(&label.into(), &self.env.fs, &self.ctx).ok()?);
                         let res_id = self.max_dent_id;
                         let _ = self.dents.insert(self.max_dent_id, dent.clone());
                         self.max_dent_id += 1;
@@ -166,7 +161,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                         syscalls::dent_open::Entry::Name(label_name),
                     ) => {
                         if let Ok(label) = Buckle::parse(label_name.as_str()) {
-                            let dent = DirEntry::Directory(base_dir.open(&label, &self.env.fs));
+                            let dent = DirEntry::Directory(base_dir.open(&label, &self.env.fs, &self.ctx).ok()?);
                             let res_id = self.max_dent_id;
                             let _ = self.dents.insert(self.max_dent_id, dent.clone());
                             self.max_dent_id += 1;
@@ -175,6 +170,15 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                             None
                         }
                     }
+                    (base, syscalls::dent_open::Entry::Path(path)) => {
+                        let (path, home) = fs::path::Path::parse_relative(&path, &self.ctx).ok()?;
+                        let base = if home { DirEntry::Directory(fs::ROOT_REF) } else { base };
+                        let dent = self.env.fs.resolve_path(base, path, &mut self.ctx).ok()?;
+                        let res_id = self.max_dent_id;
+                        let _ = self.dents.insert(self.max_dent_id, dent.clone());
+                        self.max_dent_id += 1;
+                        Some((res_id, (&dent).into()))
+                    }
                     _ => None,
                 });
         if let Some(result) = result {
@@ -208,9 +212,11 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         use syscalls::dent_create::Kind;
         let label = label.unwrap_or(Buckle::public());
         let entry: DirEntry = match kind {
-            Kind::Directory(syscalls::Void {}) => self.env.fs.create_directory(label),
-            Kind::File(syscalls::Void {}) => self.env.fs.create_file(label),
-            Kind::FacetedDirectory(syscalls::Void {}) => self.env.fs.create_faceted_directory(),
+            Kind::Directory(syscalls::Void {}) => self.env.fs.create_directory(label, &self.ctx)?,
+            Kind::File(syscalls::Void {}) => self.env.fs.create_file(label, &self.ctx)?,
+            Kind::FacetedDirectory(syscalls::Void {}) => self.env.fs.create_faceted_directory(&self.ctx)?,
+            Kind::Map(syscalls::Void {}) => self.env.fs.create_map(label, &self.ctx)?,
+            Kind::Log(syscalls::Void {}) => self.env.fs.create_log(label, &self.ctx)?,
             Kind::Gate(syscalls::Gate { kind }) => {
                 if let Some(kind) = kind {
                     match kind {
@@ -239,13 +245,9 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
 
                             let func = Function {
                                 memory: function.memory as usize,
-                                app_image: app_image.get(&self.env.fs).unwrap().unlabel().clone(),
-                                runtime_image: runtime_image
-                                    .get(&self.env.fs)
-                                    .unwrap()
-                                    .unlabel()
-                                    .clone(),
-                                kernel: kernel.get(&self.env.fs).unwrap().unlabel().clone(),
+                                app_image: app_image.read(&self.env.fs, &mut self.ctx)?,
+                                runtime_image: runtime_image.read(&self.env.fs, &mut self.ctx)?,
+                                kernel: kernel.read(&self.env.fs, &mut self.ctx)?,
                             };
                             self.env.fs.create_direct_gate(
                                 label,
@@ -261,6 +263,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                                         .unwrap_or(Component::dc_true()),
                                     function: func,
                                 },
+                                &self.ctx,
                             )?
                         }
                         syscalls::gate::Kind::Redirect(rd) => {
@@ -279,6 +282,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                                             .unwrap_or(Component::dc_true()),
                                         gate: *gate_objref,
                                     },
+                                    &self.ctx,
                                 )?
                             } else {
                                 Err(FsError::NotAGate)?
@@ -311,11 +315,12 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                         verb,
                         headers,
                     },
+                    &self.ctx,
                 )?
             }
             Kind::Blob(blobfd) => {
                 let blob = self.blobs.get(&blobfd).ok_or(FsError::NotABlob)?;
-                self.env.fs.create_blob(label, blob.name.clone())?
+                self.env.fs.create_blob(label, blob.name.clone(), &self.ctx)?
             }
         };
         let res_id = self.max_dent_id;
@@ -337,7 +342,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         match kind {
             Kind::File(data) => {
                 if let Some(DirEntry::File(file)) = self.dents.get(&fd) {
-                    file.write(data, &self.env.fs)?;
+                    file.write(data, &self.env.fs, &self.ctx)?;
                 } else {
                     return Err(FsError::NotAFile);
                 }
@@ -347,8 +352,8 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                     if let Some(kind) = kind {
                         match kind {
                             syscalls::gate::Kind::Direct(dg) => {
-                                let mut gate = if let Some(Gate::Direct(dg)) =
-                                    gateentry.get(&self.env.fs).map(|e| e.unlabel().clone())
+                                let mut gate = if let Gate::Direct(dg) =
+                                    gateentry.get(&self.env.fs)?.unlabel(&mut self.ctx)?.clone()
                                 {
                                     dg
                                 } else {
@@ -363,8 +368,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                                         else {
                                             Err(FsError::NotABlob)?
                                         };
-                                        gate.function.app_image =
-                                            app_image.get(&self.env.fs).unwrap().unlabel().clone();
+                                        gate.function.app_image = app_image.read(&self.env.fs, &mut self.ctx)?;
                                     }
                                     if function.runtime > 0 {
                                         let DirEntry::Blob(runtime_image) = self
@@ -374,11 +378,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                                         else {
                                             Err(FsError::NotABlob)?
                                         };
-                                        gate.function.runtime_image = runtime_image
-                                            .get(&self.env.fs)
-                                            .unwrap()
-                                            .unlabel()
-                                            .clone();
+                                        gate.function.runtime_image = runtime_image.read(&self.env.fs, &mut self.ctx)?;
                                     }
 
                                     if function.kernel > 0 {
@@ -389,8 +389,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                                         else {
                                             Err(FsError::NotABlob)?
                                         };
-                                        gate.function.kernel =
-                                            kernel.get(&self.env.fs).unwrap().unlabel().clone();
+                                        gate.function.kernel = kernel.read(&self.env.fs, &mut self.ctx)?;
                                     }
 
                                     if function.memory > 0 {
@@ -409,11 +408,11 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                                         invoker_integrity_clearance.into();
                                 }
 
-                                gateentry.replace(Gate::Direct(gate), &self.env.fs)?;
+                                gateentry.replace(Gate::Direct(gate), &self.env.fs, &self.ctx)?;
                             }
                             syscalls::gate::Kind::Redirect(rd) => {
-                                let mut gate = if let Some(Gate::Redirect(rg)) =
-                                    gateentry.get(&self.env.fs).map(|e| e.unlabel().clone())
+                                let mut gate = if let Gate::Redirect(rg) =
+                                    gateentry.get(&self.env.fs)?.unlabel(&mut self.ctx)?.clone()
                                 {
                                     rg
                                 } else {
@@ -438,7 +437,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                                         invoker_integrity_clearance.into();
                                 }
 
-                                gateentry.replace(Gate::Redirect(gate), &self.env.fs)?
+                                gateentry.replace(Gate::Redirect(gate), &self.env.fs, &self.ctx)?
                             }
                         }
                     } else {
@@ -474,6 +473,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                             headers,
                         },
                         &self.env.fs,
+                        &self.ctx,
                     )?
                 } else {
                     return Err(FsError::NotAService);
@@ -482,7 +482,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
             Kind::Blob(blobfd) => {
                 let blob = self.blobs.get(&blobfd).ok_or(FsError::NotABlob)?;
                 if let Some(DirEntry::Blob(blobentry)) = self.dents.get(&fd) {
-                    blobentry.replace(blob.name.clone(), &self.env.fs)?;
+                    blobentry.replace(blob.name.clone(), &self.env.fs, &self.ctx)?;
                 } else {
                     return Err(FsError::NotABlob);
                 }
@@ -496,9 +496,9 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
     }
 
     fn dent_read(&mut self, fd: u64) -> syscalls::DentResult {
-        let result = self.dents.get(&fd).and_then(|entry| {
+        let result = self.dents.get(&fd).cloned().and_then(|entry| {
             match entry {
-                DirEntry::File(file) => Ok(file.read(&self.env.fs)),
+                DirEntry::File(file) => file.read(&self.env.fs, &mut self.ctx),
                 _ => Err(FsError::NotAFile),
             }
             .ok()
@@ -510,11 +510,206 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         }
     }
 
+    fn dent_read_range(&mut self, fd: u64, offset: Option<u64>, length: Option<u64>) -> syscalls::DentResult {
+        let result = self.dents.get(&fd).cloned().and_then(|entry| {
+            match entry {
+                DirEntry::File(file) => file.read_range(offset.unwrap_or(0), length.unwrap_or(u64::MAX), &self.env.fs, &mut self.ctx),
+                _ => Err(FsError::NotAFile),
+            }
+            .ok()
+        });
+        syscalls::DentResult {
+            success: result.is_some(),
+            fd: Some(fd),
+            data: result,
+        }
+    }
+
+    fn dent_append(&mut self, fd: u64, data: &[u8]) -> syscalls::DentResult {
+        let result = match self.dents.get(&fd) {
+            Some(DirEntry::File(file)) => file.append(data, &self.env.fs, &self.ctx),
+            _ => Err(FsError::NotAFile),
+        };
+        syscalls::DentResult {
+            success: result.is_ok(),
+            fd: Some(fd),
+            data: None,
+        }
+    }
+
+    fn dent_truncate(&mut self, fd: u64, length: u64) -> syscalls::DentResult {
+        let result = match self.dents.get(&fd) {
+            Some(DirEntry::File(file)) => file.truncate(length, &self.env.fs, &self.ctx),
+            _ => Err(FsError::NotAFile),
+        };
+        syscalls::DentResult {
+            success: result.is_ok(),
+            fd: Some(fd),
+            data: None,
+        }
+    }
+
+    fn dent_map_get(&mut self, fd: u64, key: &str) -> syscalls::DentResult {
+        let result = match self.dents.get(&fd) {
+            Some(DirEntry::Map(map)) => map.lookup(key, &self.env.fs, &mut self.ctx),
+            _ => Err(FsError::NotAMap),
+        };
+        syscalls::DentResult {
+            success: result.is_ok(),
+            fd: Some(fd),
+            data: result.ok().flatten(),
+        }
+    }
+
+    fn dent_map_put(&mut self, fd: u64, key: String, value: Vec<u8>) -> syscalls::DentResult {
+        let result = match self.dents.get(&fd) {
+            Some(DirEntry::Map(map)) => map.insert(key, value, &self.env.fs, &mut self.ctx),
+            _ => Err(FsError::NotAMap),
+        };
+        syscalls::DentResult {
+            success: result.is_ok(),
+            fd: Some(fd),
+            data: None,
+        }
+    }
+
+    fn dent_map_delete(&mut self, fd: u64, key: &str) -> syscalls::DentResult {
+        let result = match self.dents.get(&fd) {
+            Some(DirEntry::Map(map)) => map.remove(key, &self.env.fs, &mut self.ctx),
+            _ => Err(FsError::NotAMap),
+        };
+        syscalls::DentResult {
+            success: result.is_ok(),
+            fd: Some(fd),
+            data: None,
+        }
+    }
+
+    fn dent_map_cas(
+        &mut self,
+        fd: u64,
+        key: &str,
+        expected: Option<Vec<u8>>,
+        value: Option<Vec<u8>>,
+    ) -> syscalls::DentResult {
+        let result = match self.dents.get(&fd) {
+            Some(DirEntry::Map(map)) => {
+                map.compare_and_swap(key, expected.as_deref(), value, &self.env.fs, &mut self.ctx)
+            }
+            _ => Err(FsError::NotAMap),
+        };
+        syscalls::DentResult {
+            success: result.unwrap_or(false),
+            fd: Some(fd),
+            data: None,
+        }
+    }
+
+    fn dent_log_append(&mut self, fd: u64, record: Vec<u8>) -> syscalls::DentResult {
+        let result = match self.dents.get(&fd) {
+            Some(DirEntry::Log(log)) => log.push(record, &self.env.fs, &self.ctx),
+            _ => Err(FsError::NotALog),
+        };
+        syscalls::DentResult {
+            success: result.is_ok(),
+            fd: Some(fd),
+            data: None,
+        }
+    }
+
+    fn dent_log_read(&mut self, fd: u64, offset: u64, max: Option<u64>) -> syscalls::DentLogReadResult {
+        let max = max.map_or(usize::MAX, |max| max as usize);
+        let result = match self.dents.get(&fd) {
+            Some(DirEntry::Log(log)) => log.read_from(offset, max, &self.env.fs, &mut self.ctx),
+            _ => Err(FsError::NotALog),
+        };
+        match result {
+            Ok((offset, records)) => syscalls::DentLogReadResult {
+                success: true,
+                offset,
+                records,
+            },
+            Err(_) => syscalls::DentLogReadResult {
+                success: false,
+                offset,
+                records: vec![],
+            },
+        }
+    }
+
+    fn dent_log_trim(&mut self, fd: u64, offset: u64) -> syscalls::DentResult {
+        let result = match self.dents.get(&fd) {
+            Some(DirEntry::Log(log)) => log.trim(offset, &self.env.fs, &self.ctx),
+            _ => Err(FsError::NotALog),
+        };
+        syscalls::DentResult {
+            success: result.is_ok(),
+            fd: Some(fd),
+            data: None,
+        }
+    }
+
+    fn dent_subscribe(&mut self, fd: u64, gate_fd: u64, subscribe: bool) -> syscalls::DentResult {
+        let result = match (self.dents.get(&fd), self.dents.get(&gate_fd)) {
+            (Some(target), Some(DirEntry::Gate(gate))) => {
+                if subscribe {
+                    self.env.fs.subscribe(target, *gate, &mut self.ctx).map(|_| true)
+                } else {
+                    self.env.fs.unsubscribe(target, *gate, &self.ctx)
+                }
+            }
+            (Some(_), _) => Err(FsError::NotAGate),
+            _ => Err(FsError::InvalidFd),
+        };
+        syscalls::DentResult {
+            success: result.unwrap_or(false),
+            fd: Some(fd),
+            data: None,
+        }
+    }
+
+    /// Runs the function as caused by a chain of `depth` notifications, see
+    /// `SecurityContext::with_notification_depth`
+    pub fn with_notification_depth(mut self, depth: u32) -> Self {
+        self.ctx = self.ctx.with_notification_depth(depth);
+        self
+    }
+
+    /// Invokes the gates subscribed to objects changed by the last syscall.
+    /// Notifications whose gates cannot be resolved anymore are dropped, and
+    /// failures to deliver them are logged, as the syscall succeeded anyway.
+    fn send_notifications(&mut self) {
+        for notification in self.ctx.take_notifications() {
+            let Ok((gate, label)) = notification.resolve(&self.env.fs) else {
+                continue;
+            };
+            let Some(sched_conn) = self.env.sched_conn.as_mut() else {
+                continue;
+            };
+            let sent = sched::rpc::labeled_invoke(
+                sched_conn,
+                sched::message::LabeledInvoke {
+                    function: Some(gate.function.into()),
+                    label: Some(label.into()),
+                    gate_privilege: Some(gate.privilege.into()),
+                    blobs: Default::default(),
+                    payload: serde_json::to_vec(&notification.event).unwrap_or_default(),
+                    headers: Default::default(),
+                    sync: false,
+                    invoker: Some(notification.subscription.subscriber.into()),
+                    notification_depth: notification.depth,
+                },
+            );
+            if let Err(e) = sent {
+                log::warn!("Failed to deliver notification to gate {:?}: {:?}", notification.subscription.gate, e);
+            }
+        }
+    }
+
     fn dent_list(&mut self, fd: u64) -> syscalls::DentListResult {
-        let result = self.dents.get(&fd).and_then(|entry| {
+        let result = self.dents.get(&fd).cloned().and_then(|entry| {
             match entry {
-                DirEntry::Directory(dir) => Ok(dir
-                    .list(&self.env.fs)
+                DirEntry::Directory(dir) => dir.list(&self.env.fs, &mut self.ctx).map(|entries| entries
                     .iter()
                     .map(|(name, direntry)| {
                         let kind = match direntry {
@@ -526,6 +721,8 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                             DirEntry::Gate(_) => syscalls::DentKind::DentGate,
                             DirEntry::Service(_) => syscalls::DentKind::DentService,
                             DirEntry::Blob(_) => syscalls::DentKind::DentBlob,
+                            DirEntry::Map(_) => syscalls::DentKind::DentMap,
+                            DirEntry::Log(_) => syscalls::DentKind::DentLog,
                         };
                         (name.clone(), kind as i32)
                     })
@@ -548,13 +745,11 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
     }
 
     fn dent_list_faceted(&mut self, fd: u64, clearance: Buckle) -> syscalls::DentLsFacetedResult {
-        let result = self.dents.get(&fd).and_then(|entry| {
+        let result = self.dents.get(&fd).cloned().and_then(|entry| {
             match entry {
-                DirEntry::FacetedDirectory(faceted) => Ok(faceted
-                    .list(&self.env.fs, &clearance)
-                    .iter()
-                    .map(|(label, _)| label.clone().into())
-                    .collect()),
+                DirEntry::FacetedDirectory(faceted) => faceted
+                    .list(&self.env.fs, &clearance, &mut self.ctx)
+                    .map(|facets| facets.keys().map(|label| label.clone().into()).collect()),
                 _ => Err(FsError::NotADir),
             }
             .ok()
@@ -572,89 +767,100 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         }
     }
 
+    fn dent_walk(&mut self, walk: syscalls::DentWalk) -> syscalls::DentWalkResult {
+        let options = walk.name.as_deref().map(glob::Pattern::new).transpose().map(|name| fs::WalkOptions {
+            max_depth: walk.max_depth.map(|max| max as usize),
+            kinds: walk.kinds().map(Into::into).collect(),
+            name,
+            clearance: walk.clearance.clone().map(Into::into),
+        });
+        let result = match (self.dents.get(&walk.fd).cloned(), options) {
+            (Some(start), Ok(options)) => self.env.fs.walk_entry(start, options, &mut self.ctx).and_then(|entries| {
+                entries.map(|visited| visited.map(|visited| syscalls::DentWalkEntry {
+                    path: visited.path.to_string(),
+                    kind: syscalls::DentKind::from(&visited.entry) as i32,
+                    depth: visited.depth as u64,
+                })).collect()
+            }),
+            _ => Err(FsError::InvalidFd),
+        };
+        match result {
+            Ok(entries) => syscalls::DentWalkResult { success: true, entries },
+            Err(_) => syscalls::DentWalkResult { success: false, entries: Default::default() },
+        }
+    }
+
     fn dent_ls_gate(&mut self, fd: u64) -> syscalls::DentLsGateResult {
-        let result = self.dents.get(&fd).map(Clone::clone).and_then(|entry| {
+        let gate = self.dents.get(&fd).map(Clone::clone).and_then(|entry| {
             match entry {
-                DirEntry::Gate(gate) => Ok(match gate.get(&self.env.fs).unwrap().unlabel() {
-                    fs::Gate::Direct(dg) => {
-                        let app_image_fd = {
-                            let blobid = self.max_blob_id;
-                            self.max_blob_id += 1;
-                            let blob = self
-                                .env
-                                .blobstore
-                                .open(dg.function.app_image.clone())
-                                .expect("open");
-                            self.blobs.insert(blobid, blob);
-                            blobid
-                        };
-                        let runtime_fd = {
-                            let blobid = self.max_blob_id;
-                            self.max_blob_id += 1;
-                            let blob = self
-                                .env
-                                .blobstore
-                                .open(dg.function.runtime_image.clone())
-                                .expect("open");
-                            self.blobs.insert(blobid, blob);
-                            blobid
-                        };
-                        let kernel_fd = {
-                            let blobid = self.max_blob_id;
-                            self.max_blob_id += 1;
-                            let blob = self
-                                .env
-                                .blobstore
-                                .open(dg.function.kernel.clone())
-                                .expect("open");
-                            self.blobs.insert(blobid, blob);
-                            blobid
-                        };
-                        let function = syscalls::Function {
-                            memory: dg.function.memory as u64,
-                            app_image: app_image_fd,
-                            runtime: runtime_fd,
-                            kernel: kernel_fd,
-                        };
-                        syscalls::Gate {
-                            kind: Some(syscalls::gate::Kind::Direct(syscalls::DirectGate {
-                                privilege: Some(dg.privilege.clone().into()),
-                                invoker_integrity_clearance: Some(
-                                    dg.invoker_integrity_clearance.clone().into(),
-                                ),
-                                declassify: Some(dg.declassify.clone().into()),
-                                function: Some(function),
-                            })),
-                        }
-                    }
-                    fs::Gate::Redirect(rd) => syscalls::Gate {
-                        kind: Some(syscalls::gate::Kind::Redirect(syscalls::RedirectGate {
-                            privilege: Some(rd.privilege.clone().into()),
-                            invoker_integrity_clearance: Some(
-                                rd.invoker_integrity_clearance.clone().into(),
-                            ),
-                            declassify: Some(rd.declassify.clone().into()),
-                            gate: 0, // unused field in this case
-                        })),
-                    },
-                }),
+                DirEntry::Gate(gate) => gate.get(&self.env.fs).and_then(|gate| gate.unlabel(&mut self.ctx).cloned()),
                 _ => Err(FsError::NotAGate),
             }
             .ok()
         });
+        let result = gate.and_then(|gate| Some(match gate {
+            fs::Gate::Direct(dg) => {
+                let function = syscalls::Function {
+                    memory: dg.function.memory as u64,
+                    app_image: self.open_blob(dg.function.app_image.clone())?.0,
+                    runtime: self.open_blob(dg.function.runtime_image.clone())?.0,
+                    kernel: self.open_blob(dg.function.kernel.clone())?.0,
+                };
+                syscalls::Gate {
+                    kind: Some(syscalls::gate::Kind::Direct(syscalls::DirectGate {
+                        privilege: Some(dg.privilege.clone().into()),
+                        invoker_integrity_clearance: Some(
+                            dg.invoker_integrity_clearance.clone().into(),
+                        ),
+                        declassify: Some(dg.declassify.clone().into()),
+                        function: Some(function),
+                    })),
+                }
+            }
+            fs::Gate::Redirect(rd) => syscalls::Gate {
+                kind: Some(syscalls::gate::Kind::Redirect(syscalls::RedirectGate {
+                    privilege: Some(rd.privilege.clone().into()),
+                    invoker_integrity_clearance: Some(
+                        rd.invoker_integrity_clearance.clone().into(),
+                    ),
+                    declassify: Some(rd.declassify.clone().into()),
+                    gate: 0, // unused field in this case
+                })),
+            },
+        }));
         syscalls::DentLsGateResult {
             success: result.is_some(),
             gate: result,
         }
     }
 
-    fn dent_link(&self, dir_fd: u64, name: String, target_fd: u64) -> syscalls::DentResult {
+    /// Opens the blob `name` as a new blob fd, and returns the fd and the
+    /// blob's length, or `None` if the blob cannot be read, e.g., because it
+    /// was collected
+    fn open_blob(&mut self, name: String) -> Option<(u64, u64)> {
+        let opened = self.env.blobstore.open(name.clone())
+            .and_then(|blob| blob.len().map(|len| (blob, len)));
+        match opened {
+            Ok((blob, len)) => {
+                let blobfd = self.max_blob_id;
+                self.max_blob_id += 1;
+                self.blobs.insert(blobfd, blob);
+                Some((blobfd, len))
+            }
+            Err(e) => {
+                log::error!("Failed to open blob {}: {:?}", name, e);
+                None
+            }
+        }
+    }
+
+    fn dent_link(&mut self, dir_fd: u64, name: String, target_fd: u64) -> syscalls::DentResult {
         let base_dir_m = self.dents.get(&dir_fd).cloned();
         let target_obj_m = self.dents.get(&target_fd).cloned();
         let result = base_dir_m.zip(target_obj_m).and_then(|(base, target)| {
             match base {
                 DirEntry::Directory(base_dir) => base_dir
-                    .link(name, target, &self.env.fs)
+                    .link(name, target, &self.env.fs, &mut self.ctx)
                     .map_err(|e| Into::into(e)),
                 _ => Err(FsError::NotADir),
             }
@@ -667,9 +873,9 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         }
     }
 
-    fn dent_unlink(&self, fd: u64, name: &String) -> syscalls::DentResult {
+    fn dent_unlink(&mut self, fd: u64, name: &String) -> syscalls::DentResult {
         let result = self.dents.get(&fd).cloned().and_then(|entry| match entry {
-            DirEntry::Directory(base_dir) => base_dir.unlink(name, &self.env.fs).ok(),
+            DirEntry::Directory(base_dir) => base_dir.unlink(name, &self.env.fs, &mut self.ctx).ok(),
             _ => None,
         });
         syscalls::DentResult {
@@ -679,6 +885,53 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         }
     }
 
+    fn dent_stat(&mut self, fd: u64) -> syscalls::DentStatResult {
+        let result = self.dents.get(&fd).cloned().and_then(|entry| {
+            let stat = entry.stat(&self.env.fs, &mut self.ctx).ok()?;
+            let size = match entry {
+                DirEntry::Blob(blob) => {
+                    let name = blob.read(&self.env.fs, &mut self.ctx).ok()?;
+                    self.env.blobstore.open(name).and_then(|blob| blob.len()).ok()
+                },
+                _ => stat.size,
+            };
+            Some((syscalls::DentKind::from(&entry), size, stat.metadata))
+        });
+        match result {
+            Some((kind, size, metadata)) => syscalls::DentStatResult {
+                success: true,
+                kind: kind as i32,
+                size,
+                created: metadata.as_ref().map(|m| m.created),
+                modified: metadata.as_ref().map(|m| m.modified),
+                creator: metadata.map(|m| m.creator.into()),
+            },
+            None => syscalls::DentStatResult {
+                success: false,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn dent_rename(&mut self, src_fd: u64, name: &String, dest_fd: u64, new_name: String) -> syscalls::DentResult {
+        let src_m = self.dents.get(&src_fd).cloned();
+        let dest_m = self.dents.get(&dest_fd).cloned();
+        let result = src_m.zip(dest_m).and_then(|entries| {
+            match entries {
+                (DirEntry::Directory(src_dir), DirEntry::Directory(dest_dir)) => {
+                    src_dir.rename(name, &dest_dir, new_name, &self.env.fs, &mut self.ctx)
+                },
+                _ => Err(FsError::NotADir),
+            }
+            .ok()
+        });
+        syscalls::DentResult {
+            success: result.is_some(),
+            fd: None,
+            data: None,
+        }
+    }
+
     fn dent_invoke(
         &mut self,
         fd: u64,
@@ -693,22 +946,22 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
             .cloned()
             .and_then(|entry| match entry {
                 DirEntry::Gate(gate) => {
-                    let gate = gate.to_invokable(&self.env.fs);
-                    if !crate::fs::utils::get_privilege().implies(&gate.invoker_integrity_clearance)
-                    {
+                    let gate = gate.to_invokable(&self.env.fs, &mut self.ctx).ok()?;
+                    if !self.ctx.privilege().implies(&gate.invoker_integrity_clearance) {
                         return None;
                     }
                     sched::rpc::labeled_invoke(
                         self.env.sched_conn.as_mut().unwrap(),
                         sched::message::LabeledInvoke {
                             function: Some(gate.function.into()),
-                            label: Some(CURRENT_LABEL.with(|cl| cl.borrow().clone()).into()),
+                            label: Some(self.ctx.label().clone().into()),
                             gate_privilege: Some(gate.privilege.into()),
                             blobs: Default::default(),
                             payload,
                             headers: parameters,
                             sync,
-                            invoker: Some(PRIVILEGE.with(|p| p.borrow().clone()).into()),
+                            invoker: Some(self.ctx.privilege().clone().into()),
+                            notification_depth: self.ctx.notification_depth(),
                         },
                     )
                     .ok()?;
@@ -721,7 +974,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                             .clone()
                             .map(Into::into)
                             .unwrap_or(Buckle::public());
-                        fs::utils::taint_with_label(res_label);
+                        self.ctx.taint(&res_label).ok()?;
                         if toblob {
                             // TODO(alevy): would be better to just pass this intent
                             // through the request and have the target just write a
@@ -743,15 +996,13 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                     }
                 }
                 DirEntry::Service(service) => {
-                    let service_info = service.to_invokable(&self.env.fs);
-                    if !crate::fs::utils::get_privilege()
-                        .implies(&service_info.invoker_integrity_clearance)
-                    {
+                    let service_info = service.to_invokable(&self.env.fs, &mut self.ctx).ok()?;
+                    if !self.ctx.privilege().implies(&service_info.invoker_integrity_clearance) {
                         return None;
                     }
-                    crate::fs::utils::declassify_with(&service_info.privilege);
+                    self.ctx.declassify_with(&service_info.privilege);
                     let sendres = self.http_send(&service_info, Some(payload), parameters);
-                    crate::fs::utils::taint_with_label(service_info.taint);
+                    self.ctx.taint(&service_info.taint).ok()?;
                     match sendres {
                         Ok(mut response) => {
                             let headers: HashMap<String, Vec<u8>> = response
@@ -791,25 +1042,23 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
     }
 
     fn dent_get_blob(&mut self, fd: u64) -> syscalls::BlobResult {
-        match self.dents.get(&fd) {
-            Some(DirEntry::Blob(blobentry)) => {
-                let blob: Blob = self
-                    .env
-                    .blobstore
-                    .open(blobentry.read(&self.env.fs))
-                    .expect("blob");
-                let blobfd = self.max_blob_id;
-                self.max_blob_id += 1;
-                let len = blob.len().expect("blob should exist");
-                self.blobs.insert(blobfd, blob);
-                syscalls::BlobResult {
-                    success: true,
-                    fd: blobfd,
-                    len,
-                    data: None,
-                }
-            }
-            _ => syscalls::BlobResult {
+        let name = match self.dents.get(&fd) {
+            Some(DirEntry::Blob(blobentry)) => blobentry.read(&self.env.fs, &mut self.ctx).ok(),
+            _ => None,
+        };
+        let opened = name.and_then(|name| {
+            // keep the garbage collector off blobs used by in-flight invocations
+            let _ = self.env.blobstore.touch(&name);
+            self.open_blob(name)
+        });
+        match opened {
+            Some((fd, len)) => syscalls::BlobResult {
+                success: true,
+                fd,
+                len,
+                data: None,
+            },
+            None => syscalls::BlobResult {
                 success: false,
                 fd: 0,
                 len: 0,
@@ -871,6 +1120,16 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
             let len = blob.len() as u64;
             match self.env.blobstore.save(blob) {
                 Ok(blob) => {
+                    // an uncharged blob is never handed out, so the garbage
+                    // collector deletes it
+                    if let Err(e) = self.env.fs.charge_blob_bytes(&blob.name, len, &self.ctx) {
+                        return syscalls::BlobResult {
+                            success: false,
+                            fd,
+                            len,
+                            data: Some(format!("{:?}", e).into()),
+                        };
+                    }
                     self.blobs.insert(fd, blob);
                     syscalls::BlobResult {
                         success: true,
@@ -953,11 +1212,13 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
 
         match sc {
             SC::Response(r) => {
-                let result_label = fs::utils::declassify_with(&crate::fs::utils::get_privilege());
+                let privilege = self.ctx.privilege().clone();
+                let result_label = self.ctx.declassify_with(&privilege);
                 return Ok(Some(TaskReturn {
                     code: ReturnCode::Success as i32,
                     payload: Some(r),
                     label: Some(result_label.into()),
+                    store_usage: None,
                 }));
             }
 
@@ -967,26 +1228,27 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                 s.send(syscalls::MaybeBuckle { label: result.ok() }.encode_to_vec())?;
             }
             SC::GetCurrentLabel(syscalls::Void {}) => {
-                s.send(
-                    CURRENT_LABEL
-                        .with(|cl| syscalls::Buckle::from(cl.borrow().clone()))
-                        .encode_to_vec(),
-                )?;
+                s.send(syscalls::Buckle::from(self.ctx.label().clone()).encode_to_vec())?;
             }
             SC::TaintWithLabel(label) => {
-                s.send(
-                    CURRENT_LABEL
-                        .with(|cl| {
-                            *cl.borrow_mut() = cl.borrow().clone().lub(label.into());
-                            syscalls::Buckle::from(cl.borrow().clone())
-                        })
-                        .encode_to_vec(),
-                )?;
+                let result = syscalls::MaybeBuckle {
+                    label: self.ctx.taint(&label.into()).ok().map(|()| self.ctx.label().clone().into()),
+                };
+                s.send(result.encode_to_vec())?;
             }
             SC::Declassify(component) => {
                 let target = component.into();
                 let result = syscalls::MaybeBuckle {
-                    label: fs::utils::declassify(target).map(Into::into).ok(),
+                    label: self.ctx.declassify(target).map(Into::into).ok(),
+                };
+                s.send(result.encode_to_vec())?;
+            }
+            SC::SetClearance(clearance) => {
+                let clearance: Buckle = clearance.into();
+                let result = syscalls::MaybeBuckle {
+                    label: self.ctx.set_clearance(clearance.clone())
+                        .ok()
+                        .map(|_| clearance.into()),
                 };
                 s.send(result.encode_to_vec())?;
             }
@@ -1042,12 +1304,50 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                 )?;
             }
             SC::DentRead(fd) => s.send(self.dent_read(fd).encode_to_vec())?,
+            SC::DentReadRange(syscalls::DentRead { fd, offset, length }) => {
+                s.send(self.dent_read_range(fd, offset, length).encode_to_vec())?
+            }
+            SC::DentMapGet(syscalls::DentMapGet { fd, key }) => {
+                s.send(self.dent_map_get(fd, &key).encode_to_vec())?
+            }
+            SC::DentMapPut(syscalls::DentMapPut { fd, key, value }) => {
+                s.send(self.dent_map_put(fd, key, value).encode_to_vec())?
+            }
+            SC::DentMapDelete(syscalls::DentMapDelete { fd, key }) => {
+                s.send(self.dent_map_delete(fd, &key).encode_to_vec())?
+            }
+            SC::DentMapCas(syscalls::DentMapCas { fd, key, expected, value }) => {
+                s.send(self.dent_map_cas(fd, &key, expected, value).encode_to_vec())?
+            }
+            SC::DentLogAppend(syscalls::DentLogAppend { fd, record }) => {
+                s.send(self.dent_log_append(fd, record).encode_to_vec())?
+            }
+            SC::DentLogRead(syscalls::DentLogRead { fd, offset, max }) => {
+                s.send(self.dent_log_read(fd, offset, max).encode_to_vec())?
+            }
+            SC::DentLogTrim(syscalls::DentLogTrim { fd, offset }) => {
+                s.send(self.dent_log_trim(fd, offset).encode_to_vec())?
+            }
+            SC::DentSubscribe(syscalls::DentSubscribe { fd, gate_fd }) => {
+                s.send(self.dent_subscribe(fd, gate_fd, true).encode_to_vec())?
+            }
+            SC::DentUnsubscribe(syscalls::DentSubscribe { fd, gate_fd }) => {
+                s.send(self.dent_subscribe(fd, gate_fd, false).encode_to_vec())?
+            }
+            SC::DentAppend(syscalls::DentAppend { fd, data }) => {
+                s.send(self.dent_append(fd, &data).encode_to_vec())?
+            }
+            SC::DentTruncate(syscalls::DentTruncate { fd, length }) => {
+                s.send(self.dent_truncate(fd, length).encode_to_vec())?
+            }
             SC::DentList(fd) => s.send(self.dent_list(fd).encode_to_vec())?,
+            SC::DentWalk(walk) => s.send(self.dent_walk(walk).encode_to_vec())?,
             SC::DentLsFaceted(syscalls::DentLsFaceted { fd, clearance }) => s.send(
                 self.dent_list_faceted(fd, clearance.map(Into::into).unwrap_or(Buckle::public()))
                     .encode_to_vec(),
             )?,
             SC::DentLsGate(fd) => s.send(self.dent_ls_gate(fd).encode_to_vec())?,
+            SC::DentStat(fd) => s.send(self.dent_stat(fd).encode_to_vec())?,
             SC::DentLink(syscalls::DentLink {
                 dir_fd,
                 name,
@@ -1056,6 +1356,9 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
             SC::DentUnlink(syscalls::DentUnlink { fd, name }) => {
                 s.send(self.dent_unlink(fd, &name).encode_to_vec())?
             }
+            SC::DentRename(syscalls::DentRename { src_fd, name, dest_fd, new_name }) => {
+                s.send(self.dent_rename(src_fd, &name, dest_fd, new_name).encode_to_vec())?
+            }
             SC::DentInvoke(DentInvoke {
                 fd,
                 sync,
@@ -1140,7 +1443,9 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
 
         loop {
             if let Some(sc) = s.wait()? {
-                match self.do_syscall(sc, s) {
+                let res = self.do_syscall(sc, s);
+                self.send_notifications();
+                match res {
                     Err(er) => return Err(er),
                     Ok(Some(tr)) => return Ok(tr),
                     _ => {}
@@ -69,6 +69,24 @@ impl From<&crate::fs::DirEntry> for DentKind {
             DirEntry::Gate(_) => DentKind::DentGate,
             DirEntry::Service(_) => DentKind::DentService,
             DirEntry::Blob(_) => DentKind::DentBlob,
+            DirEntry::Map(_) => DentKind::DentMap,
+            DirEntry::Log(_) => DentKind::DentLog,
+        }
+    }
+}
+
+impl From<DentKind> for crate::fs::EntryKind {
+    fn from(value: DentKind) -> Self {
+        use crate::fs::EntryKind;
+        match value {
+            DentKind::DentDirectory => EntryKind::Directory,
+            DentKind::DentFile => EntryKind::File,
+            DentKind::DentFacetedDirectory => EntryKind::FacetedDirectory,
+            DentKind::DentGate => EntryKind::Gate,
+            DentKind::DentService => EntryKind::Service,
+            DentKind::DentBlob => EntryKind::Blob,
+            DentKind::DentMap => EntryKind::Map,
+            DentKind::DentLog => EntryKind::Log,
         }
     }
 }
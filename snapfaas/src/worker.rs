@@ -10,10 +10,11 @@ use labeled::buckle::{Buckle, Component};
 use labeled::Label;
 use log::{debug, error};
 
+use crate::blobstore::Blobstore;
 use crate::configs::FunctionConfig;
 use crate::vm::Vm;
 //use crate::metrics::{self, WorkerMetrics};
-use crate::fs::{self, BackingStore, Function, FS};
+use crate::fs::{BackingStore, Function, FS};
 use crate::resource_manager;
 use crate::sched::{
     self,
@@ -43,6 +44,7 @@ impl<B: BackingStore> Worker<B> {
         sched_addr: SocketAddr,
         localrm: Arc<Mutex<resource_manager::ResourceManager>>,
         backing_store: B,
+        blobstore: Blobstore,
     ) -> Self {
         let thread_id = thread::current().id();
 
@@ -75,7 +77,7 @@ impl<B: BackingStore> Worker<B> {
         let env = SyscallGlobalEnv {
             sched_conn: Some(sched_conn),
             fs: default_fs,
-            blobstore: Default::default(),
+            blobstore,
         };
 
         Self {
@@ -119,15 +121,24 @@ impl<B: BackingStore> Worker<B> {
                             if let Some(mut vm) =
                                 self.try_allocate(&invoke.function.unwrap().into(), &label)
                             {
+                                // only count the store operations of this invocation
+                                let _ = self.env.fs.take_store_metrics();
                                 let mut cnt = 0;
                                 let mut ret = TaskReturn {
                                     code: ReturnCode::ProcessRequestFailed as i32,
                                     payload: None,
-                                    label: Some(fs::utils::get_current_label().into()),
+                                    label: Some(label.clone().into()),
+                                    store_usage: None,
                                 };
                                 loop {
                                     cnt += 1;
                                     let mut config: FunctionConfig = vm.function.clone().into();
+                                    // keep the blob garbage collector off the images
+                                    for image in [&vm.function.kernel, &vm.function.app_image, &vm.function.runtime_image] {
+                                        if !image.is_empty() {
+                                            let _ = self.env.blobstore.touch(image);
+                                        }
+                                    }
                                     config.kernel = self
                                         .env
                                         .blobstore
@@ -160,6 +171,7 @@ impl<B: BackingStore> Worker<B> {
                                         .blobs
                                         .iter()
                                         .map(|(k, b)| {
+                                            let _ = self.env.blobstore.touch(b);
                                             (
                                                 k.clone(),
                                                 (self.env.blobstore.open(b.clone()).unwrap()),
@@ -170,7 +182,8 @@ impl<B: BackingStore> Worker<B> {
                                         &mut self.env,
                                         label.clone(),
                                         privilege.clone(),
-                                    );
+                                    )
+                                    .with_notification_depth(invoke.notification_depth);
                                     if let Ok(result) = processor.run(
                                         invoke.payload.clone(),
                                         blobs,
@@ -190,6 +203,7 @@ impl<B: BackingStore> Worker<B> {
                                         break;
                                     }
                                 }
+                                ret.store_usage = self.env.fs.take_store_metrics().as_ref().map(Into::into);
                                 if let Err(e) = sched::rpc::finish(
                                     &mut self.env.sched_conn.as_mut().unwrap(),
                                     task_id,
@@ -204,7 +218,8 @@ impl<B: BackingStore> Worker<B> {
                                 let ret = TaskReturn {
                                     code: ReturnCode::ResourceExhausted as i32,
                                     payload: None,
-                                    label: Some(fs::utils::get_current_label().into()),
+                                    label: Some(label.clone().into()),
+                                    store_usage: None,
                                 };
                                 if let Err(e) = sched::rpc::finish(
                                     &mut self.env.sched_conn.as_mut().unwrap(),